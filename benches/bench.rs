@@ -135,3 +135,91 @@ fn insert_tale(b: &mut test::Bencher) {
         }
     })
 }
+
+#[bench]
+fn replace_long_line(b: &mut test::Bencher) {
+    let vfs = Vfs::new();
+    let path = PathBuf::from("long_line.rs");
+    vfs.set_file(&path, &"x".repeat(100_000)).unwrap();
+    b.iter(|| {
+        let start = Position::new(Row::new_zero_indexed(0), Column::new_zero_indexed(99_000));
+        let end = Position::new(Row::new_zero_indexed(0), Column::new_zero_indexed(99_010));
+        let change = Change::ReplaceText {
+            span: VfsSpan::from_usv(Span::from_positions(start, end, &path), None),
+            text: "yyyyyyyyyy".to_owned(),
+        };
+        vfs.on_changes(&[change]).unwrap();
+    })
+}
+
+#[bench]
+fn edit_end_of_1mb_line(b: &mut test::Bencher) {
+    let vfs = Vfs::new();
+    let path = PathBuf::from("1mb_line.rs");
+    // Multibyte characters, so columns and byte offsets differ.
+    vfs.set_file(&path, &"x\u{E9}".repeat(350_000)).unwrap();
+    b.iter(|| {
+        for _ in 0..10 {
+            let start = Position::new(Row::new_zero_indexed(0), Column::new_zero_indexed(699_000));
+            let end = Position::new(Row::new_zero_indexed(0), Column::new_zero_indexed(699_002));
+            let change = Change::ReplaceText {
+                span: VfsSpan::from_usv(Span::from_positions(start, end, &path), None),
+                text: "x\u{E9}".to_owned(),
+            };
+            vfs.on_changes(&[change]).unwrap();
+        }
+    })
+}
+
+// This edit does not add or remove lines, so it updates the line index in
+// O(log n) in the number of lines. An edit to the first line which does add or
+// remove lines would still take O(n).
+#[bench]
+fn edit_first_line_10k(b: &mut test::Bencher) {
+    let vfs = Vfs::new();
+    let path = PathBuf::from("10k_lines.rs");
+    let text: String = (0..10_000).map(|i| format!("let x{} = {};\n", i, i)).collect();
+    vfs.set_file(&path, &text).unwrap();
+    b.iter(|| {
+        for _ in 0..10 {
+            let start = Position::new(Row::new_zero_indexed(0), Column::new_zero_indexed(4));
+            let end = Position::new(Row::new_zero_indexed(0), Column::new_zero_indexed(6));
+            let change = Change::ReplaceText {
+                span: VfsSpan::from_usv(Span::from_positions(start, end, &path), None),
+                text: "y0".to_owned(),
+            };
+            vfs.on_changes(&[change]).unwrap();
+        }
+    })
+}
+
+fn insert_1000_lines(b: &mut test::Bencher, at_end: bool) {
+    let vfs = Vfs::new();
+    let path = PathBuf::from("log.txt");
+    let text: String = (0..10_000).map(|i| format!("line {}\n", i)).collect();
+    b.iter(|| {
+        vfs.set_file(&path, &text).unwrap();
+        for i in 0..1000 {
+            // The file ends in a newline, so its last line is empty.
+            let row = if at_end { 10_000 + i } else { 9_999 + i };
+            let pos = Position::new(Row::new_zero_indexed(row), Column::new_zero_indexed(0));
+            let change = Change::ReplaceText {
+                span: VfsSpan::from_usv(Span::from_positions(pos, pos, &path), None),
+                text: "appended line\n".to_owned(),
+            };
+            vfs.on_changes(&[change]).unwrap();
+        }
+    })
+}
+
+#[bench]
+fn append_1000_lines(b: &mut test::Bencher) {
+    insert_1000_lines(b, true)
+}
+
+// For comparison with `append_1000_lines`, which can skip rebuilding the
+// line index.
+#[bench]
+fn insert_1000_lines_before_last(b: &mut test::Bencher) {
+    insert_1000_lines(b, false)
+}
@@ -0,0 +1,144 @@
+//! A sparse index of the columns of a long line, so that converting a column
+//! to a byte offset does not need to scan the line from its start.
+
+use span::{Column, ZeroIndexed};
+
+use super::{byte_in_str, byte_in_str_utf16, Error};
+
+/// Lines at least this many bytes long get a `ColumnIndex`.
+pub const LONG_LINE_THRESHOLD: usize = 4096;
+
+// The number of characters between checkpoints in a new index.
+const STEP: usize = 256;
+
+/// Offsets of every `STEP`th character of a line, in bytes, unicode scalar
+/// values, and UTF-16 code units. Edits to the line keep the checkpoints
+/// outside the edited text, so the spacing of checkpoints becomes uneven as
+/// the line is edited.
+#[derive(Clone, Debug)]
+pub struct ColumnIndex {
+    // Sorted by (all of) their offsets, the first is always at the start of
+    // the line.
+    checkpoints: Vec<Checkpoint>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Checkpoint {
+    byte: usize,
+    chars: usize,
+    utf16: usize,
+}
+
+impl Checkpoint {
+    fn column(&self, utf16: bool) -> usize {
+        if utf16 {
+            self.utf16
+        } else {
+            self.chars
+        }
+    }
+}
+
+impl ColumnIndex {
+    pub fn new(line: &str) -> ColumnIndex {
+        let mut checkpoints = vec![];
+        let mut utf16 = 0;
+        for (chars, (byte, c)) in line.char_indices().enumerate() {
+            if chars % STEP == 0 {
+                checkpoints.push(Checkpoint { byte, chars, utf16 });
+            }
+            utf16 += c.len_utf16();
+        }
+        if checkpoints.is_empty() {
+            checkpoints.push(Checkpoint {
+                byte: 0,
+                chars: 0,
+                utf16: 0,
+            });
+        }
+        ColumnIndex { checkpoints }
+    }
+
+    /// Return the byte offset in `line` of column `c`, which is in UTF-16 code
+    /// units if `utf16` is set, or else in unicode scalar values. Errors are as
+    /// for `byte_in_str` and `byte_in_str_utf16`.
+    pub fn byte_offset(
+        &self,
+        line: &str,
+        c: Column<ZeroIndexed>,
+        utf16: bool,
+    ) -> Result<usize, Error> {
+        let target = c.0 as usize;
+        let i = self
+            .checkpoints
+            .partition_point(|cp| cp.column(utf16) <= target)
+            .max(1);
+        let cp = self.checkpoints[i - 1];
+        let rest = Column::new_zero_indexed((target - cp.column(utf16)) as u32);
+        let offset = if utf16 {
+            byte_in_str_utf16(&line[cp.byte..], rest)?
+        } else {
+            byte_in_str(&line[cp.byte..], rest)?
+        };
+        Ok(cp.byte + offset)
+    }
+
+    /// Update the index for replacing the text `removed`, which starts at byte
+    /// `start` of the line, with `inserted`. Neither may contain a newline.
+    pub fn splice(&mut self, start: usize, removed: &str, inserted: &str) {
+        let end = start + removed.len();
+        let count = |s: &str| {
+            (
+                s.len() as isize,
+                s.chars().count() as isize,
+                s.chars().map(char::len_utf16).sum::<usize>() as isize,
+            )
+        };
+        let (removed, inserted) = (count(removed), count(inserted));
+        let shift = |offset: usize, removed: isize, inserted: isize| {
+            (offset as isize - removed + inserted) as usize
+        };
+
+        self.checkpoints.retain(|cp| cp.byte <= start || cp.byte >= end);
+        for cp in &mut self.checkpoints {
+            if cp.byte >= end && (cp.byte > start || end > start) {
+                cp.byte = shift(cp.byte, removed.0, inserted.0);
+                cp.chars = shift(cp.chars, removed.1, inserted.1);
+                cp.utf16 = shift(cp.utf16, removed.2, inserted.2);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ColumnIndex;
+    use span::Column;
+    use {byte_in_str, byte_in_str_utf16};
+
+    #[test]
+    fn splice() {
+        let mut line: String = "a\u{E9}\u{1F600}b".repeat(300);
+        let mut index = ColumnIndex::new(&line);
+        let edits = [
+            (0, 0, "x"),
+            (4, 16, ""),
+            (4, 4, "\u{1F600}"),
+            (100, 1000, "yy"),
+            (0, 2, ""),
+        ];
+        for &(start, end, inserted) in &edits {
+            index.splice(start, &line[start..end], inserted);
+            line.replace_range(start..end, inserted);
+            let chars = line.chars().count() as u32;
+            for c in (0..=chars).step_by(7).chain(Some(chars + 1)) {
+                let c = Column::new_zero_indexed(c);
+                assert_eq!(index.byte_offset(&line, c, false), byte_in_str(&line, c));
+            }
+            for c in (0..=chars * 2).step_by(5) {
+                let c = Column::new_zero_indexed(c);
+                assert_eq!(index.byte_offset(&line, c, true), byte_in_str_utf16(&line, c));
+            }
+        }
+    }
+}
@@ -3,17 +3,42 @@
 extern crate rls_span as span;
 #[macro_use]
 extern crate log;
+#[cfg(feature = "flate2")]
+extern crate flate2;
+#[cfg(feature = "regex")]
+extern crate regex;
 
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt;
 use std::fs;
-use std::io::Read;
+#[cfg(feature = "async")]
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufRead, Read, Seek, SeekFrom};
 use std::marker::PhantomData;
 use std::mem;
-use std::path::{Path, PathBuf};
-use std::sync::Mutex;
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
 use std::thread::{self, Thread};
+use std::time::{Duration, Instant, SystemTime};
 
+use column_index::{ColumnIndex, LONG_LINE_THRESHOLD};
+use line_index::LineIndex;
+use path_map::PathMap;
+
+mod column_index;
+mod line_index;
+#[cfg(feature = "async")]
+mod load_async;
+mod patch;
+mod path_map;
+mod sha256;
+pub mod testing;
+pub mod text_grid;
 #[cfg(test)]
 mod test;
 
@@ -26,10 +51,20 @@ macro_rules! try_opt_loc {
     };
 }
 
-pub struct Vfs<U = ()>(VfsInternal<RealFileLoader, U>);
+pub struct Vfs<U = ()>(Arc<VfsInternal<RealFileLoader, U>>);
+
+// The byte range of each line of a file, see `Vfs::line_ranges`.
+type LineRanges = Vec<(span::Row<span::ZeroIndexed>, Range<usize>)>;
+
+// The lines of each file covered by a batch of changes, see
+// `Vfs::affected_lines`.
+type AffectedLines = HashMap<PathBuf, Vec<Range<span::Row<span::ZeroIndexed>>>>;
+
+// A file's text and user data, see `Vfs::with_user_data_multi`.
+type UserDataEntry<'a, 'p, U> = (&'p Path, Result<(Option<&'a str>, &'a mut U), Error>);
 
 /// Span of the text to be replaced defined in col/row terms.
-#[derive(Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub struct SpanData {
     /// Span of the text defined in col/row terms.
     pub span: span::Span<span::ZeroIndexed>,
@@ -38,11 +73,134 @@ pub struct SpanData {
     /// span's row_end/col_end fields. Needed for editors that
     /// can't properly calculate the latter fields.
     /// Span's row_start/col_start are still assumed valid.
+    /// The range may end exactly at the end of the file; a `len`
+    /// which runs past it is `Error::BadLocation`.
     pub len: Option<u64>,
 }
 
+/// The text units in which columns are measured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PositionEncoding {
+    /// Columns count unicode scalar values.
+    UnicodeScalarValue,
+    /// Columns count UTF-16 code units.
+    Utf16CodeUnit,
+    /// Columns count grapheme clusters, approximately. Clusters are taken to
+    /// be a base character followed by any combining marks, variation
+    /// selectors, emoji modifiers, and zero width joiner sequences; `\r\n` is
+    /// one cluster. This is not the full segmentation of Unicode Standard
+    /// Annex #29: Hangul syllables written as separate jamo, regional
+    /// indicator pairs (flags), prepended characters, and spacing marks are
+    /// each counted as more than one cluster, so columns from a client which
+    /// segments text fully may not match on such text.
+    Grapheme,
+}
+
+impl PositionEncoding {
+    /// Return the length of `s` in this encoding's text units.
+    pub fn count(self, s: &str) -> usize {
+        match self {
+            PositionEncoding::UnicodeScalarValue => s.chars().count(),
+            PositionEncoding::Utf16CodeUnit => s.chars().map(char::len_utf16).sum(),
+            PositionEncoding::Grapheme => grapheme_starts(s).count(),
+        }
+    }
+
+    // Return a UTF-8 byte offset in `s` for a given offset in this encoding.
+    fn byte_in_str(self, s: &str, c: span::Column<span::ZeroIndexed>) -> Result<usize, Error> {
+        match self {
+            PositionEncoding::UnicodeScalarValue => byte_in_str(s, c),
+            PositionEncoding::Utf16CodeUnit => byte_in_str_utf16(s, c),
+            PositionEncoding::Grapheme => byte_in_str_grapheme(s, c),
+        }
+    }
+}
+
+// Return the byte offsets at which grapheme clusters start in `s`. See
+// `PositionEncoding::Grapheme` for the approximation used.
+fn grapheme_starts(s: &str) -> impl Iterator<Item = usize> + '_ {
+    let mut prev = None;
+    s.char_indices().filter_map(move |(i, c)| {
+        let extends = match prev {
+            None => false,
+            Some('\u{200D}') => true,
+            Some('\r') => c == '\n',
+            Some(_) => extends_grapheme(c),
+        };
+        prev = Some(c);
+        if extends {
+            None
+        } else {
+            Some(i)
+        }
+    })
+}
+
+// Whether `c` continues the grapheme cluster of the character before it.
+fn extends_grapheme(c: char) -> bool {
+    match c as u32 {
+        // Combining diacritical marks (and their extensions and supplements).
+        0x0300..=0x036F | 0x1AB0..=0x1AFF | 0x1DC0..=0x1DFF | 0x20D0..=0x20FF | 0xFE20..=0xFE2F => {
+            true
+        }
+        // Zero width joiner, variation selectors, and emoji skin tone modifiers.
+        0x200D | 0xFE00..=0xFE0F | 0xE0100..=0xE01EF | 0x1F3FB..=0x1F3FF => true,
+        _ => false,
+    }
+}
+
+// The path under `dir` to which `Vfs::materialize_to` writes `path`, or `None`
+// if `path` has `..` components, which could lead outside `dir`. A prefix is
+// kept, so that files on different drives are written to different places.
+fn materialized_path(dir: &Path, path: &Path) -> Option<PathBuf> {
+    let mut target = dir.to_owned();
+    for c in path.components() {
+        match c {
+            Component::Prefix(prefix) => {
+                let prefix = prefix.as_os_str().to_string_lossy();
+                target.push(prefix.replace(|c: char| !c.is_alphanumeric(), "_"));
+            }
+            Component::Normal(c) => target.push(c),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir => return None,
+        }
+    }
+    Some(target)
+}
+
+// Whether `c` can be part of an identifier, see `Vfs::word_at`.
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+// Return the first `wanted` character in `chars` which does not close a
+// `nested` character seen before it.
+fn find_unmatched<I>(chars: I, nested: char, wanted: char) -> Option<(usize, char)>
+where
+    I: Iterator<Item = (usize, char)>,
+{
+    let mut depth = 0;
+    for (i, c) in chars {
+        if c == nested {
+            depth += 1;
+        } else if c == wanted {
+            if depth == 0 {
+                return Some((i, c));
+            }
+            depth -= 1;
+        }
+    }
+    None
+}
+
+// Strip a trailing `\n` or `\r\n` from a line.
+fn trim_line_terminator(line: &str) -> &str {
+    let line = line.strip_suffix('\n').unwrap_or(line);
+    line.strip_suffix('\r').unwrap_or(line)
+}
+
 /// Span of text that VFS can operate with.
-#[derive(Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub enum VfsSpan {
     /// Span with offsets based on unicode scalar values.
     UnicodeScalarValue(SpanData),
@@ -67,6 +225,13 @@ impl VfsSpan {
         }
     }
 
+    fn encoding(&self) -> PositionEncoding {
+        match self {
+            VfsSpan::UnicodeScalarValue(..) => PositionEncoding::UnicodeScalarValue,
+            VfsSpan::Utf16CodeUnit(..) => PositionEncoding::Utf16CodeUnit,
+        }
+    }
+
     fn as_inner(&self) -> &SpanData {
         match self {
             VfsSpan::UnicodeScalarValue(span) => span,
@@ -74,6 +239,13 @@ impl VfsSpan {
         }
     }
 
+    fn as_inner_mut(&mut self) -> &mut SpanData {
+        match self {
+            VfsSpan::UnicodeScalarValue(span) => span,
+            VfsSpan::Utf16CodeUnit(span) => span,
+        }
+    }
+
     pub fn span(&self) -> &span::Span<span::ZeroIndexed> {
         &self.as_inner().span
     }
@@ -83,7 +255,7 @@ impl VfsSpan {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Change {
     /// Create an in-memory image of the file.
     AddFile { file: PathBuf, text: String },
@@ -103,6 +275,16 @@ impl Change {
             Change::ReplaceText { ref span, .. } => span.span().file.as_ref(),
         }
     }
+
+    // Return this change with its path replaced by `file`.
+    fn with_file(&self, file: PathBuf) -> Change {
+        let mut change = self.clone();
+        match change {
+            Change::AddFile { file: ref mut f, .. } => *f = file,
+            Change::ReplaceText { ref mut span, .. } => span.as_inner_mut().span.file = file,
+        }
+        change
+    }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -118,6 +300,32 @@ pub enum Error {
     BadLocation,
     /// The requested file was not cached in the VFS.
     FileNotCached,
+    /// The given file was deleted with `Vfs::soft_delete`, and has not been
+    /// added again.
+    Deleted(PathBuf),
+    /// The given file was added with `Vfs::add_virtual`, and can not be
+    /// changed or written.
+    ReadOnly(PathBuf),
+    /// The given file was not valid UTF-8 and was decoded lossily (see
+    /// `Utf8ErrorMode::Lossy`), so writing it would lose data.
+    LossilyDecoded(PathBuf),
+    /// A batch of changes edits the given file, which is neither cached nor
+    /// added by the batch.
+    UnknownFile(PathBuf),
+    /// The contents of the given file read from disk do not match the checksum
+    /// expected for it, see `Vfs::set_expected_checksums`.
+    ChecksumMismatch { path: PathBuf },
+    /// The given file is `size` bytes long, which is larger than the limit set
+    /// with `Vfs::set_max_file_size`.
+    FileTooLarge {
+        path: PathBuf,
+        size: usize,
+        limit: usize,
+    },
+    /// The given path was given more than once where paths must be distinct,
+    /// e.g., to `Vfs::with_user_data_multi`, or two files would be written to
+    /// it by `Vfs::materialize_to`.
+    DuplicatePath(PathBuf),
     /// Not really an error, file is cached but there is no user data for it.
     NoUserDataForFile,
     /// Wrong kind of file.
@@ -136,6 +344,13 @@ impl ::std::error::Error for Error {
             }
             Error::BadLocation => "client specified location not existing within a file",
             Error::FileNotCached => "requested file was not cached in the VFS",
+            Error::Deleted(ref _path_buf) => "file was deleted",
+            Error::ReadOnly(ref _path_buf) => "file is read-only",
+            Error::LossilyDecoded(ref _path_buf) => "file was decoded lossily",
+            Error::UnknownFile(ref _path_buf) => "changes refer to a file not known to the VFS",
+            Error::ChecksumMismatch { .. } => "file contents do not match the expected checksum",
+            Error::FileTooLarge { .. } => "file is larger than the maximum file size",
+            Error::DuplicatePath(ref _path_buf) => "path given more than once",
             Error::NoUserDataForFile => "file is cached but there is no user data for it",
             Error::BadFileKind => {
                 "file is not the correct kind for the operation (e.g., text op on binary file)"
@@ -160,6 +375,33 @@ impl fmt::Display for Error {
             Error::UncommittedChanges(ref path_buf) => {
                 write!(f, "{} has uncommitted changes", path_buf.display())
             }
+            Error::UnknownFile(ref path_buf) => {
+                write!(f, "changes refer to unknown file {}", path_buf.display())
+            }
+            Error::Deleted(ref path_buf) => write!(f, "{} was deleted", path_buf.display()),
+            Error::ReadOnly(ref path_buf) => write!(f, "{} is read-only", path_buf.display()),
+            Error::LossilyDecoded(ref path_buf) => write!(
+                f,
+                "{} was not valid UTF-8 and was decoded lossily",
+                path_buf.display()
+            ),
+            Error::ChecksumMismatch { ref path } => {
+                write!(f, "contents of {} do not match the expected checksum", path.display())
+            }
+            Error::FileTooLarge {
+                ref path,
+                size,
+                limit,
+            } => write!(
+                f,
+                "{} is {} bytes long, more than the maximum of {} bytes",
+                path.display(),
+                size,
+                limit
+            ),
+            Error::DuplicatePath(ref path_buf) => {
+                write!(f, "{} given more than once", path_buf.display())
+            }
             Error::InternalError(e) => write!(f, "internal error: {}", e),
             Error::BadLocation
             | Error::FileNotCached
@@ -173,30 +415,187 @@ impl fmt::Display for Error {
 impl<U> Vfs<U> {
     /// Creates a new, empty VFS.
     pub fn new() -> Vfs<U> {
-        Vfs(VfsInternal::<RealFileLoader, U>::new())
+        Vfs(Arc::new(VfsInternal::<RealFileLoader, U>::new()))
+    }
+
+    /// Creates a new, empty VFS which reads files from each of `loaders` in
+    /// turn until one provides the file, e.g., an overlay directory followed
+    /// by a base directory. An error from a loader is returned immediately,
+    /// without trying the rest. Files which none of the loaders provide are
+    /// read from disk.
+    pub fn with_loader_chain(loaders: Vec<Box<dyn FileSource>>) -> Vfs<U> {
+        Vfs(Arc::new(VfsInternal::<RealFileLoader, U>::with_loader_chain(
+            loaders,
+        )))
+    }
+
+    /// Creates a new, empty VFS which buffers the changes passed to
+    /// `on_changes` until `window` changes are buffered or `flush_pending` is
+    /// called, then applies each file's changes in one pass. Until then, the
+    /// buffered changes are not visible when reading files.
+    pub fn with_coalescing(window: usize) -> Vfs<U> {
+        Vfs(Arc::new(VfsInternal::<RealFileLoader, U>::with_coalescing(window)))
     }
 
     /// Indicate that the current file as known to the VFS has been written to
-    /// disk.
+    /// disk. The file's user data and version are left intact.
     pub fn file_saved(&self, path: &Path) -> Result<(), Error> {
         self.0.file_saved(path)
     }
 
+    /// Like `file_saved`, but returns `Error::FileNotCached` if the file is not
+    /// cached, rather than doing nothing.
+    pub fn mark_clean(&self, path: &Path) -> Result<(), Error> {
+        self.0.mark_clean(path)
+    }
+
     /// Removes a file from the VFS. Does not check if the file is synced with
     /// the disk. Does not check if the file exists.
     pub fn flush_file(&self, path: &Path) -> Result<(), Error> {
         self.0.flush_file(path)
     }
 
+    /// Mark a cached file as deleted. Until it is added again, reading the file
+    /// or editing it returns `Error::Deleted`. If it is added again with
+    /// `Change::AddFile` within `TOMBSTONE_RETENTION`, the file is restored
+    /// before the change is applied, so it keeps its user data if the text is
+    /// unchanged.
+    pub fn soft_delete(&self, path: &Path) -> Result<(), Error> {
+        self.0.soft_delete(path)
+    }
+
+    /// Removes all files under the directory `prefix` from the VFS and returns
+    /// the number of files removed. Like `flush_file`, this does not check if
+    /// files are synced with the disk.
+    pub fn flush_under(&self, prefix: &Path) -> Result<usize, Error> {
+        self.0.flush_under(prefix)
+    }
+
+    /// Call `f` with the path of each cached file, without copying the paths.
+    ///
+    /// Note that `f` should not be a long-running operation since we hold the
+    /// lock to the VFS while it runs.
+    pub fn with_paths<F>(&self, f: F)
+    where
+        F: FnMut(&Path),
+    {
+        self.0.with_paths(f)
+    }
+
+    /// Return the deepest directory containing every cached file, or `None` if
+    /// no files are cached or they have no common ancestor, e.g., because
+    /// they are on different drives.
+    pub fn common_root(&self) -> Option<PathBuf> {
+        self.0.common_root()
+    }
+
+    /// Return the paths of all cached files under the directory `prefix`.
+    pub fn paths_under(&self, prefix: &Path) -> Vec<PathBuf> {
+        self.0.paths_under(prefix)
+    }
+
     pub fn file_is_synced(&self, path: &Path) -> Result<bool, Error> {
         self.0.file_is_synced(path)
     }
 
+    /// Return whether a file is in the VFS.
+    pub fn is_cached(&self, path: &Path) -> bool {
+        self.0.is_cached(path)
+    }
+
+    /// Report whether a file is cached, and if so, whether it is empty, or
+    /// else its size. The file is not loaded if it is not cached.
+    pub fn file_state(&self, path: &Path) -> FileState {
+        self.0.file_state(path)
+    }
+
+    /// Return whether `path` is a file on disk, rather than a file added with
+    /// `add_virtual` or a file which does not exist.
+    pub fn exists_on_disk(&self, path: &Path) -> bool {
+        self.0.exists_on_disk(path)
+    }
+
+    /// Return whether the cached text of `path` is `text`, without copying the
+    /// cached text. Binary files are never equal to a string.
+    pub fn content_equals(&self, path: &Path, text: &str) -> Result<bool, Error> {
+        self.0.content_equals(path, text)
+    }
+
     /// Record a set of changes to the VFS.
+    ///
+    /// Returns `Error::OutOfSync` without applying any changes if a cached file
+    /// with no changes in memory would be edited, but has changed on disk since
+    /// it was read.
     pub fn on_changes(&self, changes: &[Change]) -> Result<(), Error> {
         self.0.on_changes(changes)
     }
 
+    /// Return, for each file edited by `changes`, the range of lines covered by
+    /// each `ReplaceText` change, in order, without applying the changes. The
+    /// ranges of later changes are in terms of the text after earlier ones.
+    /// `AddFile` changes are ignored. Without the text, the end of a span
+    /// with a `len` can't be found, so its range ends at the span's nominal
+    /// end row, even if its `len` reaches past it.
+    pub fn affected_lines(changes: &[Change]) -> AffectedLines {
+        affected_lines(changes)
+    }
+
+    /// Apply any changes buffered by a VFS created with `with_coalescing`.
+    pub fn flush_pending(&self) -> Result<(), Error> {
+        self.0.flush_pending()
+    }
+
+    /// Like `on_changes`, but also returns a map from positions in the files
+    /// before the changes to the corresponding positions after them. Any
+    /// buffered changes are applied first.
+    pub fn on_changes_with_mapping(&self, changes: &[Change]) -> Result<PositionMap, Error> {
+        self.0.on_changes_with_mapping(changes)
+    }
+
+    /// Like `on_changes`, but also returns changes which revert `changes`.
+    /// Applying them in order, e.g., with `undo_change`, restores the files,
+    /// as long as they have not been changed in the meantime. Their columns
+    /// count unicode scalar values, unless the file's encoding is set with
+    /// `set_file_encoding`, in which case they are in its units. Any buffered
+    /// changes are applied first.
+    pub fn on_changes_with_inverses(&self, changes: &[Change]) -> Result<Vec<Change>, Error> {
+        self.0.on_changes_with_inverses(changes)
+    }
+
+    /// Apply `inverse`, a change to `path` returned by
+    /// `on_changes_with_inverses` or `undo_change`, and return the change
+    /// which reapplies what it reverted. Returns `BadLocation` if `inverse`
+    /// is a change to another file.
+    pub fn undo_change(&self, path: &Path, inverse: &Change) -> Result<Change, Error> {
+        self.0.undo_change(path, inverse)
+    }
+
+    /// Return the range of bytes of the cached file which `change` would
+    /// replace, and the number of bytes it would insert, without applying it.
+    /// Any buffered changes are applied first.
+    pub fn preview_change(&self, change: &Change) -> Result<(Range<usize>, usize), Error> {
+        self.0.preview_change(change)
+    }
+
+    /// Shrink the memory used by a cached file after many edits, e.g., when
+    /// idle. Does not change the file.
+    pub fn compact(&self, path: &Path) -> Result<(), Error> {
+        self.0.compact(path)
+    }
+
+    /// Like `compact`, for every cached file.
+    pub fn compact_all(&self) {
+        self.0.compact_all()
+    }
+
+    /// Return a `textDocument/didChange` notification for each cached file with
+    /// changes which have not been written to disk, sending its full text.
+    /// Relative paths are resolved against the root set with `set_root`;
+    /// files with relative paths are skipped if there is no root.
+    pub fn pending_lsp_changes(&self) -> Vec<LspDidChange> {
+        self.0.pending_lsp_changes()
+    }
+
     /// Return all files in the VFS.
     pub fn get_cached_files(&self) -> HashMap<PathBuf, String> {
         self.0.get_cached_files()
@@ -206,19 +605,190 @@ impl<U> Vfs<U> {
         self.0.get_changes()
     }
 
+    /// Call `f` with the path and text of each changed file, in order of their
+    /// paths, e.g., to save them, and mark each file as unchanged if `f`
+    /// succeeds. If `f` fails, stops and returns the error, leaving that file
+    /// and the rest changed. A file changed again while `f` is called for it
+    /// stays changed. The VFS is not locked while `f` is called.
+    pub fn drain_changes<F>(&self, f: F) -> Result<(), Error>
+    where
+        F: FnMut(&Path, &str) -> Result<(), Error>,
+    {
+        self.0.drain_changes(f)
+    }
+
+    /// Write the cached contents of every cached file, including changes not
+    /// yet written to disk, to a file under `dir`, e.g., for a tool which can
+    /// only read files from disk. The files mirror their original paths
+    /// relative to the root, so `/src/main.rs` is written to
+    /// `dir/src/main.rs`, and `C:\src\main.rs` to `dir\C_\src\main.rs`.
+    /// Returns the path each file was written to. Nothing is written if a
+    /// cached path has `..` components (`Io`), or two files would be written
+    /// to the same place, e.g., `/src/main.rs` and `src/main.rs`
+    /// (`DuplicatePath`).
+    pub fn materialize_to(&self, dir: &Path) -> Result<HashMap<PathBuf, PathBuf>, Error> {
+        self.0.materialize_to(dir)
+    }
+
+    /// Like `get_cached_files`, but shares the text of each file with the VFS
+    /// rather than copying it. The returned texts are a consistent snapshot:
+    /// later edits to the VFS copy a file's text before changing it if it is
+    /// still shared.
+    pub fn cached_files_arc(&self) -> HashMap<PathBuf, Arc<String>> {
+        self.0.cached_files_arc()
+    }
+
+    /// Take an immutable snapshot of the cached files, which can be shared
+    /// between threads. Text is shared with the VFS as for `cached_files_arc`,
+    /// binary files are copied. The snapshot does not include user data.
+    pub fn arc_snapshot(&self) -> Arc<VfsSnapshot<U>> {
+        self.0.arc_snapshot()
+    }
+
+    /// Lock the VFS for reading many cached files without copying them or
+    /// locking for each one. No other operation on the VFS can proceed until
+    /// the guard is dropped, so nothing is mutated while it is held; calling
+    /// into the VFS from the thread holding the guard deadlocks.
+    pub fn read_guard(&self) -> VfsReadGuard<'_, U> {
+        self.0.read_guard()
+    }
+
     /// Returns true if the VFS contains any changed files.
     pub fn has_changes(&self) -> bool {
         self.0.has_changes()
     }
 
-    pub fn set_file(&self, path: &Path, text: &str) {
+    /// Return the number of changed files, i.e., the number of files in
+    /// `get_changes`, without copying their text.
+    pub fn pending_change_count(&self) -> usize {
+        self.0.pending_change_count()
+    }
+
+    /// Return the paths of the cached files which have user data.
+    pub fn files_with_user_data(&self) -> Vec<PathBuf> {
+        self.0.files_with_user_data()
+    }
+
+    /// Return the number of files in the VFS.
+    pub fn file_count(&self) -> usize {
+        self.0.file_count()
+    }
+
+    /// Return the total size in bytes of all files in the VFS.
+    pub fn total_bytes(&self) -> usize {
+        self.0.total_bytes()
+    }
+
+    /// Return a human-readable summary of every cached file, one per line, for
+    /// logging. Lists each file's length, line count, whether it has changed,
+    /// its version, and whether it has user data.
+    pub fn debug_dump(&self) -> String {
+        self.0.debug_dump()
+    }
+
+    /// Replace the cached contents of `path` with `text`, marking it as
+    /// changed. Fails with `Error::ReadOnly` for a file added with
+    /// `add_virtual`.
+    pub fn set_file(&self, path: &Path, text: &str) -> Result<(), Error> {
         self.0.set_file(path, text)
     }
 
+    /// Like `set_file` for many files at once, taking the VFS lock only once.
+    /// If any of the files is read-only, none of them are set.
+    pub fn set_files<I>(&self, entries: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = (PathBuf, String)>,
+    {
+        self.0.set_files(entries)
+    }
+
+    /// Remove the text covered by `span` from its file and store it as the
+    /// contents of `dest`, replacing `dest` if it is already cached. Both files
+    /// are marked as changed. The move happens atomically, so other users of
+    /// the VFS see either both files before the move or both after.
+    ///
+    /// The removal is applied like a change passed to `on_changes`, so it can
+    /// be undone and appears in the change history. A cached `dest` keeps
+    /// only its version, encoding override and version hashes; its user data
+    /// and undo history are discarded.
+    ///
+    /// Fails with `Error::ReadOnly` if either file was added with
+    /// `add_virtual`, and with `Error::DuplicatePath` if `dest` is the file of
+    /// `span`.
+    pub fn move_range_to_file(&self, span: &VfsSpan, dest: &Path) -> Result<(), Error> {
+        self.0.move_range_to_file(span, dest)
+    }
+
     pub fn load_file(&self, path: &Path) -> Result<FileContents, Error> {
         self.0.load_file(path)
     }
 
+    /// Return the last `CHANGE_HISTORY_LEN` changes applied to any file, oldest
+    /// first, with the path and version of the file after each change. Unlike
+    /// `recent_changes`, the history is kept when files are removed from the
+    /// VFS, and it omits most of the text of large changes.
+    pub fn change_history(&self) -> Vec<(PathBuf, u64, ChangeSummary)> {
+        self.0.change_history()
+    }
+
+    /// Return the current change sequence number and the paths of the files
+    /// changed since `seq`, sorted. The sequence number starts at zero and is
+    /// incremented by every change to the contents of a file, including
+    /// deleting it with `soft_delete` or discarding its changes with
+    /// `flush_file`; loading a file from disk is not a change. Pass the
+    /// returned number to the next call to get the files changed in between.
+    pub fn changed_since(&self, seq: u64) -> (u64, Vec<PathBuf>) {
+        self.0.changed_since(seq)
+    }
+
+    /// Return up to the last `n` changes applied to a cached file, oldest
+    /// first. Only a bounded number of recent changes are kept for each file,
+    /// and a file's changes are forgotten when it is replaced with `set_file`.
+    pub fn recent_changes(&self, path: &Path, n: usize) -> Result<Vec<Change>, Error> {
+        self.0.recent_changes(path, n)
+    }
+
+    /// Like `load_file`, but if the file is not cached, it is loaded on one of
+    /// a fixed number of threads shared by all VFSs, so polling the returned
+    /// future never blocks on IO. The VFS lock is only held briefly while
+    /// polling. The future keeps the VFS alive until it completes.
+    ///
+    /// Only available with the `async` feature.
+    #[cfg(feature = "async")]
+    pub fn load_file_async(
+        &self,
+        path: &Path,
+    ) -> impl Future<Output = Result<FileContents, Error>> + 'static
+    where
+        U: Send + 'static,
+    {
+        self.0.load_file_async(path)
+    }
+
+    /// Return the raw bytes of a file, whether it is a text or a binary file.
+    pub fn load_file_bytes(&self, path: &Path) -> Result<Vec<u8>, Error> {
+        self.0.load_file_bytes(path)
+    }
+
+    /// Call `f` with the text of a text file, loading it if necessary, without
+    /// copying the text, and return its result.
+    ///
+    /// Note that `f` should not be a long-running operation since we hold the
+    /// lock to the VFS while it runs.
+    pub fn read_file_cow<F, R>(&self, path: &Path, f: F) -> Result<R, Error>
+    where
+        F: FnOnce(&str) -> R,
+    {
+        self.0.read_file_cow(path, f)
+    }
+
+    /// Return the number of lines in the file. Whether a file ending in a
+    /// newline has an empty last line depends on the policy set with
+    /// `set_trailing_empty_line_policy`.
+    pub fn num_lines(&self, path: &Path) -> Result<usize, Error> {
+        self.0.num_lines(path)
+    }
+
     pub fn load_line(
         &self,
         path: &Path,
@@ -236,10 +806,27 @@ impl<U> Vfs<U> {
         self.0.load_lines(path, line_start, line_end)
     }
 
+    /// Return the text of the file from the start of `line` to the end.
+    pub fn load_from_line(
+        &self,
+        path: &Path,
+        line: span::Row<span::ZeroIndexed>,
+    ) -> Result<String, Error> {
+        self.0.load_from_line(path, line)
+    }
+
     pub fn load_span(&self, span: span::Span<span::ZeroIndexed>) -> Result<String, Error> {
         self.0.load_span(span)
     }
 
+    /// Return the text of a file between the byte offsets `start` and `end`.
+    /// Returns `Error::BadLocation` unless `start <= end <= len`, where `len`
+    /// is the length of the file, and both offsets are at character
+    /// boundaries.
+    pub fn slice(&self, path: &Path, start: usize, end: usize) -> Result<String, Error> {
+        self.0.slice(path, start, end)
+    }
+
     pub fn for_each_line<F>(&self, path: &Path, f: F) -> Result<(), Error>
     where
         F: FnMut(&str, usize) -> Result<(), Error>,
@@ -247,416 +834,3604 @@ impl<U> Vfs<U> {
         self.0.for_each_line(path, f)
     }
 
-    pub fn write_file(&self, path: &Path) -> Result<(), Error> {
-        self.0.write_file(path)
+    /// Call `f` with each line of a file, including its line terminator. If
+    /// the file is not cached, it is read from disk a line at a time and is
+    /// not added to the cache. As for `for_each_line`, a file ending in a
+    /// newline has an empty last line.
+    ///
+    /// Note that for cached files, `f` should not be a long-running operation
+    /// since we hold the lock to the VFS while it runs.
+    pub fn scan_lines<F>(&self, path: &Path, f: F) -> Result<(), Error>
+    where
+        F: FnMut(span::Row<span::ZeroIndexed>, &str),
+    {
+        self.0.scan_lines(path, f)
     }
 
-    pub fn set_user_data(&self, path: &Path, data: Option<U>) -> Result<(), Error> {
-        self.0.set_user_data(path, data)
+    /// Return the length of a line in the given text units, not counting the
+    /// line terminator.
+    pub fn line_char_count(
+        &self,
+        path: &Path,
+        line: span::Row<span::ZeroIndexed>,
+        encoding: PositionEncoding,
+    ) -> Result<u32, Error> {
+        self.0.line_char_count(path, line, encoding)
     }
 
-    // If f returns NoUserDataForFile, then the user data for the given file is erased.
-    pub fn with_user_data<F, R>(&self, path: &Path, f: F) -> Result<R, Error>
-    where
-        F: FnOnce(Result<(Option<&str>, &mut U), Error>) -> Result<R, Error>,
-    {
-        self.0.with_user_data(path, f)
+    /// Return the number of times `ch` occurs in a line, not counting the line
+    /// terminator.
+    pub fn char_count_on_line(
+        &self,
+        path: &Path,
+        line: span::Row<span::ZeroIndexed>,
+        ch: char,
+    ) -> Result<u32, Error> {
+        self.0.char_count_on_line(path, line, ch)
     }
 
-    // If f returns NoUserDataForFile, then the user data for the given file is erased.
-    pub fn ensure_user_data<F>(&self, path: &Path, f: F) -> Result<(), Error>
-    where
-        F: FnOnce(Option<&str>) -> Result<U, Error>,
-    {
-        self.0.ensure_user_data(path, f)
+    /// Return the byte offset in the file of the first character of a line
+    /// which is not whitespace, or `None` if the line is blank.
+    pub fn first_nonblank_offset(
+        &self,
+        path: &Path,
+        line: span::Row<span::ZeroIndexed>,
+    ) -> Result<Option<usize>, Error> {
+        self.0.first_nonblank_offset(path, line)
     }
 
-    pub fn clear(&self) {
-        self.0.clear()
+    /// Return the text of a line from `pos` to the end of the line, not
+    /// counting the line terminator. Columns are in the units of `encoding`.
+    /// Returns `Error::BadLocation` if `pos` is past the end of the line.
+    pub fn line_suffix(
+        &self,
+        path: &Path,
+        pos: span::Position<span::ZeroIndexed>,
+        encoding: PositionEncoding,
+    ) -> Result<String, Error> {
+        self.0.line_suffix(path, pos, encoding)
     }
-}
 
-// Important invariants! If you are going to lock both files and pending_files,
-// you must lock pending_files first.
-// You must have both locks to insert or remove files.
-struct VfsInternal<T, U> {
-    files: Mutex<HashMap<PathBuf, File<U>>>,
-    pending_files: Mutex<HashMap<PathBuf, Vec<Thread>>>,
-    loader: PhantomData<T>,
-}
+    /// Return the identifier at `pos` and its span, or `None` if `pos` is not
+    /// on an identifier character (alphanumeric or `_`). Columns count unicode
+    /// scalar values, unless the file's encoding is set to
+    /// `PositionEncoding::Grapheme` with `set_file_encoding`. Then they count
+    /// grapheme clusters, and a cluster starting with an identifier character
+    /// is part of the identifier, including any combining marks. Clusters are
+    /// only approximated, see `PositionEncoding::Grapheme`.
+    pub fn word_at(
+        &self,
+        path: &Path,
+        pos: span::Position<span::ZeroIndexed>,
+    ) -> Result<Option<(String, span::Span<span::ZeroIndexed>)>, Error> {
+        self.0.word_at(path, pos)
+    }
 
-impl<T: FileLoader, U> VfsInternal<T, U> {
-    fn new() -> VfsInternal<T, U> {
-        VfsInternal {
-            files: Mutex::new(HashMap::new()),
-            pending_files: Mutex::new(HashMap::new()),
-            loader: PhantomData,
-        }
+    /// Replace every occurrence in a file of the identifier at `pos`, as found
+    /// by `word_at`, with `new_name`, and return the number of occurrences.
+    /// Only whole identifiers are replaced, not identifiers containing the one
+    /// at `pos`. If there is no identifier at `pos`, nothing is replaced.
+    pub fn rename_word(
+        &self,
+        path: &Path,
+        pos: span::Position<span::ZeroIndexed>,
+        new_name: &str,
+    ) -> Result<usize, Error> {
+        self.0.rename_word(path, pos, new_name)
     }
 
-    fn clear(&self) {
-        let mut pending_files = self.pending_files.lock().unwrap();
-        let mut files = self.files.lock().unwrap();
-        *files = HashMap::new();
-        let mut new_pending_files = HashMap::new();
-        mem::swap(&mut *pending_files, &mut new_pending_files);
-        for ts in new_pending_files.values() {
-            for t in ts {
-                t.unpark();
-            }
-        }
+    /// Return the span from the innermost unmatched `open` before `pos` to its
+    /// matching `close`, including both brackets, or `None` if `pos` is not
+    /// inside a balanced pair. `open` and `close` must be different
+    /// characters. Columns count unicode scalar values.
+    pub fn enclosing_brackets(
+        &self,
+        path: &Path,
+        pos: span::Position<span::ZeroIndexed>,
+        open: char,
+        close: char,
+    ) -> Result<Option<span::Span<span::ZeroIndexed>>, Error> {
+        self.0.enclosing_brackets(path, pos, open, close)
     }
 
-    fn file_saved(&self, path: &Path) -> Result<(), Error> {
-        let mut files = self.files.lock().unwrap();
-        if let Some(ref mut f) = files.get_mut(path) {
-            match f.kind {
-                FileKind::Text(ref mut f) => f.changed = false,
-                FileKind::Binary(_) => return Err(Error::BadFileKind),
-            }
-        }
-        Ok(())
+    /// Return the byte range of every line in the file, including its line
+    /// terminator. The ranges cover the whole file without gaps; a file ending
+    /// in a newline has an empty last line, unless the trailing empty line
+    /// policy is `Drop`.
+    pub fn line_ranges(&self, path: &Path) -> Result<LineRanges, Error> {
+        self.0.line_ranges(path)
     }
 
-    fn flush_file(&self, path: &Path) -> Result<(), Error> {
-        loop {
-            let mut pending_files = self.pending_files.lock().unwrap();
-            let mut files = self.files.lock().unwrap();
-            if !pending_files.contains_key(path) {
-                files.remove(path);
-                return Ok(());
-            }
+    /// Return the byte offset just after each `\n` in the file, in order.
+    pub fn newline_offsets(&self, path: &Path) -> Result<Vec<usize>, Error> {
+        self.0.newline_offsets(path)
+    }
 
-            pending_files.get_mut(path).unwrap().push(thread::current());
-            thread::park();
-        }
+    /// Return the width of the longest line in the file, in unicode scalar
+    /// values and not counting line terminators.
+    pub fn max_line_width(&self, path: &Path) -> Result<u32, Error> {
+        self.0.max_line_width(path)
     }
 
-    fn file_is_synced(&self, path: &Path) -> Result<bool, Error> {
-        let files = self.files.lock().unwrap();
-        match files.get(path) {
-            Some(f) => Ok(!f.changed()),
-            None => Err(Error::FileNotCached),
-        }
+    /// Report which line endings the file uses. A file without any line
+    /// endings is reported as `LineEnding::Lf`.
+    pub fn line_ending(&self, path: &Path) -> Result<LineEnding, Error> {
+        self.0.line_ending(path)
     }
 
-    fn on_changes(&self, changes: &[Change]) -> Result<(), Error> {
-        trace!("on_changes: {:?}", changes);
-        for (file_name, changes) in coalesce_changes(changes) {
-            let path = Path::new(file_name);
-            {
-                let mut files = self.files.lock().unwrap();
-                if let Some(file) = files.get_mut(Path::new(path)) {
-                    file.make_change(&changes)?;
-                    continue;
-                }
-            }
+    /// Report how line `line` of the file ends.
+    pub fn line_terminator(
+        &self,
+        path: &Path,
+        line: span::Row<span::ZeroIndexed>,
+    ) -> Result<LineTerminator, Error> {
+        self.0.line_terminator(path, line)
+    }
 
-            // FIXME(#11): if the first change is `Add`, we should avoid
-            // loading the file. If the first change is not `Add`, then
-            // this is subtly broken, because we can't guarantee that the
-            // edits are intended to be applied to the version of the file
-            // we read from disk. That is, the on disk contents might have
-            // changed after the edit request.
-            let mut file = T::read(Path::new(path))?;
-            file.make_change(&changes)?;
+    /// Rewrite all line endings in a cached file to `ending`. If this changes
+    /// the text, the file is marked as changed and its user data is cleared.
+    /// Normalizing to `LineEnding::Mixed` leaves the file untouched.
+    pub fn normalize_line_endings(&self, path: &Path, ending: LineEnding) -> Result<(), Error> {
+        self.0.normalize_line_endings(path, ending)
+    }
 
-            let mut files = self.files.lock().unwrap();
-            files.insert(path.to_path_buf(), file);
-        }
+    /// Give `f` mutable access to the text of a cached file. Afterwards, the
+    /// file is re-indexed and marked as changed, and its user data is cleared.
+    ///
+    /// Note that `f` should not be a long-running operation since we hold the
+    /// lock to the VFS while it runs.
+    pub fn with_mut_text<F>(&self, path: &Path, f: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut String),
+    {
+        self.0.with_mut_text(path, f)
+    }
 
-        Ok(())
+    /// Replace every match of `pattern` in a cached text file with
+    /// `replacement`, which may refer to capture groups as in
+    /// `Regex::replace_all`, and return the number of matches. If there are
+    /// any, the file is re-indexed and marked as changed, and its user data is
+    /// cleared.
+    #[cfg(feature = "regex")]
+    pub fn regex_replace(
+        &self,
+        path: &Path,
+        pattern: &regex::Regex,
+        replacement: &str,
+    ) -> Result<usize, Error> {
+        self.0.regex_replace(path, pattern, replacement)
     }
 
-    fn set_file(&self, path: &Path, text: &str) {
-        let file = File {
-            kind: FileKind::Text(TextFile {
-                text: text.to_owned(),
-                line_indices: make_line_indices(text),
-                changed: true,
-            }),
-            user_data: None,
-        };
+    /// Apply a unified diff, e.g., the output of `diff -u`, to a cached text
+    /// file. If any hunk's context or removed lines do not match the file, or
+    /// the patch is malformed, the file is left unchanged and
+    /// `Error::BadLocation` is returned. Otherwise the file is re-indexed and
+    /// marked as changed, and its user data is cleared.
+    pub fn apply_patch(&self, path: &Path, patch: &str) -> Result<(), Error> {
+        self.0.apply_patch(path, patch)
+    }
 
-        loop {
-            let mut pending_files = self.pending_files.lock().unwrap();
-            let mut files = self.files.lock().unwrap();
-            if !pending_files.contains_key(path) {
-                files.insert(path.to_owned(), file);
-                return;
-            }
+    /// Call `f` with each line of a cached text file, as counted by
+    /// `num_lines`, and its text without the line terminator, and replace the
+    /// text of each line for which `f` returns `Some`. Returns the number of
+    /// lines replaced. The file is re-indexed once, after all the lines, and
+    /// if any line was replaced, it is marked as changed and its user data is
+    /// cleared.
+    pub fn transform_lines<F>(&self, path: &Path, f: F) -> Result<usize, Error>
+    where
+        F: Fn(span::Row<span::ZeroIndexed>, &str) -> Option<String>,
+    {
+        self.0.transform_lines(path, f)
+    }
 
-            pending_files.get_mut(path).unwrap().push(thread::current());
-            thread::park();
-        }
+    /// Compare a cached text file with its version on disk. Returns `None` if
+    /// they are the same, otherwise the line edits which turn the disk version
+    /// into the cached version.
+    pub fn diff_against_disk(&self, path: &Path) -> Result<Option<Vec<TextEdit>>, Error> {
+        self.0.diff_against_disk(path)
     }
 
-    fn get_cached_files(&self) -> HashMap<PathBuf, String> {
-        let files = self.files.lock().unwrap();
-        files
-            .iter()
-            .filter_map(|(p, f)| match f.kind {
-                FileKind::Text(ref f) => Some((p.clone(), f.text.clone())),
-                FileKind::Binary(_) => None,
-            }).collect()
+    /// Return the version of a cached file. The version starts at zero when a
+    /// file is loaded and is incremented every time its contents change.
+    pub fn file_version(&self, path: &Path) -> Result<u64, Error> {
+        self.0.file_version(path)
     }
 
-    fn get_changes(&self) -> HashMap<PathBuf, String> {
-        let files = self.files.lock().unwrap();
-        files
-            .iter()
-            .filter_map(|(p, f)| match f.kind {
-                FileKind::Text(ref f) if f.changed => Some((p.clone(), f.text.clone())),
-                _ => None,
-            }).collect()
+    /// Return a hash of the contents of a cached file at `version`, or `None`
+    /// if the file is not cached or the hash of that version is not known. The
+    /// hash of the current version is always known; those of the last
+    /// `VERSION_HASH_LEN` earlier versions are known if version hashes were
+    /// enabled for the file, see `enable_version_hashes`. Hashes are only
+    /// comparable between programs built with the same version of Rust.
+    pub fn hash_at_version(&self, path: &Path, version: u64) -> Option<u64> {
+        self.0.hash_at_version(path, version)
     }
 
-    fn has_changes(&self) -> bool {
-        let files = self.files.lock().unwrap();
-        files.values().any(|f| f.changed())
+    /// Replace the text of a cached text file with `text`, but only if its
+    /// version is still `expected_version`, and return whether it was
+    /// replaced. If it was, the file is marked as changed, its version is
+    /// incremented, and its user data is cleared, as for `with_mut_text`.
+    pub fn replace_if_version(
+        &self,
+        path: &Path,
+        expected_version: u64,
+        text: &str,
+    ) -> Result<bool, Error> {
+        self.0.replace_if_version(path, expected_version, text)
     }
 
-    fn load_line(&self, path: &Path, line: span::Row<span::ZeroIndexed>) -> Result<String, Error> {
-        self.ensure_file(path, |f| f.load_line(line).map(|s| s.to_owned()))
+    /// Read a cached file without in-memory edits from disk (or its registered
+    /// loader) again. If its contents have not changed, its user data and
+    /// version are kept. Files with in-memory edits are not reloaded, and cause
+    /// `Error::UncommittedChanges`.
+    pub fn reload_file(&self, path: &Path) -> Result<(), Error> {
+        self.0.reload_file(path)
     }
 
-    fn load_lines(
+    /// Return when a cached file was loaded, or when its contents were last
+    /// changed in memory, whichever is later.
+    pub fn last_modified(&self, path: &Path) -> Result<SystemTime, Error> {
+        self.0.last_modified(path)
+    }
+
+    /// Write a cached file to disk, or to its registered loader if that stores
+    /// files (see `FileSource::write`), and mark it as unchanged. Since the
+    /// contents don't change, the file's user data and version are left intact.
+    pub fn write_file(&self, path: &Path) -> Result<(), Error> {
+        self.0.write_file(path)
+    }
+
+    /// Serve files under the directory `prefix` from `loader` instead of the
+    /// disk. If several registered prefixes match a path, the longest one
+    /// wins. Registering a loader for the same prefix again replaces it.
+    pub fn register_loader(&self, prefix: &Path, loader: Box<dyn FileSource>) {
+        self.0.register_loader(prefix, loader)
+    }
+
+    /// Serve the file at `path`, which need not exist on disk, e.g., `<stdin>`,
+    /// with contents `text`. The file is read-only: changing or writing it
+    /// fails with `Error::ReadOnly`. Adding the same path again replaces its
+    /// contents, discarding any cached copy.
+    pub fn add_virtual(&self, path: &Path, text: String) {
+        self.0.add_virtual(path, text)
+    }
+
+    /// Treat `path` as a scratch buffer, e.g., an editor's untitled buffer,
+    /// which only exists in memory. Reading it fails with
+    /// `Error::FileNotCached` until it is added with `Change::AddFile`, and
+    /// `write_file` does nothing for it. Any cached copy is discarded.
+    pub fn register_scratch(&self, path: &Path) {
+        self.0.register_scratch(path)
+    }
+
+    /// If set, `on_changes` rejects a batch of changes with
+    /// `Error::UnknownFile` if any change edits a file which is neither cached
+    /// nor added earlier in the batch, rather than loading it from disk. No
+    /// changes from a rejected batch are applied.
+    pub fn set_validate_changes(&self, validate: bool) {
+        self.0.set_validate_changes(validate)
+    }
+
+    /// If set, the line endings of text inserted by `Change::ReplaceText` are
+    /// converted to the line ending most common in the file, so that edits do
+    /// not mix line endings.
+    pub fn set_preserve_line_endings(&self, preserve: bool) {
+        self.0.set_preserve_line_endings(preserve)
+    }
+
+    /// If set, a `Change::ReplaceText` which inserts at the start of a file
+    /// which is neither cached nor on disk creates the file, as if it were
+    /// empty, rather than failing to read it.
+    pub fn set_create_missing_files(&self, create: bool) {
+        self.0.set_create_missing_files(create)
+    }
+
+    /// Set the directory relative paths are resolved against, which should be
+    /// absolute. The paths of changes passed to `on_changes`, and of files
+    /// read with `load_file` and other methods which load a file if it is not
+    /// cached, are then joined to the root, so the file is cached under its
+    /// absolute path. Other methods take paths as they are. If `None`, the
+    /// default, relative paths are used as they are.
+    pub fn set_root(&self, root: Option<PathBuf>) {
+        self.0.set_root(root)
+    }
+
+    /// Verify files against the given SHA-256 checksums when they are read
+    /// from disk (or a registered loader). Reading a file whose contents do not
+    /// match its checksum fails with `Error::ChecksumMismatch`; files without
+    /// an entry are not checked. Replaces any previously set checksums.
+    pub fn set_expected_checksums(&self, checksums: HashMap<PathBuf, [u8; 32]>) {
+        self.0.set_expected_checksums(checksums)
+    }
+
+    /// Measure the columns of changes to a cached file in `encoding`, rather
+    /// than in the units of the changes' spans. The setting is kept when the
+    /// file's contents are replaced, but not once it is evicted from the VFS.
+    pub fn set_file_encoding(&self, path: &Path, encoding: PositionEncoding) -> Result<(), Error> {
+        self.0.set_file_encoding(path, encoding)
+    }
+
+    /// Record the hash of each version of every file changed from now on, see
+    /// `hash_at_version`. This hashes a file's contents whenever they change.
+    pub fn enable_version_hashes(&self) {
+        self.0.enable_version_hashes()
+    }
+
+    /// Keep an undo stack for every file changed from now on, see `undo`.
+    pub fn enable_undo(&self) {
+        self.0.enable_undo()
+    }
+
+    /// Keep an undo stack for a cached file, see `undo`.
+    pub fn enable_file_undo(&self, path: &Path) -> Result<(), Error> {
+        self.0.enable_file_undo(path)
+    }
+
+    /// Revert the most recent change to a file made since undo was enabled for
+    /// it. Returns `false` if there is no change to revert. Only a bounded
+    /// number of changes are kept, and a file's history is lost when it is
+    /// replaced with `set_file` or evicted from the VFS, or when its text is
+    /// replaced other than by `on_changes`, e.g., by `with_mut_text`.
+    pub fn undo(&self, path: &Path) -> Result<bool, Error> {
+        self.0.undo(path)
+    }
+
+    /// Reapply the most recent change reverted by `undo`. Returns `false` if
+    /// there is no change to reapply. Making a new change to the file, or
+    /// marking it as saved, discards the changes which could be reapplied.
+    pub fn redo(&self, path: &Path) -> Result<bool, Error> {
+        self.0.redo(path)
+    }
+
+    /// Refuse to read files from disk which are larger than `limit` bytes,
+    /// returning `Error::FileTooLarge` instead. `None` removes the limit.
+    pub fn set_max_file_size(&self, limit: Option<usize>) {
+        self.0.set_max_file_size(limit)
+    }
+
+    /// Read at most `limit` files from disk, or from registered loaders, at
+    /// once; other threads wait for a read to finish before starting theirs.
+    /// `None`, the default, removes the limit, and `Some(0)` is treated as
+    /// `Some(1)`.
+    pub fn set_read_concurrency(&self, limit: Option<usize>) {
+        self.0.set_read_concurrency(limit)
+    }
+
+    /// Choose how to read files from disk which are not valid UTF-8.
+    pub fn set_utf8_error_mode(&self, mode: Utf8ErrorMode) {
+        self.0.set_utf8_error_mode(mode)
+    }
+
+    /// If set, files whose path ends in `.gz` or which start with the gzip
+    /// magic bytes are decompressed when read, and compressed again when
+    /// written. Their cached text is the decompressed text. The setting is
+    /// independent of `set_write_options`.
+    #[cfg(feature = "flate2")]
+    pub fn set_gzip(&self, enabled: bool) {
+        self.0.set_gzip(enabled)
+    }
+
+    /// Choose whether a file ending in a newline has an empty last line, as
+    /// seen by `num_lines`, `load_line`, and `line_ranges`. Other methods
+    /// always treat such files as having an empty last line.
+    pub fn set_trailing_empty_line_policy(&self, policy: TrailingEmptyLinePolicy) {
+        self.0.set_trailing_empty_line_policy(policy)
+    }
+
+    /// Remember the editor's selections in a cached file, e.g., to prioritize
+    /// analysis of the region the user is working on. Empty ranges represent
+    /// carets. Replaces any previous selections.
+    ///
+    /// Selections are shifted by changes made with `on_changes`, and should use
+    /// the same column units as those changes. They are cleared if the file's
+    /// text is changed in other ways, e.g., by `undo` or `with_mut_text`.
+    pub fn set_selections(
         &self,
         path: &Path,
-        line_start: span::Row<span::ZeroIndexed>,
-        line_end: span::Row<span::ZeroIndexed>,
-    ) -> Result<String, Error> {
-        self.ensure_file(path, |f| {
-            f.load_lines(line_start, line_end).map(|s| s.to_owned())
-        })
+        selections: Vec<span::Range<span::ZeroIndexed>>,
+    ) -> Result<(), Error> {
+        self.0.set_selections(path, selections)
     }
 
-    fn load_span(&self, span: span::Span<span::ZeroIndexed>) -> Result<String, Error> {
-        self.ensure_file(&span.file, |f| {
-            f.load_range(span.range).map(|s| s.to_owned())
-        })
+    /// Return the selections in a cached file, see `set_selections`.
+    pub fn selections(&self, path: &Path) -> Result<Vec<span::Range<span::ZeroIndexed>>, Error> {
+        self.0.selections(path)
     }
 
-    fn for_each_line<F>(&self, path: &Path, f: F) -> Result<(), Error>
+    /// Set the metadata tag `key` of a cached file to `value`, e.g., the
+    /// file's language id. Unlike user data, metadata is kept when the file
+    /// changes. It is lost when the file is removed from the VFS.
+    pub fn set_metadata(&self, path: &Path, key: &str, value: &str) -> Result<(), Error> {
+        self.0.set_metadata(path, key, value)
+    }
+
+    /// Return the metadata tag `key` of a cached file, see `set_metadata`.
+    pub fn get_metadata(&self, path: &Path, key: &str) -> Result<Option<String>, Error> {
+        self.0.get_metadata(path, key)
+    }
+
+    /// Set the options used by `write_file`.
+    pub fn set_write_options(&self, options: WriteOptions) {
+        self.0.set_write_options(options)
+    }
+
+    pub fn set_user_data(&self, path: &Path, data: Option<U>) -> Result<(), Error> {
+        self.0.set_user_data(path, data)
+    }
+
+    // If f returns NoUserDataForFile, then the user data for the given file is erased.
+    pub fn with_user_data<F, R>(&self, path: &Path, f: F) -> Result<R, Error>
     where
-        F: FnMut(&str, usize) -> Result<(), Error>,
+        F: FnOnce(Result<(Option<&str>, &mut U), Error>) -> Result<R, Error>,
     {
-        self.ensure_file(path, |file| file.for_each_line(f))
+        self.0.with_user_data(path, f)
     }
 
-    fn load_file(&self, path: &Path) -> Result<FileContents, Error> {
-        self.ensure_file(path, |f| Ok(f.contents()))
+    // If f returns NoUserDataForFile, then the user data for the given file is erased.
+    pub fn ensure_user_data<F>(&self, path: &Path, f: F) -> Result<(), Error>
+    where
+        F: FnOnce(Option<&str>) -> Result<U, Error>,
+    {
+        self.0.ensure_user_data(path, f)
     }
 
-    fn ensure_file<F, R>(&self, path: &Path, f: F) -> Result<R, Error>
+    /// Like `with_user_data`, but for several files at once, under a single
+    /// lock. `f` is given an entry for each of `paths`, in order, with the
+    /// file's text and user data, or an error if the file is not cached, has
+    /// no user data, or appears earlier in `paths` (`DuplicatePath`). Paths
+    /// are compared as the cache compares them. The user data of each file
+    /// whose entry is `Err(NoUserDataForFile)` when `f` returns is erased.
+    pub fn with_user_data_multi<F, R>(&self, paths: &[&Path], f: F) -> R
     where
-        F: FnOnce(&File<U>) -> Result<R, Error>,
+        F: FnOnce(&mut [UserDataEntry<'_, '_, U>]) -> R,
+    {
+        self.0.with_user_data_multi(paths, f)
+    }
+
+    /// Like `with_user_data`, but if the file has no user data, it is first set
+    /// to `U::default()`.
+    // If f returns NoUserDataForFile, then the user data for the given file is erased.
+    pub fn with_user_data_or_default<F, R>(&self, path: &Path, f: F) -> Result<R, Error>
+    where
+        U: Default,
+        F: FnOnce(Option<&str>, &mut U) -> Result<R, Error>,
+    {
+        self.0.with_user_data_or_default(path, f)
+    }
+
+    /// Recompute the user data for every cached text file using `f`. Binary
+    /// files are skipped. If `f` returns an error, recomputation stops and the
+    /// error is returned; files visited so far keep their new data.
+    ///
+    /// Note that `f` should not be a long-running operation since we hold the
+    /// lock to the VFS while it runs.
+    pub fn recompute_all_user_data<F>(&self, f: F) -> Result<(), Error>
+    where
+        F: Fn(&Path, &str) -> Result<Option<U>, Error>,
+    {
+        self.0.recompute_all_user_data(f)
+    }
+
+    /// Clear the user data of every cached text file for which `f` returns
+    /// true, given the file's path, text, and user data. Binary files and
+    /// files without user data are skipped.
+    ///
+    /// Note that `f` should not be a long-running operation since we hold the
+    /// lock to the VFS while it runs.
+    pub fn invalidate_user_data_where<F>(&self, f: F)
+    where
+        F: Fn(&Path, &str, &U) -> bool,
     {
+        self.0.invalidate_user_data_where(f)
+    }
+
+    /// Index the lines of a file on disk without reading its contents into
+    /// memory. Subsequent `load_line`, `load_lines`, and `load_span` requests
+    /// for the file read just the requested bytes from disk. The file is
+    /// fully loaded as usual as soon as it is edited or loaded as a whole.
+    ///
+    /// Does nothing if the file is already cached.
+    pub fn open_paged(&self, path: &Path) -> Result<(), Error> {
+        self.0.open_paged(path)
+    }
+
+    /// Return the cached files without in-memory edits whose contents on disk
+    /// (or in a registered loader) differ from the cache, including files
+    /// which can no longer be read. Files whose modification time and size
+    /// on disk are unchanged since they were loaded are assumed to be in sync.
+    pub fn out_of_sync_files(&self) -> Vec<PathBuf> {
+        self.0.out_of_sync_files()
+    }
+
+    /// Return statistics about the VFS's activity.
+    pub fn stats(&self) -> VfsStats {
+        self.0.stats()
+    }
+
+    /// Check the internal consistency of every cached file, returning
+    /// `Error::InternalError` if there is a problem. This is a bug in the VFS,
+    /// and is intended for use in tests. Debug builds also check files after
+    /// every edit.
+    pub fn check_invariants(&self) -> Result<(), Error> {
+        self.0.check_invariants()
+    }
+
+    pub fn clear(&self) {
+        self.0.clear()
+    }
+}
+
+/// Options controlling how files are written to disk.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WriteOptions {
+    /// Create any missing parent directories of a file being written, e.g.,
+    /// if they were deleted after the file was cached. If unset, writing to a
+    /// missing directory is an `Error::Io` naming the directory.
+    pub create_dirs: bool,
+    /// Delete a text file from disk, rather than writing it, if it is empty or
+    /// contains only whitespace. The file is also removed from the VFS.
+    pub delete_if_empty: bool,
+    /// Wait for the file's contents to reach the disk before returning, so a
+    /// saved file survives a crash or power loss. This can make writing much
+    /// slower, especially on spinning disks or network file systems.
+    pub fsync: bool,
+}
+
+// Options controlling how files are read from disk.
+#[derive(Debug, Clone, Default)]
+struct ReadOptions {
+    max_file_size: Option<usize>,
+    utf8_errors: Utf8ErrorMode,
+}
+
+// Options controlling how changes are applied to a file.
+#[derive(Debug, Clone, Copy)]
+struct ChangeOptions {
+    undo: bool,
+    version_hashes: bool,
+    preserve_line_endings: bool,
+}
+
+/// How to read files which are not valid UTF-8, see
+/// `Vfs::set_utf8_error_mode`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Utf8ErrorMode {
+    /// Read such files as binary files.
+    #[default]
+    Strict,
+    /// Read such files as text, replacing invalid sequences with U+FFFD.
+    /// Writing such a file fails with `Error::LossilyDecoded`, unless its
+    /// contents have since been replaced with `Change::AddFile`.
+    Lossy,
+}
+
+/// Whether a file ending in a newline has an empty line after it, see
+/// `Vfs::set_trailing_empty_line_policy`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TrailingEmptyLinePolicy {
+    /// A file ending in a newline has an empty last line, so `"a\n"` has two
+    /// lines.
+    #[default]
+    Keep,
+    /// A final newline ends the last line, so `"a\n"` has one line.
+    Drop,
+}
+
+// Settings of a VFS which may be changed while it is in use.
+#[derive(Default)]
+struct Config {
+    read_options: ReadOptions,
+    // Decompress gzip-compressed files when reading them, and compress them
+    // when writing them, see `Vfs::set_gzip`. Only set with the `flate2`
+    // feature.
+    gzip: bool,
+    write_options: WriteOptions,
+    validate_changes: bool,
+    expected_checksums: HashMap<PathBuf, [u8; 32]>,
+    undo: bool,
+    // See `Vfs::enable_version_hashes`.
+    version_hashes: bool,
+    // Buffer changes until there are this many, see `Vfs::with_coalescing`.
+    coalescing_window: Option<usize>,
+    // Paths added with `add_virtual`, compared as the cache compares them.
+    virtual_paths: PathMap<()>,
+    // Paths registered with `register_scratch`.
+    scratch_paths: PathMap<()>,
+    preserve_line_endings: bool,
+    // See `Vfs::set_create_missing_files`.
+    create_missing_files: bool,
+    trailing_empty_line: TrailingEmptyLinePolicy,
+    // See `Vfs::set_root`.
+    root: Option<PathBuf>,
+}
+
+impl Config {
+    fn change_options(&self) -> ChangeOptions {
+        ChangeOptions {
+            undo: self.undo,
+            version_hashes: self.version_hashes,
+            preserve_line_endings: self.preserve_line_endings,
+        }
+    }
+}
+
+/// The number of earlier versions of a file whose hashes are kept, see
+/// `Vfs::hash_at_version`.
+pub const VERSION_HASH_LEN: usize = 32;
+
+/// The number of changes kept in the history returned by
+/// `Vfs::change_history`.
+pub const CHANGE_HISTORY_LEN: usize = 256;
+
+/// The number of bytes of a change's text kept in its `ChangeSummary`.
+pub const CHANGE_SUMMARY_TEXT_LEN: usize = 80;
+
+/// A change in the history returned by `Vfs::change_history`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeSummary {
+    /// The span which was replaced, or `None` if the whole file was replaced
+    /// by a `Change::AddFile`.
+    pub span: Option<VfsSpan>,
+    /// The length of the new text, in bytes.
+    pub text_len: usize,
+    /// The start of the new text, at most `CHANGE_SUMMARY_TEXT_LEN` bytes and
+    /// cut at a character boundary.
+    pub text: String,
+}
+
+impl ChangeSummary {
+    fn new(change: &Change) -> ChangeSummary {
+        let (span, text) = match *change {
+            Change::AddFile { ref text, .. } => (None, text),
+            Change::ReplaceText { ref span, ref text } => (Some(span.clone()), text),
+        };
+        let mut end = text.len().min(CHANGE_SUMMARY_TEXT_LEN);
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        ChangeSummary {
+            span,
+            text_len: text.len(),
+            text: text[..end].to_owned(),
+        }
+    }
+}
+
+/// Statistics about the activity of a VFS.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VfsStats {
+    /// Bytes read from disk to serve requests for files opened with
+    /// `open_paged`, not counting the initial scan of their line endings.
+    pub paged_bytes_read: u64,
+    /// The number of times a batch of changes has been applied to a file,
+    /// re-indexing it.
+    pub change_batches: u64,
+    /// The number of times part of a file's line index was rebuilt while
+    /// applying a batch of changes, after changes which added or removed
+    /// lines. This happens once per batch, unless a change follows one which
+    /// added or removed lines before it.
+    pub line_index_rebuilds: u64,
+    /// The number of changes with a UTF-16 column between the two halves of a
+    /// surrogate pair, which was clamped to the start of the character.
+    pub clamped_changes: u64,
+}
+
+// Important invariants! If you are going to lock both files and pending_files,
+// you must lock pending_files first. Lock buffered_changes before either.
+// You must have both locks to insert or remove files.
+// If you lock paged_files, stats, config, sources, tombstones, or history with
+// other locks, lock them last.
+struct VfsInternal<T, U> {
+    files: Mutex<PathMap<File<U>>>,
+    pending_files: Mutex<PathMap<Vec<Thread>>>,
+    // Changes waiting to be applied, see `Vfs::with_coalescing`.
+    buffered_changes: Mutex<Vec<Change>>,
+    // Files opened with `open_paged` which have not been fully loaded since.
+    paged_files: Mutex<PathMap<PagedFile>>,
+    stats: Mutex<VfsStats>,
+    config: Mutex<Config>,
+    // Sources registered with `register_loader`, and the directories they serve.
+    sources: Mutex<Vec<(PathBuf, Arc<dyn FileSource>)>>,
+    // Files removed with `soft_delete`. Expired tombstones are dropped lazily.
+    tombstones: Mutex<HashMap<PathBuf, Tombstone<U>>>,
+    // The most recent changes to all files, see `Vfs::change_history`.
+    history: Mutex<VecDeque<(PathBuf, u64, ChangeSummary)>>,
+    change_seqs: Mutex<ChangeSeqs>,
+    read_limit: ReadLimit,
+    loader: PhantomData<T>,
+}
+
+// Limits how many files are read at once, see `Vfs::set_read_concurrency`.
+#[derive(Default)]
+struct ReadLimit {
+    // The maximum number of reads, if any, and the number in progress.
+    state: Mutex<(Option<usize>, usize)>,
+    finished: Condvar,
+}
+
+impl ReadLimit {
+    fn set_limit(&self, limit: Option<usize>) {
+        self.state.lock().unwrap().0 = limit;
+        // Waiting readers may now be allowed to start.
+        self.finished.notify_all();
+    }
+
+    // Wait until another read is allowed to start. The read lasts until the
+    // returned permit is dropped.
+    fn acquire(&self) -> ReadPermit<'_> {
+        let mut state = self.state.lock().unwrap();
+        while state.0.is_some_and(|limit| state.1 >= limit) {
+            state = self.finished.wait(state).unwrap();
+        }
+        state.1 += 1;
+        ReadPermit(self)
+    }
+}
+
+struct ReadPermit<'a>(&'a ReadLimit);
+
+impl Drop for ReadPermit<'_> {
+    fn drop(&mut self) {
+        self.0.state.lock().unwrap().1 -= 1;
+        self.0.finished.notify_one();
+    }
+}
+
+// The sequence number of the latest change to any file, and of the latest
+// change to each file, see `Vfs::changed_since`.
+#[derive(Default)]
+struct ChangeSeqs {
+    seq: u64,
+    files: PathMap<u64>,
+}
+
+/// How long a file removed with `Vfs::soft_delete` can be restored for.
+pub const TOMBSTONE_RETENTION: Duration = Duration::from_secs(60);
+
+// A file removed with `soft_delete`, kept in case it is added again.
+struct Tombstone<U> {
+    deleted: Instant,
+    file: File<U>,
+}
+
+impl<T: FileLoader, U> VfsInternal<T, U> {
+    fn new() -> VfsInternal<T, U> {
+        VfsInternal {
+            files: Mutex::new(PathMap::new()),
+            pending_files: Mutex::new(PathMap::new()),
+            buffered_changes: Mutex::new(vec![]),
+            paged_files: Mutex::new(PathMap::new()),
+            stats: Mutex::new(VfsStats::default()),
+            config: Mutex::new(Config::default()),
+            sources: Mutex::new(vec![]),
+            tombstones: Mutex::new(HashMap::new()),
+            history: Mutex::new(VecDeque::new()),
+            change_seqs: Mutex::new(ChangeSeqs::default()),
+            read_limit: ReadLimit::default(),
+            loader: PhantomData,
+        }
+    }
+
+    fn with_loader_chain(loaders: Vec<Box<dyn FileSource>>) -> VfsInternal<T, U> {
+        let vfs = VfsInternal::new();
+        // An empty prefix matches every path, but is the shortest possible
+        // prefix, so loaders registered later still take precedence.
+        vfs.register_loader(Path::new(""), Box::new(LoaderChain(loaders)));
+        vfs
+    }
+
+    fn with_coalescing(window: usize) -> VfsInternal<T, U> {
+        let vfs = VfsInternal::new();
+        vfs.config.lock().unwrap().coalescing_window = Some(window);
+        vfs
+    }
+
+    fn clear(&self) {
+        self.paged_files.lock().unwrap().clear();
+        self.tombstones.lock().unwrap().clear();
+        let mut pending_files = self.pending_files.lock().unwrap();
+        let mut files = self.files.lock().unwrap();
+        *files = PathMap::new();
+        let mut new_pending_files = PathMap::new();
+        mem::swap(&mut *pending_files, &mut new_pending_files);
+        for ts in new_pending_files.values() {
+            for t in ts {
+                t.unpark();
+            }
+        }
+    }
+
+    fn file_saved(&self, path: &Path) -> Result<(), Error> {
+        match self.mark_clean(path) {
+            Err(Error::FileNotCached) => Ok(()),
+            result => result,
+        }
+    }
+
+    fn mark_clean(&self, path: &Path) -> Result<(), Error> {
+        // We should not hold the lock while we look at the disk.
+        let stamp = T::stamp(path);
+        let mut files = self.files.lock().unwrap();
+        let f = files.get_mut(path).ok_or(Error::FileNotCached)?;
+        match f.kind {
+            FileKind::Text(ref mut f) => f.changed = false,
+            FileKind::Binary(_) => return Err(Error::BadFileKind),
+        }
+        f.disk_stamp = stamp;
+        if let Some(ref mut undo) = f.undo {
+            undo.redo.clear();
+        }
+        Ok(())
+    }
+
+    fn flush_file(&self, path: &Path) -> Result<(), Error> {
+        self.paged_files.lock().unwrap().remove(path);
+        self.tombstones.lock().unwrap().remove(path);
         loop {
             {
                 let mut pending_files = self.pending_files.lock().unwrap();
-                let files = self.files.lock().unwrap();
-                if files.contains_key(path) {
-                    return f(&files[path]);
-                }
+                let mut files = self.files.lock().unwrap();
                 if !pending_files.contains_key(path) {
-                    pending_files.insert(path.to_owned(), vec![]);
-                    break;
+                    if files.remove(path).is_some_and(|f| f.changed()) {
+                        self.record_change_seq(path);
+                    }
+                    return Ok(());
                 }
                 pending_files.get_mut(path).unwrap().push(thread::current());
             }
             thread::park();
         }
+    }
 
-        // We should not hold the locks while we read from disk.
-        let file = T::read(path);
+    fn flush_under(&self, prefix: &Path) -> Result<usize, Error> {
+        let paths = self.paths_under(prefix);
+        for path in &paths {
+            self.flush_file(path)?;
+        }
+        Ok(paths.len())
+    }
 
-        // Need to re-get the locks here.
-        let mut pending_files = self.pending_files.lock().unwrap();
-        let mut files = self.files.lock().unwrap();
-        match file {
-            Ok(file) => {
-                files.insert(path.to_owned(), file);
-                let ts = pending_files.remove(path).unwrap();
-                for t in ts {
-                    t.unpark();
+    fn paths_under(&self, prefix: &Path) -> Vec<PathBuf> {
+        let files = self.files.lock().unwrap();
+        // `Path::starts_with` compares whole components.
+        files
+            .keys()
+            .filter(|p| p.starts_with(prefix))
+            .cloned()
+            .collect()
+    }
+
+    fn common_root(&self) -> Option<PathBuf> {
+        let files = self.files.lock().unwrap();
+        let mut dirs = files.keys().map(|p| p.parent().unwrap_or(p));
+        let mut root = dirs.next()?.to_owned();
+        for dir in dirs {
+            let len = root
+                .components()
+                .zip(dir.components())
+                .take_while(|(a, b)| a == b)
+                .count();
+            root = root.components().take(len).collect();
+        }
+        if root.as_os_str().is_empty() {
+            None
+        } else {
+            Some(root)
+        }
+    }
+
+    fn with_paths<F>(&self, f: F)
+    where
+        F: FnMut(&Path),
+    {
+        let files = self.files.lock().unwrap();
+        files.keys().map(PathBuf::as_path).for_each(f);
+    }
+
+    fn file_is_synced(&self, path: &Path) -> Result<bool, Error> {
+        let files = self.files.lock().unwrap();
+        match files.get(path) {
+            Some(f) => Ok(!f.changed()),
+            None => Err(Error::FileNotCached),
+        }
+    }
+
+    fn is_cached(&self, path: &Path) -> bool {
+        self.files.lock().unwrap().contains_key(path)
+    }
+
+    fn file_state(&self, path: &Path) -> FileState {
+        let policy = self.trailing_empty_line_policy();
+        let files = self.files.lock().unwrap();
+        let file = match files.get(path) {
+            Some(f) => f,
+            None => return FileState::NotCached,
+        };
+        let bytes = file.kind.as_bytes().len();
+        if bytes == 0 {
+            return FileState::Empty;
+        }
+        FileState::NonEmpty {
+            lines: file.num_lines(policy).unwrap_or(0),
+            bytes,
+            changed: file.changed(),
+        }
+    }
+
+    fn exists_on_disk(&self, path: &Path) -> bool {
+        !self.is_virtual(path) && !self.is_scratch(path) && T::exists(path)
+    }
+
+    fn is_virtual(&self, path: &Path) -> bool {
+        self.config.lock().unwrap().virtual_paths.contains_key(path)
+    }
+
+    fn is_scratch(&self, path: &Path) -> bool {
+        self.config.lock().unwrap().scratch_paths.contains_key(path)
+    }
+
+    fn content_equals(&self, path: &Path, text: &str) -> Result<bool, Error> {
+        let files = self.files.lock().unwrap();
+        match files.get(path) {
+            Some(f) => Ok(match f.kind {
+                FileKind::Text(ref t) => *t.text == text,
+                FileKind::Binary(_) => false,
+            }),
+            None => Err(Error::FileNotCached),
+        }
+    }
+
+    fn on_changes(&self, changes: &[Change]) -> Result<(), Error> {
+        let window = self.config.lock().unwrap().coalescing_window;
+        let window = match window {
+            Some(window) => window,
+            None => return self.apply_changes(changes, None, None),
+        };
+        // Keep the lock while applying the changes, so that batches are
+        // applied in order.
+        let mut buffered = self.buffered_changes.lock().unwrap();
+        buffered.extend_from_slice(changes);
+        if buffered.len() < window {
+            return Ok(());
+        }
+        self.apply_changes(&mem::take(&mut *buffered), None, None)
+    }
+
+    fn flush_pending(&self) -> Result<(), Error> {
+        let mut buffered = self.buffered_changes.lock().unwrap();
+        if buffered.is_empty() {
+            return Ok(());
+        }
+        self.apply_changes(&mem::take(&mut *buffered), None, None)
+    }
+
+    fn on_changes_with_mapping(&self, changes: &[Change]) -> Result<PositionMap, Error> {
+        self.flush_pending()?;
+        let mut map = PositionMap::default();
+        self.apply_changes(changes, Some(&mut map), None)?;
+        Ok(map)
+    }
+
+    fn on_changes_with_inverses(&self, changes: &[Change]) -> Result<Vec<Change>, Error> {
+        self.flush_pending()?;
+        let mut inverses = vec![];
+        self.apply_changes(changes, None, Some(&mut inverses))?;
+        inverses.reverse();
+        Ok(inverses)
+    }
+
+    fn preview_change(&self, change: &Change) -> Result<(Range<usize>, usize), Error> {
+        self.flush_pending()?;
+        let preserve_line_endings = self.config.lock().unwrap().preserve_line_endings;
+        self.ensure_file(change.file(), |f| match f.kind {
+            FileKind::Text(ref t) => {
+                let line_ending = if preserve_line_endings {
+                    Some(t.dominant_line_ending())
+                } else {
+                    None
+                };
+                t.preview_change(change, f.encoding, line_ending)
+            }
+            FileKind::Binary(_) => Err(Error::BadFileKind),
+        })
+    }
+
+    fn undo_change(&self, path: &Path, inverse: &Change) -> Result<Change, Error> {
+        if inverse.file() != path {
+            return Err(Error::BadLocation);
+        }
+        let mut inverses = self.on_changes_with_inverses(std::slice::from_ref(inverse))?;
+        // Re-adding a file with its current contents does not change it.
+        Ok(inverses.pop().unwrap_or_else(|| inverse.clone()))
+    }
+
+    // Apply `changes`, recording the edits made to each file in `map`, and the
+    // changes which revert them in `inverses`, in the order they are made.
+    fn apply_changes(
+        &self,
+        changes: &[Change],
+        mut map: Option<&mut PositionMap>,
+        mut inverses: Option<&mut Vec<Change>>,
+    ) -> Result<(), Error> {
+        trace!("on_changes: {:?}", changes);
+        let root = self.config.lock().unwrap().root.clone();
+        let resolved: Vec<Change>;
+        let changes = match root {
+            Some(ref root) if changes.iter().any(|c| c.file().is_relative()) => {
+                // Joining an absolute path gives the path itself.
+                resolved = changes.iter().map(|c| c.with_file(root.join(c.file()))).collect();
+                &resolved[..]
+            }
+            _ => changes,
+        };
+        let (validate, options, create_missing) = {
+            let config = self.config.lock().unwrap();
+            let virtual_path = changes.iter().find(|c| config.virtual_paths.contains_key(c.file()));
+            if let Some(c) = virtual_path {
+                return Err(Error::ReadOnly(c.file().to_owned()));
+            }
+            (config.validate_changes, config.change_options(), config.create_missing_files)
+        };
+        if validate {
+            self.validate_changes(changes)?;
+        }
+        self.check_in_sync(changes)?;
+        for (file_name, changes) in coalesce_changes(changes) {
+            let path = Path::new(file_name);
+            let edits = map
+                .as_mut()
+                .map(|map| map.edits.entry(path.to_owned()).or_insert_with(Vec::new));
+            {
+                let mut files = self.files.lock().unwrap();
+                if let Some(file) = files.get_mut(Path::new(path)) {
+                    let inverses = inverses.as_deref_mut();
+                    self.change_file(path, file, &changes, edits, inverses, options)?;
+                    continue;
+                }
+            }
+
+            // A deleted file which is added again is restored.
+            let restored = match changes[0] {
+                Change::AddFile { .. } => self.take_tombstone(path),
+                Change::ReplaceText { .. } => None,
+            };
+            let is_restored = restored.is_some();
+            // FIXME(#11): if the first change is `Add`, we should avoid
+            // loading the file. If the first change is not `Add`, then
+            // this is subtly broken, because we can't guarantee that the
+            // edits are intended to be applied to the version of the file
+            // we read from disk. That is, the on disk contents might have
+            // changed after the edit request.
+            let mut file = match restored {
+                Some(file) => file,
+                // There is nothing to read for a scratch buffer.
+                None if self.is_scratch(path) && matches!(changes[0], Change::AddFile { .. }) => {
+                    File::new(FileKind::Text(TextFile::new(String::new(), false)))
+                }
+                None => match self.read_file(Path::new(path)) {
+                    Err(ref e)
+                        if create_missing
+                            && !matches!(*e, Error::Deleted(_))
+                            && inserts_at_start(changes[0])
+                            && !T::exists(path) =>
+                    {
+                        File::new(FileKind::Text(TextFile::new(String::new(), false)))
+                    }
+                    result => result?,
+                },
+            };
+            let inverses = inverses.as_deref_mut();
+            let changed = self.change_file(path, &mut file, &changes, edits, inverses, options)?;
+            // Restoring a deleted file is a change, even if its contents are
+            // the same, but loading one from disk is not.
+            if !changed && is_restored {
+                self.record_history(path, file.version, &changes);
+                self.record_change_seq(path);
+            }
+
+            {
+                let mut files = self.files.lock().unwrap();
+                files.insert(path.to_path_buf(), file);
+            }
+            self.paged_files.lock().unwrap().remove(path);
+        }
+
+        Ok(())
+    }
+
+    // Apply `changes`, which are all to `path`, to `file`, recording them if
+    // they change its version. Return whether they did.
+    fn change_file(
+        &self,
+        path: &Path,
+        file: &mut File<U>,
+        changes: &[&Change],
+        edits: Option<&mut Vec<PositionEdit>>,
+        inverses: Option<&mut Vec<Change>>,
+        options: ChangeOptions,
+    ) -> Result<bool, Error> {
+        if options.undo && file.undo.is_none() {
+            file.undo = Some(UndoStack::default());
+        }
+        if options.version_hashes && file.version_hashes.is_none() {
+            file.version_hashes = Some(VecDeque::new());
+        }
+        let counters = file.stat_counters();
+        let version = file.version;
+        file.make_change(changes, edits, inverses, options.preserve_line_endings)?;
+        self.record_batch_stats(counters, file);
+        // Re-adding a file with its current contents changes nothing.
+        if file.version == version {
+            return Ok(false);
+        }
+        self.record_history(path, file.version, changes);
+        self.record_change_seq(path);
+        Ok(true)
+    }
+
+    // Add `changes`, which were applied to `path` giving version `version`,
+    // to the change history.
+    fn record_history(&self, path: &Path, version: u64, changes: &[&Change]) {
+        let mut history = self.history.lock().unwrap();
+        for c in changes {
+            if history.len() == CHANGE_HISTORY_LEN {
+                history.pop_front();
+            }
+            history.push_back((path.to_owned(), version, ChangeSummary::new(c)));
+        }
+    }
+
+    fn change_history(&self) -> Vec<(PathBuf, u64, ChangeSummary)> {
+        self.history.lock().unwrap().iter().cloned().collect()
+    }
+
+    // Record that the contents of `path` changed, see `changed_since`.
+    fn record_change_seq(&self, path: &Path) {
+        let mut change_seqs = self.change_seqs.lock().unwrap();
+        change_seqs.seq += 1;
+        let seq = change_seqs.seq;
+        change_seqs.files.insert(path.to_owned(), seq);
+    }
+
+    fn changed_since(&self, seq: u64) -> (u64, Vec<PathBuf>) {
+        let change_seqs = self.change_seqs.lock().unwrap();
+        let mut paths: Vec<_> = change_seqs
+            .files
+            .iter()
+            .filter(|&(_, &s)| s > seq)
+            .map(|(p, _)| p.clone())
+            .collect();
+        paths.sort();
+        (change_seqs.seq, paths)
+    }
+
+    // Call `f` with the cached file at `path`, recording a change if `f`
+    // changes its version. Fails if the file is read-only.
+    fn modify_file<F, R>(&self, path: &Path, f: F) -> Result<R, Error>
+    where
+        F: FnOnce(&mut File<U>) -> Result<R, Error>,
+    {
+        if self.is_virtual(path) {
+            return Err(Error::ReadOnly(path.to_owned()));
+        }
+        let mut files = self.files.lock().unwrap();
+        let file = files.get_mut(path).ok_or(Error::FileNotCached)?;
+        let version = file.version;
+        let result = f(file);
+        if file.version != version {
+            self.record_change_seq(path);
+        }
+        result
+    }
+
+    // Count a batch of changes applied to `file`, given its `stat_counters`
+    // from before.
+    fn record_batch_stats(&self, before: (u64, u64), file: &File<U>) {
+        let after = file.stat_counters();
+        let mut stats = self.stats.lock().unwrap();
+        stats.change_batches += 1;
+        stats.line_index_rebuilds += after.0 - before.0;
+        stats.clamped_changes += after.1 - before.1;
+    }
+
+    fn soft_delete(&self, path: &Path) -> Result<(), Error> {
+        self.paged_files.lock().unwrap().remove(path);
+        let file = loop {
+            {
+                let mut pending_files = self.pending_files.lock().unwrap();
+                let mut files = self.files.lock().unwrap();
+                if !pending_files.contains_key(path) {
+                    break files.remove(path).ok_or(Error::FileNotCached)?;
+                }
+                pending_files.get_mut(path).unwrap().push(thread::current());
+            }
+            thread::park();
+        };
+
+        let mut tombstones = self.tombstones.lock().unwrap();
+        tombstones.retain(|_, t| t.deleted.elapsed() < TOMBSTONE_RETENTION);
+        let deleted = Instant::now();
+        tombstones.insert(path.to_owned(), Tombstone { deleted, file });
+        self.record_change_seq(path);
+        Ok(())
+    }
+
+    // Return whether `path` was deleted with `soft_delete` and not added since.
+    fn is_deleted(&self, path: &Path) -> bool {
+        let mut tombstones = self.tombstones.lock().unwrap();
+        match tombstones.get(path) {
+            Some(t) if t.deleted.elapsed() < TOMBSTONE_RETENTION => true,
+            Some(_) => {
+                tombstones.remove(path);
+                false
+            }
+            None => false,
+        }
+    }
+
+    // Remove the tombstone of `path`, returning the deleted file if it can
+    // still be restored.
+    fn take_tombstone(&self, path: &Path) -> Option<File<U>> {
+        let tombstone = self.tombstones.lock().unwrap().remove(path)?;
+        if tombstone.deleted.elapsed() < TOMBSTONE_RETENTION {
+            Some(tombstone.file)
+        } else {
+            None
+        }
+    }
+
+    fn set_file(&self, path: &Path, text: &str) -> Result<(), Error> {
+        if self.is_virtual(path) {
+            return Err(Error::ReadOnly(path.to_owned()));
+        }
+        let mut file = File::new(FileKind::Text(TextFile::new(text.to_owned(), true)));
+        self.tombstones.lock().unwrap().remove(path);
+
+        loop {
+            {
+                let mut pending_files = self.pending_files.lock().unwrap();
+                let mut files = self.files.lock().unwrap();
+                if !pending_files.contains_key(path) {
+                    if let Some(old) = files.get_mut(path) {
+                        file.version = old.version + 1;
+                        file.encoding = old.encoding;
+                        file.version_hashes = old.take_version_hashes();
+                    }
+                    files.insert(path.to_owned(), file);
+                    self.record_change_seq(path);
+                    return Ok(());
+                }
+                pending_files.get_mut(path).unwrap().push(thread::current());
+            }
+            thread::park();
+        }
+    }
+
+    fn set_files<I>(&self, entries: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = (PathBuf, String)>,
+    {
+        let new_files: Vec<(PathBuf, File<U>)> = entries
+            .into_iter()
+            .map(|(path, text)| (path, File::new(FileKind::Text(TextFile::new(text, true)))))
+            .collect();
+        if let Some((path, _)) = new_files.iter().find(|(path, _)| self.is_virtual(path)) {
+            return Err(Error::ReadOnly(path.clone()));
+        }
+        {
+            let mut tombstones = self.tombstones.lock().unwrap();
+            for (path, _) in &new_files {
+                tombstones.remove(path);
+            }
+        }
+
+        loop {
+            {
+                let mut pending_files = self.pending_files.lock().unwrap();
+                let mut files = self.files.lock().unwrap();
+                let pending_path = new_files
+                    .iter()
+                    .map(|(path, _)| path)
+                    .find(|path| pending_files.contains_key(path))
+                    .cloned();
+                match pending_path {
+                    Some(path) => pending_files.get_mut(&path).unwrap().push(thread::current()),
+                    None => {
+                        for (path, mut file) in new_files {
+                            if let Some(old) = files.get_mut(&path) {
+                                file.version = old.version + 1;
+                                file.encoding = old.encoding;
+                                file.version_hashes = old.take_version_hashes();
+                            }
+                            self.record_change_seq(&path);
+                            files.insert(path, file);
+                        }
+                        return Ok(());
+                    }
+                }
+            }
+            thread::park();
+        }
+    }
+
+    fn move_range_to_file(&self, span: &VfsSpan, dest: &Path) -> Result<(), Error> {
+        let src = self.resolve(&span.span().file).into_owned();
+        let dest = &*self.resolve(dest);
+        // Replacing the source with the text moved out of it would lose the
+        // rest of the file.
+        if self.files.lock().unwrap().same_path(&src, dest) {
+            return Err(Error::DuplicatePath(dest.to_owned()));
+        }
+        let options = {
+            let config = self.config.lock().unwrap();
+            for &path in &[&*src, dest] {
+                if config.virtual_paths.contains_key(path) {
+                    return Err(Error::ReadOnly(path.to_owned()));
+                }
+            }
+            config.change_options()
+        };
+        let change = Change::ReplaceText {
+            span: span.clone(),
+            text: String::new(),
+        }.with_file(src.clone());
+        let src = &*src;
+        self.check_in_sync(std::slice::from_ref(&change))?;
+        self.ensure_file(src, |_| Ok(()))?;
+
+        loop {
+            {
+                let mut pending_files = self.pending_files.lock().unwrap();
+                let mut files = self.files.lock().unwrap();
+                if !pending_files.contains_key(dest) {
+                    let text = {
+                        let file = files.get_mut(src).ok_or(Error::FileNotCached)?;
+                        let text = match file.kind {
+                            FileKind::Text(ref t) => {
+                                let encoding = file.encoding.unwrap_or_else(|| span.encoding());
+                                let (start, end) = t.byte_range(span, encoding)?;
+                                t.text[start..end].to_owned()
+                            }
+                            FileKind::Binary(_) => return Err(Error::BadFileKind),
+                        };
+                        self.change_file(src, file, &[&change], None, None, options)?;
+                        text
+                    };
+
+                    // The destination starts afresh, keeping only its version,
+                    // encoding and version hashes.
+                    let mut file = File::new(FileKind::Text(TextFile::new(text, true)));
+                    if let Some(old) = files.get_mut(dest) {
+                        file.version = old.version + 1;
+                        file.encoding = old.encoding;
+                        file.version_hashes = old.take_version_hashes();
+                    }
+                    files.insert(dest.to_owned(), file);
+                    self.tombstones.lock().unwrap().remove(dest);
+                    self.record_change_seq(dest);
+                    self.paged_files.lock().unwrap().remove(dest);
+                    return Ok(());
+                }
+
+                pending_files.get_mut(dest).unwrap().push(thread::current());
+            }
+            thread::park();
+        }
+    }
+
+    fn get_cached_files(&self) -> HashMap<PathBuf, String> {
+        let files = self.files.lock().unwrap();
+        files
+            .iter()
+            .filter_map(|(p, f)| match f.kind {
+                FileKind::Text(ref f) => Some((p.clone(), (*f.text).clone())),
+                FileKind::Binary(_) => None,
+            }).collect()
+    }
+
+    fn materialize_to(&self, dir: &Path) -> Result<HashMap<PathBuf, PathBuf>, Error> {
+        let contents: Vec<_> = {
+            let files = self.files.lock().unwrap();
+            files.iter().map(|(p, f)| (p.clone(), f.contents())).collect()
+        };
+
+        // Check every target before writing any of them.
+        let mut seen = PathMap::new();
+        let mut targets = vec![];
+        for (path, _) in &contents {
+            let target = materialized_path(dir, path).ok_or_else(|| {
+                Error::Io(
+                    Some(path.clone()),
+                    Some(format!("{} has `..` components", path.display())),
+                )
+            })?;
+            if seen.contains_key(&target) {
+                return Err(Error::DuplicatePath(target));
+            }
+            seen.insert(target.clone(), ());
+            targets.push(target);
+        }
+
+        // We should not hold the lock while we write to disk.
+        let mut result = HashMap::new();
+        for ((path, contents), target) in contents.into_iter().zip(targets) {
+            let io_err = |e: io::Error| Error::Io(Some(target.clone()), Some(e.to_string()));
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent).map_err(io_err)?;
+            }
+            let bytes = match contents {
+                FileContents::Text(ref s) => s.as_bytes(),
+                FileContents::Binary(ref b) => b,
+            };
+            fs::write(&target, bytes).map_err(io_err)?;
+            result.insert(path, target);
+        }
+        Ok(result)
+    }
+
+    fn compact(&self, path: &Path) -> Result<(), Error> {
+        let mut files = self.files.lock().unwrap();
+        match files.get_mut(path) {
+            Some(f) => {
+                f.compact();
+                Ok(())
+            }
+            None => Err(Error::FileNotCached),
+        }
+    }
+
+    fn compact_all(&self) {
+        let mut files = self.files.lock().unwrap();
+        for f in files.values_mut() {
+            f.compact();
+        }
+    }
+
+    fn pending_lsp_changes(&self) -> Vec<LspDidChange> {
+        let root = self.config.lock().unwrap().root.clone();
+        let files = self.files.lock().unwrap();
+        let mut result: Vec<_> = files
+            .iter()
+            .filter(|(_, f)| f.changed())
+            .filter_map(|(path, f)| {
+                // A relative path has no URI without a root to resolve it.
+                let path = match root {
+                    _ if path.has_root() => Cow::Borrowed(&**path),
+                    Some(ref root) => Cow::Owned(root.join(path)),
+                    None => return None,
+                };
+                match f.kind {
+                    FileKind::Text(ref t) => Some(LspDidChange {
+                        uri: file_uri(&path),
+                        version: f.version,
+                        text: (*t.text).clone(),
+                    }),
+                    FileKind::Binary(_) => None,
+                }
+            })
+            .collect();
+        result.sort_by(|a, b| a.uri.cmp(&b.uri));
+        result
+    }
+
+    fn read_guard(&self) -> VfsReadGuard<'_, U> {
+        VfsReadGuard {
+            files: self.files.lock().unwrap(),
+        }
+    }
+
+    fn cached_files_arc(&self) -> HashMap<PathBuf, Arc<String>> {
+        let files = self.files.lock().unwrap();
+        files
+            .iter()
+            .filter_map(|(p, f)| match f.kind {
+                FileKind::Text(ref f) => Some((p.clone(), f.text.clone())),
+                FileKind::Binary(_) => None,
+            }).collect()
+    }
+
+    fn arc_snapshot(&self) -> Arc<VfsSnapshot<U>> {
+        let files = self.files.lock().unwrap();
+        let mut snapshot = PathMap::new();
+        for (path, f) in files.iter() {
+            let contents = match f.kind {
+                FileKind::Text(ref t) => SnapshotContents::Text(t.text.clone()),
+                FileKind::Binary(ref b) => SnapshotContents::Binary(b.clone()),
+            };
+            snapshot.insert(path.clone(), (contents, f.version));
+        }
+        Arc::new(VfsSnapshot {
+            files: snapshot,
+            user_data: PhantomData,
+        })
+    }
+
+    fn get_changes(&self) -> HashMap<PathBuf, String> {
+        let files = self.files.lock().unwrap();
+        files
+            .iter()
+            .filter_map(|(p, f)| match f.kind {
+                FileKind::Text(ref f) if f.changed => Some((p.clone(), (*f.text).clone())),
+                _ => None,
+            }).collect()
+    }
+
+    fn drain_changes<F>(&self, mut f: F) -> Result<(), Error>
+    where
+        F: FnMut(&Path, &str) -> Result<(), Error>,
+    {
+        let mut changed: Vec<_> = {
+            let files = self.files.lock().unwrap();
+            files
+                .iter()
+                .filter_map(|(p, file)| match file.kind {
+                    FileKind::Text(ref t) if t.changed => {
+                        Some((p.clone(), t.text.clone(), file.version))
+                    }
+                    _ => None,
+                })
+                .collect()
+        };
+        changed.sort_by(|a, b| a.0.cmp(&b.0));
+
+        for (path, text, version) in changed {
+            f(&path, &text)?;
+            let mut files = self.files.lock().unwrap();
+            if let Some(file) = files.get_mut(&path) {
+                if file.version == version {
+                    if let FileKind::Text(ref mut t) = file.kind {
+                        t.changed = false;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn has_changes(&self) -> bool {
+        self.files.lock().unwrap().values().any(|f| f.changed())
+    }
+
+    fn pending_change_count(&self) -> usize {
+        self.files.lock().unwrap().values().filter(|f| f.changed()).count()
+    }
+
+    fn files_with_user_data(&self) -> Vec<PathBuf> {
+        let files = self.files.lock().unwrap();
+        files
+            .iter()
+            .filter(|(_, f)| f.user_data.is_some())
+            .map(|(p, _)| p.clone())
+            .collect()
+    }
+
+    fn file_count(&self) -> usize {
+        self.files.lock().unwrap().len()
+    }
+
+    fn total_bytes(&self) -> usize {
+        let files = self.files.lock().unwrap();
+        files.values().map(|f| f.kind.as_bytes().len()).sum()
+    }
+
+    fn debug_dump(&self) -> String {
+        let files = self.files.lock().unwrap();
+        let mut files: Vec<_> = files.iter().collect();
+        files.sort_by_key(|(path, _)| *path);
+
+        let mut result = String::new();
+        for (path, f) in files {
+            let lines = match f.kind {
+                FileKind::Text(ref t) => format!("{} lines", t.line_indices.len() - 1),
+                FileKind::Binary(_) => "binary".to_owned(),
+            };
+            result.push_str(&format!(
+                "{}: {} bytes, {}, {}, version {}, {}\n",
+                path.display(),
+                f.kind.as_bytes().len(),
+                lines,
+                if f.changed() { "changed" } else { "unchanged" },
+                f.version,
+                if f.user_data.is_some() { "user data" } else { "no user data" },
+            ));
+        }
+        result
+    }
+
+    fn num_lines(&self, path: &Path) -> Result<usize, Error> {
+        let policy = self.trailing_empty_line_policy();
+        if !self.is_cached(path) {
+            if let Some(f) = self.paged_files.lock().unwrap().get(path) {
+                return Ok(f.num_lines(policy));
+            }
+        }
+        self.ensure_file(path, |f| f.num_lines(policy))
+    }
+
+    fn load_line(&self, path: &Path, line: span::Row<span::ZeroIndexed>) -> Result<String, Error> {
+        let policy = self.trailing_empty_line_policy();
+        let paged = self.with_paged_file(path, |f| {
+            if line.0 as usize >= f.num_lines(policy) {
+                return Err(Error::BadLocation);
+            }
+            f.load_line(line)
+        });
+        if let Some(result) = paged {
+            return result;
+        }
+        self.ensure_file(path, |f| {
+            if line.0 as usize >= f.num_lines(policy)? {
+                return Err(Error::BadLocation);
+            }
+            f.load_line(line).map(|s| s.to_owned())
+        })
+    }
+
+    fn load_from_line(
+        &self,
+        path: &Path,
+        line: span::Row<span::ZeroIndexed>,
+    ) -> Result<String, Error> {
+        if let Some(result) = self.with_paged_file(path, |f| f.load_from_line(line)) {
+            return result;
+        }
+        self.ensure_file(path, |f| f.load_from_line(line).map(|s| s.to_owned()))
+    }
+
+    fn load_lines(
+        &self,
+        path: &Path,
+        line_start: span::Row<span::ZeroIndexed>,
+        line_end: span::Row<span::ZeroIndexed>,
+    ) -> Result<String, Error> {
+        if let Some(result) = self.with_paged_file(path, |f| f.load_lines(line_start, line_end)) {
+            return result;
+        }
+        self.ensure_file(path, |f| {
+            f.load_lines(line_start, line_end).map(|s| s.to_owned())
+        })
+    }
+
+    fn load_span(&self, span: span::Span<span::ZeroIndexed>) -> Result<String, Error> {
+        if let Some(result) = self.with_paged_file(&span.file, |f| f.load_range(span.range)) {
+            return result;
+        }
+        self.ensure_file(&span.file, |f| {
+            f.load_range(span.range).map(|s| s.to_owned())
+        })
+    }
+
+    fn slice(&self, path: &Path, start: usize, end: usize) -> Result<String, Error> {
+        self.ensure_file(path, |f| f.slice(start, end).map(|s| s.to_owned()))
+    }
+
+    fn open_paged(&self, path: &Path) -> Result<(), Error> {
+        if self.files.lock().unwrap().contains_key(path) {
+            return Ok(());
+        }
+
+        // We should not hold the locks while we read from disk.
+        let file = PagedFile::open(path)?;
+        self.paged_files
+            .lock()
+            .unwrap()
+            .insert(path.to_owned(), file);
+        Ok(())
+    }
+
+    // Returns `None` if `path` is cached in full or was not opened with
+    // `open_paged`.
+    fn with_paged_file<F>(&self, path: &Path, f: F) -> Option<Result<String, Error>>
+    where
+        F: FnOnce(&PagedFile) -> Result<String, Error>,
+    {
+        if self.files.lock().unwrap().contains_key(path) {
+            return None;
+        }
+
+        let result = {
+            let paged_files = self.paged_files.lock().unwrap();
+            f(paged_files.get(path)?)
+        };
+        if let Ok(ref s) = result {
+            self.stats.lock().unwrap().paged_bytes_read += s.len() as u64;
+        }
+        Some(result)
+    }
+
+    fn out_of_sync_files(&self) -> Vec<PathBuf> {
+        let candidates: Vec<_> = {
+            let files = self.files.lock().unwrap();
+            files
+                .iter()
+                .filter(|(_, f)| !f.changed())
+                .map(|(p, f)| (p.clone(), f.disk_stamp.clone()))
+                .collect()
+        };
+
+        let mut result = vec![];
+        for (path, stamp) in candidates {
+            if stamp.is_some() && T::stamp(&path) == stamp {
+                continue;
+            }
+            // We should not hold the locks while we read from disk.
+            let disk = self.read_file(&path);
+            let files = self.files.lock().unwrap();
+            // Skip files which were edited or evicted while we were reading.
+            if let Some(f) = files.get(&path) {
+                if f.changed() {
+                    continue;
+                }
+                let in_sync = match disk {
+                    Ok(disk) => disk.kind.as_bytes() == f.kind.as_bytes(),
+                    Err(_) => false,
+                };
+                if !in_sync {
+                    result.push(path);
+                }
+            }
+        }
+        result
+    }
+
+    fn stats(&self) -> VfsStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    fn for_each_line<F>(&self, path: &Path, f: F) -> Result<(), Error>
+    where
+        F: FnMut(&str, usize) -> Result<(), Error>,
+    {
+        self.ensure_file(path, |file| file.for_each_line(f))
+    }
+
+    fn load_file(&self, path: &Path) -> Result<FileContents, Error> {
+        self.ensure_file(path, |f| Ok(f.contents()))
+    }
+
+    fn load_file_bytes(&self, path: &Path) -> Result<Vec<u8>, Error> {
+        self.ensure_file(path, |f| Ok(f.kind.as_bytes().to_vec()))
+    }
+
+    fn read_file_cow<F, R>(&self, path: &Path, f: F) -> Result<R, Error>
+    where
+        F: FnOnce(&str) -> R,
+    {
+        self.ensure_file(path, |file| match file.kind {
+            FileKind::Text(ref t) => Ok(f(&t.text)),
+            FileKind::Binary(_) => Err(Error::BadFileKind),
+        })
+    }
+
+    fn scan_lines<F>(&self, path: &Path, mut f: F) -> Result<(), Error>
+    where
+        F: FnMut(span::Row<span::ZeroIndexed>, &str),
+    {
+        let mut f = |line: &str, i: usize| {
+            f(span::Row::new_zero_indexed(i as u32), line);
+            Ok(())
+        };
+        if let Some(file) = self.files.lock().unwrap().get(path) {
+            return file.for_each_line(&mut f);
+        }
+
+        if self.is_deleted(path) {
+            return Err(Error::Deleted(path.to_owned()));
+        }
+        let source = self.source_for(path);
+        if let Some(file) = read_source::<()>(source.as_deref(), path)? {
+            return file.for_each_line(f);
+        }
+        T::scan_lines(path, f)
+    }
+
+    fn line_char_count(
+        &self,
+        path: &Path,
+        line: span::Row<span::ZeroIndexed>,
+        encoding: PositionEncoding,
+    ) -> Result<u32, Error> {
+        self.ensure_file(path, |f| {
+            let line = trim_line_terminator(f.load_line(line)?);
+            Ok(encoding.count(line) as u32)
+        })
+    }
+
+    fn char_count_on_line(
+        &self,
+        path: &Path,
+        line: span::Row<span::ZeroIndexed>,
+        ch: char,
+    ) -> Result<u32, Error> {
+        self.ensure_file(path, |f| {
+            let line = trim_line_terminator(f.load_line(line)?);
+            Ok(line.matches(ch).count() as u32)
+        })
+    }
+
+    fn first_nonblank_offset(
+        &self,
+        path: &Path,
+        line: span::Row<span::ZeroIndexed>,
+    ) -> Result<Option<usize>, Error> {
+        self.ensure_file(path, |f| {
+            let start = f.line_byte_range(line)?.start;
+            let text = f.load_line(line)?;
+            Ok(text.find(|c: char| !c.is_whitespace()).map(|i| start + i))
+        })
+    }
+
+    fn line_suffix(
+        &self,
+        path: &Path,
+        pos: span::Position<span::ZeroIndexed>,
+        encoding: PositionEncoding,
+    ) -> Result<String, Error> {
+        self.ensure_file(path, |f| {
+            let line = trim_line_terminator(f.load_line(pos.row)?);
+            let start = encoding.byte_in_str(line, pos.col).map_err(|_| Error::BadLocation)?;
+            Ok(line[start..].to_owned())
+        })
+    }
+
+    fn word_at(
+        &self,
+        path: &Path,
+        pos: span::Position<span::ZeroIndexed>,
+    ) -> Result<Option<(String, span::Span<span::ZeroIndexed>)>, Error> {
+        let line = self.load_line(path, pos.row)?;
+        let encoding = self.files.lock().unwrap().get(path).and_then(|f| f.encoding);
+        // The text in each column.
+        let units: Vec<&str> = if encoding == Some(PositionEncoding::Grapheme) {
+            let starts: Vec<usize> = grapheme_starts(&line).chain(Some(line.len())).collect();
+            starts.windows(2).map(|w| &line[w[0]..w[1]]).collect()
+        } else {
+            line.char_indices().map(|(i, c)| &line[i..i + c.len_utf8()]).collect()
+        };
+        let is_word = |u: &&str| u.starts_with(is_word_char);
+        let col = pos.col.0 as usize;
+        if col >= units.len() || !is_word(&units[col]) {
+            return Ok(None);
+        }
+
+        let start = units[..col].iter().rposition(|u| !is_word(u)).map_or(0, |i| i + 1);
+        let end = units[col..]
+            .iter()
+            .position(|u| !is_word(u))
+            .map_or(units.len(), |i| col + i);
+        let span = span::Span::new(
+            pos.row,
+            pos.row,
+            span::Column::new_zero_indexed(start as u32),
+            span::Column::new_zero_indexed(end as u32),
+            path,
+        );
+        Ok(Some((units[start..end].concat(), span)))
+    }
+
+    fn rename_word(
+        &self,
+        path: &Path,
+        pos: span::Position<span::ZeroIndexed>,
+        new_name: &str,
+    ) -> Result<usize, Error> {
+        let word = match self.word_at(path, pos)? {
+            Some((word, _)) => word,
+            None => return Ok(0),
+        };
+
+        self.modify_file(path, |file| {
+            let (text, count) = match file.kind {
+                FileKind::Text(ref t) => {
+                    let mut text = String::with_capacity(t.text.len());
+                    let mut count = 0;
+                    let mut last = 0;
+                    for (i, _) in t.text.match_indices(&word) {
+                        let end = i + word.len();
+                        let whole_word = !t.text[..i].ends_with(is_word_char)
+                            && !t.text[end..].starts_with(is_word_char);
+                        if !whole_word {
+                            continue;
+                        }
+                        text.push_str(&t.text[last..i]);
+                        text.push_str(new_name);
+                        last = end;
+                        count += 1;
+                    }
+                    if count == 0 {
+                        return Ok(0);
+                    }
+                    text.push_str(&t.text[last..]);
+                    (text, count)
+                }
+                FileKind::Binary(_) => return Err(Error::BadFileKind),
+            };
+            file.with_mut_text(|t| *t = text)?;
+            Ok(count)
+        })
+    }
+
+    fn enclosing_brackets(
+        &self,
+        path: &Path,
+        pos: span::Position<span::ZeroIndexed>,
+        open: char,
+        close: char,
+    ) -> Result<Option<span::Span<span::ZeroIndexed>>, Error> {
+        self.ensure_file(path, |f| {
+            let t = match f.kind {
+                FileKind::Text(ref t) => t,
+                FileKind::Binary(_) => return Err(Error::BadFileKind),
+            };
+            let line_start = t.line_byte_range(pos.row)?.start;
+            let line = t.load_line(pos.row)?;
+            let offset = line_start + byte_in_str(line, pos.col).map_err(|_| Error::BadLocation)?;
+
+            let start = match find_unmatched(t.text[..offset].char_indices().rev(), close, open) {
+                Some((i, _)) => i,
+                None => return Ok(None),
+            };
+            let end = match find_unmatched(t.text[offset..].char_indices(), open, close) {
+                Some((i, c)) => offset + i + c.len_utf8(),
+                None => return Ok(None),
+            };
+
+            let (start, end) = (t.position_of(start), t.position_of(end));
+            Ok(Some(span::Span::from_positions(start, end, path)))
+        })
+    }
+
+    fn line_ranges(&self, path: &Path) -> Result<LineRanges, Error> {
+        let policy = self.trailing_empty_line_policy();
+        self.ensure_file(path, |f| {
+            let mut ranges = f.line_ranges()?;
+            ranges.truncate(f.num_lines(policy)?);
+            Ok(ranges)
+        })
+    }
+
+    fn newline_offsets(&self, path: &Path) -> Result<Vec<usize>, Error> {
+        self.ensure_file(path, |f| f.newline_offsets())
+    }
+
+    fn max_line_width(&self, path: &Path) -> Result<u32, Error> {
+        self.ensure_file(path, |f| f.max_line_width())
+    }
+
+    fn line_ending(&self, path: &Path) -> Result<LineEnding, Error> {
+        self.ensure_file(path, |f| f.line_ending())
+    }
+
+    fn line_terminator(
+        &self,
+        path: &Path,
+        line: span::Row<span::ZeroIndexed>,
+    ) -> Result<LineTerminator, Error> {
+        self.ensure_file(path, |f| {
+            let line = f.load_line(line)?;
+            Ok(if line.ends_with("\r\n") {
+                LineTerminator::CrLf
+            } else if line.ends_with('\n') {
+                LineTerminator::Lf
+            } else {
+                LineTerminator::Unterminated
+            })
+        })
+    }
+
+    fn normalize_line_endings(&self, path: &Path, ending: LineEnding) -> Result<(), Error> {
+        self.modify_file(path, |f| f.normalize_line_endings(ending))
+    }
+
+    fn with_mut_text<F>(&self, path: &Path, f: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut String),
+    {
+        self.modify_file(path, |file| file.with_mut_text(f))
+    }
+
+    fn replace_if_version(
+        &self,
+        path: &Path,
+        expected_version: u64,
+        text: &str,
+    ) -> Result<bool, Error> {
+        self.modify_file(path, |file| {
+            if let FileKind::Binary(_) = file.kind {
+                return Err(Error::BadFileKind);
+            }
+            if file.version != expected_version {
+                return Ok(false);
+            }
+            file.with_mut_text(|t| *t = text.to_owned())?;
+            Ok(true)
+        })
+    }
+
+    #[cfg(feature = "regex")]
+    fn regex_replace(
+        &self,
+        path: &Path,
+        pattern: &regex::Regex,
+        replacement: &str,
+    ) -> Result<usize, Error> {
+        self.modify_file(path, |file| {
+            let (text, count) = match file.kind {
+                FileKind::Text(ref t) => {
+                    let count = pattern.find_iter(&t.text).count();
+                    if count == 0 {
+                        return Ok(0);
+                    }
+                    (pattern.replace_all(&t.text, replacement).into_owned(), count)
+                }
+                FileKind::Binary(_) => return Err(Error::BadFileKind),
+            };
+            file.with_mut_text(|t| *t = text)?;
+            Ok(count)
+        })
+    }
+
+    fn apply_patch(&self, path: &Path, patch: &str) -> Result<(), Error> {
+        self.modify_file(path, |file| {
+            let text = match file.kind {
+                FileKind::Text(ref t) => patch::apply(&t.text, patch)?,
+                FileKind::Binary(_) => return Err(Error::BadFileKind),
+            };
+            file.with_mut_text(|t| *t = text)
+        })
+    }
+
+    fn transform_lines<F>(&self, path: &Path, f: F) -> Result<usize, Error>
+    where
+        F: Fn(span::Row<span::ZeroIndexed>, &str) -> Option<String>,
+    {
+        let policy = self.trailing_empty_line_policy();
+        self.modify_file(path, |file| {
+            let (text, count) = match file.kind {
+                FileKind::Text(ref t) => {
+                    let mut ranges = t.line_ranges();
+                    ranges.truncate(t.num_lines(policy));
+                    let mut text = String::with_capacity(t.text.len());
+                    let mut count = 0;
+                    let mut end = 0;
+                    for (row, range) in ranges {
+                        let line = &t.text[range.clone()];
+                        let content = trim_line_terminator(line);
+                        match f(row, content) {
+                            Some(new) => {
+                                text.push_str(&new);
+                                text.push_str(&line[content.len()..]);
+                                count += 1;
+                            }
+                            None => text.push_str(line),
+                        }
+                        end = range.end;
+                    }
+                    if count == 0 {
+                        return Ok(0);
+                    }
+                    text.push_str(&t.text[end..]);
+                    (text, count)
+                }
+                FileKind::Binary(_) => return Err(Error::BadFileKind),
+            };
+            file.with_mut_text(|t| *t = text)?;
+            Ok(count)
+        })
+    }
+
+    fn diff_against_disk(&self, path: &Path) -> Result<Option<Vec<TextEdit>>, Error> {
+        let cached = {
+            let files = self.files.lock().unwrap();
+            match files.get(path) {
+                Some(f) => match f.kind {
+                    FileKind::Text(ref t) => t.text.clone(),
+                    FileKind::Binary(_) => return Err(Error::BadFileKind),
+                },
+                None => return Err(Error::FileNotCached),
+            }
+        };
+
+        // We should not hold the locks while we read from disk.
+        let disk = match self.read_file(path)?.kind {
+            FileKind::Text(t) => t.text,
+            FileKind::Binary(_) => return Err(Error::BadFileKind),
+        };
+
+        if disk == cached {
+            Ok(None)
+        } else {
+            Ok(Some(diff_lines(&disk, &cached)))
+        }
+    }
+
+    fn recent_changes(&self, path: &Path, n: usize) -> Result<Vec<Change>, Error> {
+        let files = self.files.lock().unwrap();
+        match files.get(path) {
+            Some(f) => {
+                let skip = f.change_log.len().saturating_sub(n);
+                Ok(f.change_log.iter().skip(skip).cloned().collect())
+            }
+            None => Err(Error::FileNotCached),
+        }
+    }
+
+    fn check_invariants(&self) -> Result<(), Error> {
+        let files = self.files.lock().unwrap();
+        for file in files.values() {
+            if let FileKind::Text(ref t) = file.kind {
+                t.check_invariants()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn hash_at_version(&self, path: &Path, version: u64) -> Option<u64> {
+        let files = self.files.lock().unwrap();
+        let file = files.get(path)?;
+        if version == file.version {
+            return Some(content_hash(file.kind.as_bytes()));
+        }
+        let hashes = file.version_hashes.as_ref()?;
+        hashes.iter().find(|&&(v, _)| v == version).map(|&(_, hash)| hash)
+    }
+
+    fn file_version(&self, path: &Path) -> Result<u64, Error> {
+        let files = self.files.lock().unwrap();
+        match files.get(path) {
+            Some(f) => Ok(f.version),
+            None => Err(Error::FileNotCached),
+        }
+    }
+
+    fn last_modified(&self, path: &Path) -> Result<SystemTime, Error> {
+        let files = self.files.lock().unwrap();
+        match files.get(path) {
+            Some(f) => Ok(f.modified),
+            None => Err(Error::FileNotCached),
+        }
+    }
+
+    fn ensure_file<F, R>(&self, path: &Path, f: F) -> Result<R, Error>
+    where
+        F: FnOnce(&File<U>) -> Result<R, Error>,
+    {
+        let path = &*self.resolve(path);
+        loop {
+            {
+                let mut pending_files = self.pending_files.lock().unwrap();
+                let files = self.files.lock().unwrap();
+                if files.contains_key(path) {
+                    return f(&files[path]);
+                }
+                if !pending_files.contains_key(path) {
+                    pending_files.insert(path.to_owned(), vec![]);
+                    break;
+                }
+                pending_files.get_mut(path).unwrap().push(thread::current());
+            }
+            thread::park();
+        }
+
+        // We should not hold the locks while we read from disk.
+        let file = self.read_file(path);
+
+        // Need to re-get the locks here.
+        let mut pending_files = self.pending_files.lock().unwrap();
+        let mut files = self.files.lock().unwrap();
+        match file {
+            Ok(file) => {
+                files.insert(path.to_owned(), file);
+                self.paged_files.lock().unwrap().remove(path);
+                let ts = pending_files.remove(path).unwrap();
+                for t in ts {
+                    t.unpark();
+                }
+            }
+            Err(e) => {
+                let ts = pending_files.remove(path).unwrap();
+                for t in ts {
+                    t.unpark();
+                }
+                return Err(e);
+            }
+        }
+
+        f(&files[path])
+    }
+
+    fn reload_file(&self, path: &Path) -> Result<(), Error> {
+        // Take the file out of the cache while we reload it, marking it as
+        // pending so that other threads wait for us.
+        let mut old = loop {
+            {
+                let mut pending_files = self.pending_files.lock().unwrap();
+                let mut files = self.files.lock().unwrap();
+                if !pending_files.contains_key(path) {
+                    match files.get(path) {
+                        Some(f) if f.changed() => {
+                            return Err(Error::UncommittedChanges(path.to_owned()))
+                        }
+                        Some(_) => {}
+                        None => return Err(Error::FileNotCached),
+                    }
+                    pending_files.insert(path.to_owned(), vec![]);
+                    break files.remove(path).unwrap();
+                }
+                pending_files.get_mut(path).unwrap().push(thread::current());
+            }
+            thread::park();
+        };
+
+        // We should not hold the locks while we read from disk.
+        let source = self.source_for(path);
+        let (options, gzip) = self.read_options();
+        let new = {
+            let _permit = self.read_limit.acquire();
+            match read_source(source.as_deref(), path) {
+                Ok(Some(file)) => Ok(file),
+                Ok(None) => T::reload(path, &old, &options, gzip),
+                Err(e) => Err(e),
+            }
+        };
+        let new = new.and_then(|f| self.verify_checksum(path, &f.kind).map(|_| f));
+
+        let mut pending_files = self.pending_files.lock().unwrap();
+        let mut files = self.files.lock().unwrap();
+        let (file, result) = match new {
+            Ok(mut new) => {
+                if new.kind.as_bytes() == old.kind.as_bytes() {
+                    new.user_data = old.user_data;
+                    new.version = old.version;
+                    new.modified = old.modified;
+                    new.change_log = old.change_log;
+                    new.metadata = old.metadata;
+                    new.version_hashes = old.version_hashes;
+                    new.undo = old.undo;
+                    if let Some(ref mut undo) = new.undo {
+                        undo.redo.clear();
+                    }
+                } else {
+                    new.version = old.version + 1;
+                    new.version_hashes = old.take_version_hashes();
+                    new.metadata = old.metadata;
+                    self.record_change_seq(path);
+                    // The history refers to the old contents.
+                    new.undo = old.undo.map(|_| UndoStack::default());
+                }
+                (new, Ok(()))
+            }
+            Err(e) => (old, Err(e)),
+        };
+        // If the file was loaded again while we were reading it (e.g., by
+        // `on_changes`), that version wins.
+        files.insert_if_absent(path.to_owned(), file);
+        for t in pending_files.remove(path).unwrap() {
+            t.unpark();
+        }
+        result
+    }
+
+    fn write_file(&self, path: &Path) -> Result<(), Error> {
+        if self.is_virtual(path) {
+            return Err(Error::ReadOnly(path.to_owned()));
+        }
+        if self.is_scratch(path) {
+            return if self.is_cached(path) {
+                Ok(())
+            } else {
+                Err(Error::FileNotCached)
+            };
+        }
+        let file = {
+            let mut files = self.files.lock().unwrap();
+            match files.get_mut(path) {
+                Some(f) => {
+                    if f.lossy {
+                        return Err(Error::LossilyDecoded(path.to_owned()));
+                    }
+                    if let FileKind::Text(ref mut f) = f.kind {
+                        f.changed = false;
+                    }
+                    f.kind.clone()
+                }
+                None => return Err(Error::FileNotCached),
+            }
+        };
+
+        let (options, gzip) = {
+            let config = self.config.lock().unwrap();
+            (config.write_options.clone(), config.gzip)
+        };
+        if let FileKind::Text(ref t) = file {
+            if options.delete_if_empty && t.text.trim().is_empty() {
+                T::remove(path)?;
+                return self.flush_file(path);
+            }
+        }
+        if let Some(source) = self.source_for(path) {
+            if source.write(path, &file.contents())? {
+                return Ok(());
+            }
+        }
+        T::write(path, &file, &options, gzip)?;
+
+        // The cached text now matches the disk again.
+        let stamp = T::stamp(path);
+        if let Some(f) = self.files.lock().unwrap().get_mut(path) {
+            f.disk_stamp = stamp;
+        }
+        Ok(())
+    }
+
+    fn set_file_encoding(&self, path: &Path, encoding: PositionEncoding) -> Result<(), Error> {
+        let mut files = self.files.lock().unwrap();
+        match files.get_mut(path) {
+            Some(f) => {
+                f.encoding = Some(encoding);
+                Ok(())
+            }
+            None => Err(Error::FileNotCached),
+        }
+    }
+
+    fn enable_version_hashes(&self) {
+        self.config.lock().unwrap().version_hashes = true;
+    }
+
+    fn enable_undo(&self) {
+        self.config.lock().unwrap().undo = true;
+    }
+
+    fn enable_file_undo(&self, path: &Path) -> Result<(), Error> {
+        let mut files = self.files.lock().unwrap();
+        match files.get_mut(path) {
+            Some(f) => {
+                if f.undo.is_none() {
+                    f.undo = Some(UndoStack::default());
+                }
+                Ok(())
+            }
+            None => Err(Error::FileNotCached),
+        }
+    }
+
+    fn undo(&self, path: &Path) -> Result<bool, Error> {
+        self.modify_file(path, |f| f.step_history(false))
+    }
+
+    fn redo(&self, path: &Path) -> Result<bool, Error> {
+        self.modify_file(path, |f| f.step_history(true))
+    }
+
+    fn set_max_file_size(&self, limit: Option<usize>) {
+        self.config.lock().unwrap().read_options.max_file_size = limit;
+    }
+
+    fn set_read_concurrency(&self, limit: Option<usize>) {
+        self.read_limit.set_limit(limit.map(|n| n.max(1)));
+    }
+
+    fn set_utf8_error_mode(&self, mode: Utf8ErrorMode) {
+        self.config.lock().unwrap().read_options.utf8_errors = mode;
+    }
+
+    #[cfg(feature = "flate2")]
+    fn set_gzip(&self, enabled: bool) {
+        self.config.lock().unwrap().gzip = enabled;
+    }
+
+    fn set_trailing_empty_line_policy(&self, policy: TrailingEmptyLinePolicy) {
+        self.config.lock().unwrap().trailing_empty_line = policy;
+    }
+
+    fn trailing_empty_line_policy(&self) -> TrailingEmptyLinePolicy {
+        self.config.lock().unwrap().trailing_empty_line
+    }
+
+    fn set_metadata(&self, path: &Path, key: &str, value: &str) -> Result<(), Error> {
+        let mut files = self.files.lock().unwrap();
+        match files.get_mut(path) {
+            Some(f) => {
+                f.metadata.insert(key.to_owned(), value.to_owned());
+                Ok(())
+            }
+            None => Err(Error::FileNotCached),
+        }
+    }
+
+    fn get_metadata(&self, path: &Path, key: &str) -> Result<Option<String>, Error> {
+        let files = self.files.lock().unwrap();
+        match files.get(path) {
+            Some(f) => Ok(f.metadata.get(key).cloned()),
+            None => Err(Error::FileNotCached),
+        }
+    }
+
+    fn set_selections(
+        &self,
+        path: &Path,
+        selections: Vec<span::Range<span::ZeroIndexed>>,
+    ) -> Result<(), Error> {
+        let mut files = self.files.lock().unwrap();
+        match files.get_mut(path) {
+            Some(f) => {
+                f.selections = selections;
+                Ok(())
+            }
+            None => Err(Error::FileNotCached),
+        }
+    }
+
+    fn selections(&self, path: &Path) -> Result<Vec<span::Range<span::ZeroIndexed>>, Error> {
+        let files = self.files.lock().unwrap();
+        match files.get(path) {
+            Some(f) => Ok(f.selections.clone()),
+            None => Err(Error::FileNotCached),
+        }
+    }
+
+    fn set_write_options(&self, options: WriteOptions) {
+        self.config.lock().unwrap().write_options = options;
+    }
+
+    fn register_loader(&self, prefix: &Path, source: Box<dyn FileSource>) {
+        let mut sources = self.sources.lock().unwrap();
+        sources.retain(|(p, _)| p != prefix);
+        sources.push((prefix.to_owned(), Arc::from(source)));
+    }
+
+    fn add_virtual(&self, path: &Path, text: String) {
+        let source = VirtualSource {
+            path: path.to_owned(),
+            text,
+        };
+        self.register_loader(path, Box::new(source));
+        self.config.lock().unwrap().virtual_paths.insert(path.to_owned(), ());
+        // The next read will use the new contents.
+        let _ = self.flush_file(path);
+    }
+
+    fn register_scratch(&self, path: &Path) {
+        let source = ScratchSource {
+            path: path.to_owned(),
+        };
+        self.register_loader(path, Box::new(source));
+        self.config.lock().unwrap().scratch_paths.insert(path.to_owned(), ());
+        let _ = self.flush_file(path);
+    }
+
+    // Return the source registered for the longest prefix of `path`.
+    fn source_for(&self, path: &Path) -> Option<Arc<dyn FileSource>> {
+        let sources = self.sources.lock().unwrap();
+        sources
+            .iter()
+            .filter(|(prefix, _)| path.starts_with(prefix))
+            .max_by_key(|(prefix, _)| prefix.components().count())
+            .map(|(_, source)| source.clone())
+    }
+
+    // Read a file from its registered source, or else from the disk. No locks
+    // should be held while calling this.
+    fn read_file(&self, path: &Path) -> Result<File<U>, Error> {
+        if self.is_deleted(path) {
+            return Err(Error::Deleted(path.to_owned()));
+        }
+        let source = self.source_for(path);
+        let (options, gzip) = self.read_options();
+        let file = {
+            let _permit = self.read_limit.acquire();
+            read_from_source::<T, U>(source.as_deref(), path, &options, gzip)?
+        };
+        self.verify_checksum(path, &file.kind)?;
+        Ok(file)
+    }
+
+    // The options for reading files, and whether to decompress gzip files.
+    fn read_options(&self) -> (ReadOptions, bool) {
+        let config = self.config.lock().unwrap();
+        (config.read_options.clone(), config.gzip)
+    }
+
+    fn set_expected_checksums(&self, checksums: HashMap<PathBuf, [u8; 32]>) {
+        self.config.lock().unwrap().expected_checksums = checksums;
+    }
+
+    // Check a file just read against its expected checksum, if there is one.
+    fn verify_checksum(&self, path: &Path, kind: &FileKind) -> Result<(), Error> {
+        let expected = match self.config.lock().unwrap().expected_checksums.get(path) {
+            Some(expected) => *expected,
+            None => return Ok(()),
+        };
+        if sha256::digest(kind.as_bytes()) != expected {
+            return Err(Error::ChecksumMismatch {
+                path: path.to_owned(),
+            });
+        }
+        Ok(())
+    }
+
+    fn set_validate_changes(&self, validate: bool) {
+        self.config.lock().unwrap().validate_changes = validate;
+    }
+
+    fn set_preserve_line_endings(&self, preserve: bool) {
+        self.config.lock().unwrap().preserve_line_endings = preserve;
+    }
+
+    fn set_create_missing_files(&self, create: bool) {
+        self.config.lock().unwrap().create_missing_files = create;
+    }
+
+    fn set_root(&self, root: Option<PathBuf>) {
+        self.config.lock().unwrap().root = root;
+    }
+
+    // Return `path` joined to the root, if it is relative and a root is set.
+    fn resolve<'a>(&self, path: &'a Path) -> Cow<'a, Path> {
+        if path.is_absolute() {
+            return Cow::Borrowed(path);
+        }
+        match self.config.lock().unwrap().root {
+            Some(ref root) => Cow::Owned(root.join(path)),
+            None => Cow::Borrowed(path),
+        }
+    }
+
+    // Check that no file which `changes` edit, without first replacing it, is
+    // cached without changes but has changed on disk since it was read.
+    fn check_in_sync(&self, changes: &[Change]) -> Result<(), Error> {
+        let mut seen = HashSet::new();
+        let mut stamps = vec![];
+        {
+            let files = self.files.lock().unwrap();
+            for c in changes {
+                let file = c.file();
+                if !seen.insert(file) {
+                    continue;
+                }
+                if let Change::ReplaceText { .. } = *c {
+                    let stamp = files
+                        .get(file)
+                        .filter(|f| !f.changed())
+                        .and_then(|f| f.disk_stamp.clone());
+                    if let Some(stamp) = stamp {
+                        stamps.push((file, stamp));
+                    }
+                }
+            }
+        }
+
+        // We should not hold the lock while we look at the disk.
+        for (path, stamp) in stamps {
+            if T::stamp(path) != Some(stamp) {
+                return Err(Error::OutOfSync(path.to_owned()));
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_changes(&self, changes: &[Change]) -> Result<(), Error> {
+        let files = self.files.lock().unwrap();
+        let mut added = HashSet::new();
+        for c in changes {
+            match *c {
+                Change::AddFile { ref file, .. } => {
+                    added.insert(file.as_path());
+                }
+                Change::ReplaceText { .. } => {
+                    let file = c.file();
+                    if !files.contains_key(file) && !added.contains(file) {
+                        return Err(Error::UnknownFile(file.to_owned()));
+                    }
                 }
             }
-            Err(e) => {
-                let ts = pending_files.remove(path).unwrap();
-                for t in ts {
-                    t.unpark();
+        }
+        Ok(())
+    }
+
+    pub fn set_user_data(&self, path: &Path, data: Option<U>) -> Result<(), Error> {
+        let mut files = self.files.lock().unwrap();
+        match files.get_mut(path) {
+            Some(ref mut f) => {
+                f.user_data = data;
+                Ok(())
+            }
+            None => Err(Error::FileNotCached),
+        }
+    }
+
+    // Note that f should not be a long-running operation since we hold the lock
+    // to the VFS while it runs.
+    pub fn with_user_data<F, R>(&self, path: &Path, f: F) -> Result<R, Error>
+    where
+        F: FnOnce(Result<(Option<&str>, &mut U), Error>) -> Result<R, Error>,
+    {
+        let mut files = self.files.lock().unwrap();
+        let file = match files.get_mut(path) {
+            Some(f) => f,
+            None => return f(Err(Error::FileNotCached)),
+        };
+
+        let result = f(match file.user_data {
+            Some(ref mut u) => {
+                let text = match file.kind {
+                    FileKind::Text(ref f) => Some(&f.text as &str),
+                    FileKind::Binary(_) => None,
+                };
+                Ok((text, u))
+            }
+            None => Err(Error::NoUserDataForFile),
+        });
+
+        if let Err(Error::NoUserDataForFile) = result {
+            file.user_data = None;
+        }
+
+        result
+    }
+
+    pub fn with_user_data_multi<F, R>(&self, paths: &[&Path], f: F) -> R
+    where
+        F: FnOnce(&mut [UserDataEntry<'_, '_, U>]) -> R,
+    {
+        let mut files = self.files.lock().unwrap();
+        // Take the files out of the cache while `f` borrows their user data,
+        // so that we can lend out several at once.
+        let mut seen = PathMap::new();
+        let mut taken = vec![];
+        let slots: Vec<_> = paths
+            .iter()
+            .map(|path| {
+                if seen.contains_key(path) {
+                    return Err(Error::DuplicatePath(path.to_path_buf()));
                 }
-                return Err(e);
+                seen.insert(path.to_path_buf(), ());
+                let entry = files.remove_entry(path).ok_or(Error::FileNotCached)?;
+                taken.push(entry);
+                Ok(())
+            })
+            .collect();
+        let is_taken: Vec<bool> = slots.iter().map(Result::is_ok).collect();
+
+        let mut taken_files = taken.iter_mut();
+        let mut entries: Vec<_> = paths
+            .iter()
+            .zip(slots)
+            .map(|(path, slot)| {
+                let entry = slot.and_then(|()| {
+                    let file = &mut taken_files.next().unwrap().1;
+                    let File {
+                        ref kind,
+                        ref mut user_data,
+                        ..
+                    } = *file;
+                    match *user_data {
+                        Some(ref mut u) => {
+                            let text = match *kind {
+                                FileKind::Text(ref f) => Some(&f.text as &str),
+                                FileKind::Binary(_) => None,
+                            };
+                            Ok((text, u))
+                        }
+                        None => Err(Error::NoUserDataForFile),
+                    }
+                });
+                (*path, entry)
+            })
+            .collect();
+
+        let result = f(&mut entries);
+
+        let erased: Vec<bool> = entries
+            .iter()
+            .zip(is_taken)
+            .filter(|&(_, is_taken)| is_taken)
+            .map(|(entry, _)| matches!(entry.1, Err(Error::NoUserDataForFile)))
+            .collect();
+        drop(entries);
+        for ((path, mut file), erased) in taken.into_iter().zip(erased) {
+            if erased {
+                file.user_data = None;
+            }
+            files.insert(path, file);
+        }
+
+        result
+    }
+
+    pub fn ensure_user_data<F>(&self, path: &Path, f: F) -> Result<(), Error>
+    where
+        F: FnOnce(Option<&str>) -> Result<U, Error>,
+    {
+        let mut files = self.files.lock().unwrap();
+        match files.get_mut(path) {
+            Some(ref mut file) => {
+                if let None = file.user_data {
+                    let text = match file.kind {
+                        FileKind::Text(ref f) => Some(&f.text as &str),
+                        FileKind::Binary(_) => None,
+                    };
+                    match f(text) {
+                        Ok(u) => {
+                            file.user_data = Some(u);
+                            Ok(())
+                        }
+                        Err(Error::NoUserDataForFile) => {
+                            file.user_data = None;
+                            Ok(())
+                        }
+                        Err(e) => Err(e),
+                    }
+                } else {
+                    Ok(())
+                }
+            }
+            None => Err(Error::FileNotCached),
+        }
+    }
+
+    pub fn with_user_data_or_default<F, R>(&self, path: &Path, f: F) -> Result<R, Error>
+    where
+        U: Default,
+        F: FnOnce(Option<&str>, &mut U) -> Result<R, Error>,
+    {
+        let mut files = self.files.lock().unwrap();
+        let file = match files.get_mut(path) {
+            Some(f) => f,
+            None => return Err(Error::FileNotCached),
+        };
+
+        let result = {
+            let text = match file.kind {
+                FileKind::Text(ref f) => Some(&f.text as &str),
+                FileKind::Binary(_) => None,
+            };
+            f(text, file.user_data.get_or_insert_with(U::default))
+        };
+
+        if let Err(Error::NoUserDataForFile) = result {
+            file.user_data = None;
+        }
+
+        result
+    }
+
+    pub fn recompute_all_user_data<F>(&self, f: F) -> Result<(), Error>
+    where
+        F: Fn(&Path, &str) -> Result<Option<U>, Error>,
+    {
+        let mut files = self.files.lock().unwrap();
+        for (path, file) in files.iter_mut() {
+            if let FileKind::Text(ref t) = file.kind {
+                file.user_data = f(path, &t.text)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn invalidate_user_data_where<F>(&self, f: F)
+    where
+        F: Fn(&Path, &str, &U) -> bool,
+    {
+        let mut files = self.files.lock().unwrap();
+        for (path, file) in files.iter_mut() {
+            let invalid = match (&file.kind, &file.user_data) {
+                (FileKind::Text(t), Some(u)) => f(path, &t.text, u),
+                _ => false,
+            };
+            if invalid {
+                file.user_data = None;
+            }
+        }
+    }
+}
+
+fn read_from_source<T: FileLoader, U>(
+    source: Option<&dyn FileSource>,
+    path: &Path,
+    options: &ReadOptions,
+    gzip: bool,
+) -> Result<File<U>, Error> {
+    match read_source(source, path)? {
+        Some(file) => Ok(file),
+        None => T::read(path, options, gzip),
+    }
+}
+
+// Read a file from `source`, if there is one and it provides the file.
+fn read_source<U>(source: Option<&dyn FileSource>, path: &Path) -> Result<Option<File<U>>, Error> {
+    if let Some(source) = source {
+        if let Some(contents) = source.read(path)? {
+            return Ok(Some(File::new(match contents {
+                FileContents::Text(s) => FileKind::Text(TextFile::new(s, false)),
+                FileContents::Binary(b) => FileKind::Binary(b),
+            })));
+        }
+    }
+    Ok(None)
+}
+
+// Convert the line endings in `text` to `ending`, if any, see
+// `convert_line_endings`.
+fn with_line_ending(text: &str, ending: Option<LineEnding>) -> Cow<'_, str> {
+    match ending {
+        Some(ending) if text.contains('\n') => Cow::Owned(convert_line_endings(text, ending)),
+        _ => Cow::Borrowed(text),
+    }
+}
+
+// Convert all line endings in `text` to `ending`. `Mixed` leaves them as they
+// are.
+fn convert_line_endings(text: &str, ending: LineEnding) -> String {
+    let lf_text = text.replace("\r\n", "\n");
+    match ending {
+        LineEnding::Lf => lf_text,
+        LineEnding::CrLf => lf_text.replace('\n', "\r\n"),
+        LineEnding::Mixed => text.to_owned(),
+    }
+}
+
+fn coalesce_changes<'a>(changes: &'a [Change]) -> HashMap<&'a Path, Vec<&'a Change>> {
+    // Note that for any given file, we preserve the order of the changes.
+    let mut result = HashMap::new();
+    for c in changes {
+        result.entry(&*c.file()).or_insert(vec![]).push(c);
+    }
+    result
+}
+
+fn affected_lines(changes: &[Change]) -> AffectedLines {
+    let mut result = HashMap::new();
+    for c in changes {
+        if let Change::ReplaceText { ref span, .. } = *c {
+            // The nominal end, as finding where `len` ends needs the text.
+            let range = span.span().range;
+            let end = span::Row::new_zero_indexed(range.row_end.0 + 1);
+            result
+                .entry(span.span().file.clone())
+                .or_insert_with(Vec::new)
+                .push(range.row_start..end);
+        }
+    }
+    result
+}
+
+// Beyond this many cells in the table of common subsequence lengths,
+// `diff_lines` gives up on finding a minimal diff.
+const MAX_DIFF_CELLS: usize = 1 << 22;
+
+// Compute the edits which turn `old` into `new`, line by line. Edits are
+// ordered by position and line numbers refer to `old`.
+fn diff_lines(old: &str, new: &str) -> Vec<TextEdit> {
+    let old: Vec<&str> = old.split_inclusive('\n').collect();
+    let new: Vec<&str> = new.split_inclusive('\n').collect();
+
+    let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let old_mid = &old[prefix..old.len() - suffix];
+    let new_mid = &new[prefix..new.len() - suffix];
+    let (n, m) = (old_mid.len(), new_mid.len());
+
+    let make_edit = |old_start: usize, old_end: usize, new_lines: &[&str]| TextEdit {
+        line_start: span::Row::new_zero_indexed((prefix + old_start) as u32),
+        line_end: span::Row::new_zero_indexed((prefix + old_end) as u32),
+        text: new_lines.concat(),
+    };
+
+    if (n + 1) * (m + 1) > MAX_DIFF_CELLS {
+        return vec![make_edit(0, n, new_mid)];
+    }
+
+    // lcs[i][j] is the length of the longest common subsequence of
+    // old_mid[i..] and new_mid[j..].
+    let mut lcs = vec![vec![0u32; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if old_mid[i] == new_mid[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut edits = vec![];
+    let (mut i, mut j) = (0, 0);
+    let (mut old_start, mut new_start) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && old_mid[i] == new_mid[j] {
+            if old_start < i || new_start < j {
+                edits.push(make_edit(old_start, i, &new_mid[new_start..j]));
+            }
+            i += 1;
+            j += 1;
+            old_start = i;
+            new_start = j;
+        } else if j == m || (i < n && lcs[i + 1][j] >= lcs[i][j + 1]) {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    if old_start < n || new_start < m {
+        edits.push(make_edit(old_start, n, &new_mid[new_start..]));
+    }
+    edits
+}
+
+#[derive(Clone)]
+enum FileKind {
+    Text(TextFile),
+    Binary(Vec<u8>),
+}
+
+impl FileKind {
+    fn as_bytes(&self) -> &[u8] {
+        match *self {
+            FileKind::Text(ref t) => t.text.as_bytes(),
+            FileKind::Binary(ref b) => b,
+        }
+    }
+
+    fn contents(&self) -> FileContents {
+        match *self {
+            FileKind::Text(ref t) => FileContents::Text((*t.text).clone()),
+            FileKind::Binary(ref b) => FileContents::Binary(b.clone()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FileContents {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// The state of a file in the VFS, see `Vfs::file_state`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileState {
+    /// The file is not in the VFS.
+    NotCached,
+    /// The file is cached and has no contents.
+    Empty,
+    /// The file is cached and has contents.
+    NonEmpty {
+        /// The number of lines, as for `Vfs::num_lines`, or zero for a binary
+        /// file.
+        lines: usize,
+        /// The length of the contents in bytes.
+        bytes: usize,
+        /// Whether the file has changes which have not been written to disk.
+        changed: bool,
+    },
+}
+
+/// The parameters of an LSP `textDocument/didChange` notification replacing
+/// the whole text of a document, see `Vfs::pending_lsp_changes`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LspDidChange {
+    /// The `file://` URI of the document.
+    pub uri: String,
+    /// The version of the file in the VFS, see `Vfs::file_version`.
+    pub version: u64,
+    /// The full text of the document.
+    pub text: String,
+}
+
+// Convert `path` to a `file://` URI, percent-encoding characters other than
+// unreserved characters and path separators.
+fn file_uri(path: &Path) -> String {
+    let path = path.to_string_lossy().replace('\\', "/");
+    let mut uri = "file://".to_owned();
+    if !path.starts_with('/') {
+        uri.push('/');
+    }
+    for b in path.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                uri.push(b as char)
             }
+            _ => uri.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    uri
+}
+
+/// Read access to the cached files of a VFS, see `Vfs::read_guard`.
+pub struct VfsReadGuard<'a, U> {
+    files: MutexGuard<'a, PathMap<File<U>>>,
+}
+
+impl<'a, U> VfsReadGuard<'a, U> {
+    /// Return the cached file at `path`, if any. Unlike `Vfs::load_file`, this
+    /// never reads from disk.
+    pub fn get(&self, path: &Path) -> Option<FileView<'_, U>> {
+        self.files.get(path).map(|file| FileView { file })
+    }
+
+    /// Return the paths of all cached files.
+    pub fn paths(&self) -> impl Iterator<Item = &Path> {
+        self.files.keys().map(PathBuf::as_path)
+    }
+}
+
+/// A cached file borrowed from a `VfsReadGuard`.
+pub struct FileView<'a, U> {
+    file: &'a File<U>,
+}
+
+impl<'a, U> FileView<'a, U> {
+    /// The text of the file, or `None` for a binary file.
+    pub fn text(&self) -> Option<&'a str> {
+        match self.file.kind {
+            FileKind::Text(ref t) => Some(&t.text),
+            FileKind::Binary(_) => None,
+        }
+    }
+
+    /// The raw bytes of the file, whether it is a text or a binary file.
+    pub fn bytes(&self) -> &'a [u8] {
+        self.file.kind.as_bytes()
+    }
+
+    /// Return a line of the file, including its line terminator.
+    pub fn load_line(&self, line: span::Row<span::ZeroIndexed>) -> Result<&'a str, Error> {
+        self.file.load_line(line)
+    }
+
+    /// See `Vfs::file_version`.
+    pub fn version(&self) -> u64 {
+        self.file.version
+    }
+
+    /// Whether the file has changes which have not been written to disk.
+    pub fn changed(&self) -> bool {
+        self.file.changed()
+    }
+
+    /// The file's user data, if it has any.
+    pub fn user_data(&self) -> Option<&'a U> {
+        self.file.user_data.as_ref()
+    }
+
+    /// See `Vfs::last_modified`.
+    pub fn modified(&self) -> SystemTime {
+        self.file.modified
+    }
+}
+
+/// The cached files of a VFS at one point in time, see `Vfs::arc_snapshot`.
+pub struct VfsSnapshot<U> {
+    files: PathMap<(SnapshotContents, u64)>,
+    // The snapshot holds no user data, so is `Send` and `Sync` whatever `U` is.
+    user_data: PhantomData<fn() -> U>,
+}
+
+enum SnapshotContents {
+    Text(Arc<String>),
+    Binary(Vec<u8>),
+}
+
+impl<U> VfsSnapshot<U> {
+    /// Return the contents of the file at `path`, or `Error::FileNotCached` if
+    /// it was not cached when the snapshot was taken.
+    pub fn load_file(&self, path: &Path) -> Result<FileContents, Error> {
+        match self.files.get(path) {
+            Some((SnapshotContents::Text(text), _)) => Ok(FileContents::Text((**text).clone())),
+            Some((SnapshotContents::Binary(bytes), _)) => Ok(FileContents::Binary(bytes.clone())),
+            None => Err(Error::FileNotCached),
         }
+    }
 
-        f(&files[path])
+    /// Return the text of the file at `path` without copying it, or `None` if
+    /// it is binary or was not cached.
+    pub fn text(&self, path: &Path) -> Option<Arc<String>> {
+        match self.files.get(path) {
+            Some((SnapshotContents::Text(text), _)) => Some(text.clone()),
+            _ => None,
+        }
     }
 
-    fn write_file(&self, path: &Path) -> Result<(), Error> {
-        let file = {
-            let mut files = self.files.lock().unwrap();
-            match files.get_mut(path) {
-                Some(f) => {
-                    if let FileKind::Text(ref mut f) = f.kind {
-                        f.changed = false;
-                    }
-                    f.kind.clone()
-                }
-                None => return Err(Error::FileNotCached),
-            }
-        };
+    /// See `Vfs::file_version`.
+    pub fn version(&self, path: &Path) -> Option<u64> {
+        self.files.get(path).map(|&(_, version)| version)
+    }
 
-        T::write(path, &file)?;
-        Ok(())
+    /// Return the paths of all files in the snapshot.
+    pub fn paths(&self) -> impl Iterator<Item = &Path> {
+        self.files.keys().map(PathBuf::as_path)
     }
+}
 
-    pub fn set_user_data(&self, path: &Path, data: Option<U>) -> Result<(), Error> {
-        let mut files = self.files.lock().unwrap();
-        match files.get_mut(path) {
-            Some(ref mut f) => {
-                f.user_data = data;
-                Ok(())
-            }
-            None => Err(Error::FileNotCached),
-        }
+/// Read access to a file system, so that code can read files either from disk
+/// or through a VFS, see `VfsFs`.
+pub trait FsRead {
+    /// Read the whole file at `path` as UTF-8 text.
+    fn read_to_string(&self, path: &Path) -> io::Result<String>;
+
+    /// Return the length and modification time of the file at `path`.
+    fn metadata(&self, path: &Path) -> io::Result<FsMetadata>;
+}
+
+/// The metadata of a file, see `FsRead::metadata`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FsMetadata {
+    /// The length of the file in bytes.
+    pub len: u64,
+    pub modified: SystemTime,
+}
+
+/// Reads files from disk using `std::fs`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DiskFs;
+
+impl FsRead for DiskFs {
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        fs::read_to_string(path)
     }
 
-    // Note that f should not be a long-running operation since we hold the lock
-    // to the VFS while it runs.
-    pub fn with_user_data<F, R>(&self, path: &Path, f: F) -> Result<R, Error>
-    where
-        F: FnOnce(Result<(Option<&str>, &mut U), Error>) -> Result<R, Error>,
-    {
-        let mut files = self.files.lock().unwrap();
-        let file = match files.get_mut(path) {
-            Some(f) => f,
-            None => return f(Err(Error::FileNotCached)),
-        };
+    fn metadata(&self, path: &Path) -> io::Result<FsMetadata> {
+        let metadata = fs::metadata(path)?;
+        Ok(FsMetadata {
+            len: metadata.len(),
+            modified: metadata.modified()?,
+        })
+    }
+}
 
-        let result = f(match file.user_data {
-            Some(ref mut u) => {
-                let text = match file.kind {
-                    FileKind::Text(ref f) => Some(&f.text as &str),
-                    FileKind::Binary(_) => None,
-                };
-                Ok((text, u))
-            }
-            None => Err(Error::NoUserDataForFile),
-        });
+/// Reads cached files from a VFS, including changes which have not been
+/// written to disk, and other files from disk, without caching them. The
+/// modification time of a cached file is that returned by `Vfs::last_modified`.
+pub struct VfsFs<'a, U> {
+    vfs: &'a Vfs<U>,
+}
 
-        if let Err(Error::NoUserDataForFile) = result {
-            file.user_data = None;
-        }
+impl<'a, U> VfsFs<'a, U> {
+    pub fn new(vfs: &'a Vfs<U>) -> VfsFs<'a, U> {
+        VfsFs { vfs }
+    }
+}
 
-        result
+impl<'a, U> FsRead for VfsFs<'a, U> {
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        if let Some(file) = self.vfs.read_guard().get(path) {
+            return match file.text() {
+                Some(text) => Ok(text.to_owned()),
+                None => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "stream did not contain valid UTF-8",
+                )),
+            };
+        }
+        DiskFs.read_to_string(path)
     }
 
-    pub fn ensure_user_data<F>(&self, path: &Path, f: F) -> Result<(), Error>
-    where
-        F: FnOnce(Option<&str>) -> Result<U, Error>,
-    {
-        let mut files = self.files.lock().unwrap();
-        match files.get_mut(path) {
-            Some(ref mut file) => {
-                if let None = file.user_data {
-                    let text = match file.kind {
-                        FileKind::Text(ref f) => Some(&f.text as &str),
-                        FileKind::Binary(_) => None,
-                    };
-                    match f(text) {
-                        Ok(u) => {
-                            file.user_data = Some(u);
-                            Ok(())
-                        }
-                        Err(Error::NoUserDataForFile) => {
-                            file.user_data = None;
-                            Ok(())
-                        }
-                        Err(e) => Err(e),
-                    }
-                } else {
-                    Ok(())
-                }
-            }
-            None => Err(Error::FileNotCached),
+    fn metadata(&self, path: &Path) -> io::Result<FsMetadata> {
+        if let Some(file) = self.vfs.read_guard().get(path) {
+            return Ok(FsMetadata {
+                len: file.bytes().len() as u64,
+                modified: file.modified(),
+            });
         }
+        DiskFs.metadata(path)
     }
 }
 
-fn coalesce_changes<'a>(changes: &'a [Change]) -> HashMap<&'a Path, Vec<&'a Change>> {
-    // Note that for any given file, we preserve the order of the changes.
-    let mut result = HashMap::new();
-    for c in changes {
-        result.entry(&*c.file()).or_insert(vec![]).push(c);
-    }
-    result
+/// Maps positions in files from before a batch of changes to after it, see
+/// `Vfs::on_changes_with_mapping`. Columns are in the units of the changes'
+/// spans.
+#[derive(Debug, Clone, Default)]
+pub struct PositionMap {
+    // The edits made to each file, in the order they were applied.
+    edits: HashMap<PathBuf, Vec<PositionEdit>>,
 }
 
-fn make_line_indices(text: &str) -> Vec<u32> {
-    let mut result = vec![0];
-    for (i, b) in text.bytes().enumerate() {
-        if b == 0xA {
-            result.push((i + 1) as u32);
+impl PositionMap {
+    /// Translate `pos`, a position in `path` before the changes, to the
+    /// corresponding position after them. Positions after an edit are shifted
+    /// by the text it added or removed, and positions within replaced text move
+    /// to its start. A position exactly where text was inserted ends up after
+    /// the inserted text. Positions in files which were not changed are
+    /// returned as is.
+    pub fn map(
+        &self,
+        path: &Path,
+        pos: span::Position<span::ZeroIndexed>,
+    ) -> span::Position<span::ZeroIndexed> {
+        match self.edits.get(path) {
+            Some(edits) => edits.iter().fold(pos, |pos, edit| edit.map(pos)),
+            None => pos,
         }
     }
-    result.push(text.len() as u32);
-    result
 }
 
-#[derive(Clone)]
-enum FileKind {
-    Text(TextFile),
-    Binary(Vec<u8>),
+// A single edit, in the coordinates of the file at the time it was applied.
+#[derive(Debug, Clone)]
+struct PositionEdit {
+    start: span::Position<span::ZeroIndexed>,
+    // The end of the replaced text before and after the edit.
+    old_end: span::Position<span::ZeroIndexed>,
+    new_end: span::Position<span::ZeroIndexed>,
 }
 
-impl FileKind {
-    fn as_bytes(&self) -> &[u8] {
-        match *self {
-            FileKind::Text(ref t) => t.text.as_bytes(),
-            FileKind::Binary(ref b) => b,
+impl PositionEdit {
+    fn map(&self, pos: span::Position<span::ZeroIndexed>) -> span::Position<span::ZeroIndexed> {
+        if pos < self.start {
+            pos
+        } else if pos < self.old_end {
+            self.start
+        } else if pos.row == self.old_end.row {
+            let col = self.new_end.col.0 + (pos.col.0 - self.old_end.col.0);
+            span::Position::new(self.new_end.row, span::Column::new_zero_indexed(col))
+        } else {
+            let row = pos.row.0 - self.old_end.row.0 + self.new_end.row.0;
+            span::Position::new(span::Row::new_zero_indexed(row), pos.col)
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
-pub enum FileContents {
-    Text(String),
-    Binary(Vec<u8>),
+// The position at the end of `text` if it were to start at `start`.
+fn end_position(
+    start: span::Position<span::ZeroIndexed>,
+    text: &str,
+    encoding: PositionEncoding,
+) -> span::Position<span::ZeroIndexed> {
+    match text.rfind('\n') {
+        Some(i) => {
+            let lines = text.bytes().filter(|&b| b == b'\n').count() as u32;
+            span::Position::new(
+                span::Row::new_zero_indexed(start.row.0 + lines),
+                span::Column::new_zero_indexed(encoding.count(&text[i + 1..]) as u32),
+            )
+        }
+        None => span::Position::new(
+            start.row,
+            span::Column::new_zero_indexed(start.col.0 + encoding.count(text) as u32),
+        ),
+    }
+}
+
+/// An edit replacing whole lines of a file, see `Vfs::diff_against_disk`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextEdit {
+    /// The first line to be replaced.
+    pub line_start: span::Row<span::ZeroIndexed>,
+    /// The line after the last line to be replaced. Equal to `line_start` if
+    /// the edit is an insertion.
+    pub line_end: span::Row<span::ZeroIndexed>,
+    /// The replacement text, including line endings.
+    pub text: String,
+}
+
+/// The kind of line endings used in a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineEnding {
+    /// All lines end in `\n`.
+    Lf,
+    /// All lines end in `\r\n`.
+    CrLf,
+    /// Both kinds of line endings are used.
+    Mixed,
+}
+
+/// How a single line ends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineTerminator {
+    /// The line ends in `\n`.
+    Lf,
+    /// The line ends in `\r\n`.
+    CrLf,
+    /// The line is the last line and has no line ending.
+    Unterminated,
 }
 
 #[derive(Clone)]
 struct TextFile {
     // FIXME(https://github.com/jonathandturner/rustls/issues/21) should use a rope.
-    text: String,
-    line_indices: Vec<u32>,
+    // Shared with snapshots handed out by `cached_files_arc`, so edits copy
+    // the text if a snapshot is alive.
+    text: Arc<String>,
+    line_indices: LineIndex,
     changed: bool,
+    // Cached result of `max_line_width`, cleared whenever the text changes.
+    max_line_width: Cell<Option<u32>>,
+    // Column indices of long lines, built as they are needed.
+    column_indices: RefCell<HashMap<usize, ColumnIndex>>,
+    // The number of changes with a UTF-16 column which was clamped, see
+    // `VfsStats::clamped_changes`.
+    clamped_changes: u64,
 }
 
 struct File<U> {
     kind: FileKind,
     user_data: Option<U>,
+    // Incremented every time the contents of the file change.
+    version: u64,
+    // The most recent changes applied to the file, oldest first.
+    change_log: VecDeque<Change>,
+    // Overrides the units of the columns of changes to the file.
+    encoding: Option<PositionEncoding>,
+    // Identifies the version of the file on disk the file was read from, if
+    // it was read from disk.
+    disk_stamp: Option<DiskStamp>,
+    // Set if undo is enabled for the file.
+    undo: Option<UndoStack>,
+    // Set if version hashes are enabled for the file: the hashes of its
+    // earlier versions, oldest first, see `Vfs::hash_at_version`.
+    version_hashes: Option<VecDeque<(u64, u64)>>,
+    // The editor's selections in the file, see `Vfs::set_selections`.
+    selections: Vec<span::Range<span::ZeroIndexed>>,
+    // Set if the file was not valid UTF-8 and was decoded lossily.
+    lossy: bool,
+    // When the file was loaded or last changed in memory.
+    modified: SystemTime,
+    // Tags set with `Vfs::set_metadata`, kept when the file changes.
+    metadata: HashMap<String, String>,
+}
+
+// The number of changes which can be undone for each file.
+const UNDO_LEN: usize = 100;
+
+// Changes to a file which can be undone or redone, most recent last.
+#[derive(Default)]
+struct UndoStack {
+    undo: Vec<UndoEdit>,
+    redo: Vec<UndoEdit>,
+}
+
+impl UndoStack {
+    // Record a new change. This clears the redo stack.
+    fn push(&mut self, edit: UndoEdit) {
+        if self.undo.len() == UNDO_LEN {
+            self.undo.remove(0);
+        }
+        self.undo.push(edit);
+        self.redo.clear();
+    }
+}
+
+// A change which replaced `removed` with `inserted` at byte `start`.
+struct UndoEdit {
+    start: usize,
+    removed: String,
+    inserted: String,
+}
+
+// The modification time and size of a file on disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct DiskStamp {
+    modified: SystemTime,
+    len: u64,
+}
+
+impl DiskStamp {
+    fn new(metadata: &fs::Metadata) -> Option<DiskStamp> {
+        Some(DiskStamp {
+            modified: metadata.modified().ok()?,
+            len: metadata.len(),
+        })
+    }
+}
+
+// Whether `change` inserts text at the start of a file, without replacing any.
+fn inserts_at_start(change: &Change) -> bool {
+    match *change {
+        Change::ReplaceText { ref span, .. } => {
+            let range = span.span().range;
+            let at_start = |row: span::Row<_>, col: span::Column<_>| row.0 == 0 && col.0 == 0;
+            at_start(range.row_start, range.col_start)
+                && match span.len() {
+                    Some(len) => len == 0,
+                    None => at_start(range.row_end, range.col_end),
+                }
+        }
+        Change::AddFile { .. } => false,
+    }
+}
+
+// The number of changes kept in each file's change log.
+const CHANGE_LOG_LEN: usize = 32;
+
+fn content_hash(bytes: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    hasher.finish()
 }
 
 impl<U> File<U> {
+    fn new(kind: FileKind) -> File<U> {
+        File {
+            kind,
+            user_data: None,
+            version: 0,
+            change_log: VecDeque::new(),
+            encoding: None,
+            disk_stamp: None,
+            undo: None,
+            version_hashes: None,
+            selections: vec![],
+            lossy: false,
+            modified: SystemTime::now(),
+            metadata: HashMap::new(),
+        }
+    }
+
+    // If version hashes are enabled, record the hash of the current version,
+    // before the contents change.
+    fn record_version_hash(&mut self) {
+        let hashes = match self.version_hashes {
+            Some(ref mut hashes) => hashes,
+            None => return,
+        };
+        let version = self.version;
+        if hashes.back().is_some_and(|&(v, _)| v == version) {
+            return;
+        }
+        if hashes.len() == VERSION_HASH_LEN {
+            hashes.pop_front();
+        }
+        hashes.push_back((version, content_hash(self.kind.as_bytes())));
+    }
+
+    // Take the version hashes, including that of the current version, for a
+    // file which replaces this one.
+    fn take_version_hashes(&mut self) -> Option<VecDeque<(u64, u64)>> {
+        self.record_version_hash();
+        self.version_hashes.take()
+    }
+
+    fn compact(&mut self) {
+        match self.kind {
+            FileKind::Text(ref mut t) => t.compact(),
+            FileKind::Binary(ref mut b) => b.shrink_to_fit(),
+        }
+        self.change_log.shrink_to_fit();
+        self.selections.shrink_to_fit();
+    }
+
     fn contents(&self) -> FileContents {
+        self.kind.contents()
+    }
+
+    // The number of line index rebuilds and of clamped changes so far, see
+    // `VfsStats`.
+    fn stat_counters(&self) -> (u64, u64) {
         match self.kind {
-            FileKind::Text(ref t) => FileContents::Text(t.text.clone()),
-            FileKind::Binary(ref b) => FileContents::Binary(b.clone()),
+            FileKind::Text(ref t) => (t.line_indices.rebuilds(), t.clamped_changes),
+            FileKind::Binary(_) => (0, 0),
         }
     }
 
-    fn make_change(&mut self, changes: &[&Change]) -> Result<(), Error> {
+    fn make_change(
+        &mut self,
+        changes: &[&Change],
+        edits: Option<&mut Vec<PositionEdit>>,
+        inverses: Option<&mut Vec<Change>>,
+        preserve_line_endings: bool,
+    ) -> Result<(), Error> {
+        self.record_version_hash();
         match self.kind {
             FileKind::Text(ref mut t) => {
+                // Re-adding a file with its current contents (e.g., when an
+                // editor re-opens it) is a no-op, so that we keep its user data.
+                let only_adds = changes.iter().all(|c| matches!(**c, Change::AddFile { .. }));
+                if let Some(Change::AddFile { text, .. }) = changes.last().copied() {
+                    if only_adds && *text == *t.text {
+                        return Ok(());
+                    }
+                }
+
                 self.user_data = None;
-                t.make_change(changes)
+                self.version += 1;
+                self.modified = SystemTime::now();
+
+                // We need the edits to shift the selections.
+                let mut local_edits = vec![];
+                let mut edits = match edits {
+                    None if !self.selections.is_empty() => Some(&mut local_edits),
+                    edits => edits,
+                };
+                let first_edit = edits.as_ref().map_or(0, |e| e.len());
+                let line_ending = if preserve_line_endings {
+                    Some(t.dominant_line_ending())
+                } else {
+                    None
+                };
+                let result = t.make_change(
+                    changes,
+                    self.encoding,
+                    line_ending,
+                    self.undo.as_mut(),
+                    edits.as_deref_mut(),
+                    inverses,
+                );
+                if let Some(edits) = edits {
+                    let map = |pos| edits[first_edit..].iter().fold(pos, |pos, e| e.map(pos));
+                    for sel in &mut self.selections {
+                        *sel = span::Range::from_positions(map(sel.start()), map(sel.end()));
+                    }
+                }
+                result?;
+
+                if changes.iter().any(|c| matches!(**c, Change::AddFile { .. })) {
+                    self.lossy = false;
+                }
+                for &c in changes {
+                    if self.change_log.len() == CHANGE_LOG_LEN {
+                        self.change_log.pop_front();
+                    }
+                    self.change_log.push_back(c.clone());
+                }
+                Ok(())
             }
             FileKind::Binary(_) => Err(Error::BadFileKind),
         }
     }
 
+    // Revert the most recent change recorded in the file's undo stack, or if
+    // `redo` is set, reapply the most recently reverted change. Return
+    // whether there was a change to revert or reapply.
+    fn step_history(&mut self, redo: bool) -> Result<bool, Error> {
+        self.record_version_hash();
+        let t = match self.kind {
+            FileKind::Text(ref mut t) => t,
+            FileKind::Binary(_) => return Err(Error::BadFileKind),
+        };
+        let stack = match self.undo {
+            Some(ref mut stack) => stack,
+            None => return Ok(false),
+        };
+        let edit = if redo {
+            stack.redo.pop()
+        } else {
+            stack.undo.pop()
+        };
+        let edit = match edit {
+            Some(edit) => edit,
+            None => return Ok(false),
+        };
+
+        let (old, new) = if redo {
+            (&edit.removed, &edit.inserted)
+        } else {
+            (&edit.inserted, &edit.removed)
+        };
+        // The text should not have changed since the edit was recorded, but
+        // if it has, the history is no use.
+        let end = edit.start + old.len();
+        if t.text.get(edit.start..end) != Some(old.as_str()) {
+            *stack = UndoStack::default();
+            return Err(Error::BadLocation);
+        }
+        t.replace_range(edit.start, end, new);
+        t.max_line_width.set(None);
+        t.changed = true;
+        t.debug_check_invariants();
+        if redo {
+            stack.undo.push(edit);
+        } else {
+            stack.redo.push(edit);
+        }
+        self.user_data = None;
+        self.version += 1;
+        self.modified = SystemTime::now();
+        self.selections.clear();
+        Ok(true)
+    }
+
+    fn num_lines(&self, policy: TrailingEmptyLinePolicy) -> Result<usize, Error> {
+        match self.kind {
+            FileKind::Text(ref t) => Ok(t.num_lines(policy)),
+            FileKind::Binary(_) => Err(Error::BadFileKind),
+        }
+    }
+
     fn load_line(&self, line: span::Row<span::ZeroIndexed>) -> Result<&str, Error> {
         match self.kind {
             FileKind::Text(ref t) => t.load_line(line),
@@ -664,6 +4439,20 @@ impl<U> File<U> {
         }
     }
 
+    fn line_byte_range(&self, line: span::Row<span::ZeroIndexed>) -> Result<Range<usize>, Error> {
+        match self.kind {
+            FileKind::Text(ref t) => t.line_byte_range(line),
+            FileKind::Binary(_) => Err(Error::BadFileKind),
+        }
+    }
+
+    fn load_from_line(&self, line: span::Row<span::ZeroIndexed>) -> Result<&str, Error> {
+        match self.kind {
+            FileKind::Text(ref t) => t.load_from_line(line),
+            FileKind::Binary(_) => Err(Error::BadFileKind),
+        }
+    }
+
     fn load_lines(
         &self,
         line_start: span::Row<span::ZeroIndexed>,
@@ -682,16 +4471,99 @@ impl<U> File<U> {
         }
     }
 
-    fn for_each_line<F>(&self, f: F) -> Result<(), Error>
+    fn slice(&self, start: usize, end: usize) -> Result<&str, Error> {
+        match self.kind {
+            // `get` checks the order and bounds of the offsets, and that they
+            // are at character boundaries.
+            FileKind::Text(ref t) => t.text.get(start..end).ok_or(Error::BadLocation),
+            FileKind::Binary(_) => Err(Error::BadFileKind),
+        }
+    }
+
+    fn for_each_line<F>(&self, f: F) -> Result<(), Error>
+    where
+        F: FnMut(&str, usize) -> Result<(), Error>,
+    {
+        match self.kind {
+            FileKind::Text(ref t) => t.for_each_line(f),
+            FileKind::Binary(_) => Err(Error::BadFileKind),
+        }
+    }
+
+    fn line_ranges(&self) -> Result<LineRanges, Error> {
+        match self.kind {
+            FileKind::Text(ref t) => Ok(t.line_ranges()),
+            FileKind::Binary(_) => Err(Error::BadFileKind),
+        }
+    }
+
+    fn newline_offsets(&self) -> Result<Vec<usize>, Error> {
+        match self.kind {
+            FileKind::Text(ref t) => Ok(t.newline_offsets()),
+            FileKind::Binary(_) => Err(Error::BadFileKind),
+        }
+    }
+
+    fn max_line_width(&self) -> Result<u32, Error> {
+        match self.kind {
+            FileKind::Text(ref t) => Ok(t.max_line_width()),
+            FileKind::Binary(_) => Err(Error::BadFileKind),
+        }
+    }
+
+    fn line_ending(&self) -> Result<LineEnding, Error> {
+        match self.kind {
+            FileKind::Text(ref t) => Ok(t.line_ending()),
+            FileKind::Binary(_) => Err(Error::BadFileKind),
+        }
+    }
+
+    fn normalize_line_endings(&mut self, ending: LineEnding) -> Result<(), Error> {
+        self.record_version_hash();
+        match self.kind {
+            FileKind::Text(ref mut t) => {
+                if t.normalize_line_endings(ending) {
+                    self.clear_undo();
+                    self.user_data = None;
+                    self.version += 1;
+                    self.modified = SystemTime::now();
+                    self.selections.clear();
+                }
+                Ok(())
+            }
+            FileKind::Binary(_) => Err(Error::BadFileKind),
+        }
+    }
+
+    fn with_mut_text<F>(&mut self, f: F) -> Result<(), Error>
     where
-        F: FnMut(&str, usize) -> Result<(), Error>,
+        F: FnOnce(&mut String),
     {
+        self.record_version_hash();
         match self.kind {
-            FileKind::Text(ref t) => t.for_each_line(f),
+            FileKind::Text(ref mut t) => {
+                let mut text = mem::take(Arc::make_mut(&mut t.text));
+                f(&mut text);
+                t.set_text(text);
+                self.clear_undo();
+                self.user_data = None;
+                self.version += 1;
+                self.modified = SystemTime::now();
+                self.selections.clear();
+                Ok(())
+            }
             FileKind::Binary(_) => Err(Error::BadFileKind),
         }
     }
 
+    // Forget the changes which could be undone or redone, after the text was
+    // replaced other than by a change, since they refer to the old text.
+    fn clear_undo(&mut self) {
+        if let Some(ref mut undo) = self.undo {
+            *undo = UndoStack::default();
+        }
+    }
+
     fn changed(&self) -> bool {
         match self.kind {
             FileKind::Text(ref t) => t.changed,
@@ -701,66 +4573,442 @@ impl<U> File<U> {
 }
 
 impl TextFile {
-    fn make_change(&mut self, changes: &[&Change]) -> Result<(), Error> {
+    fn new(text: String, changed: bool) -> TextFile {
+        TextFile {
+            line_indices: LineIndex::new(&text),
+            text: Arc::new(text),
+            changed,
+            max_line_width: Cell::new(None),
+            column_indices: RefCell::new(HashMap::new()),
+            clamped_changes: 0,
+        }
+    }
+
+    // Apply `changes`, recording each edit in `undo` and `edits`, and the
+    // change which reverts it in `inverses`, if given. If `encoding` is
+    // given, it overrides the units of the changes' spans, and the columns of
+    // the inverses are in its units too, since it overrides theirs as well.
+    // If `line_ending` is given, the line endings of text inserted by
+    // `ReplaceText` changes are converted to it.
+    fn make_change(
+        &mut self,
+        changes: &[&Change],
+        encoding: Option<PositionEncoding>,
+        line_ending: Option<LineEnding>,
+        mut undo: Option<&mut UndoStack>,
+        mut edits: Option<&mut Vec<PositionEdit>>,
+        mut inverses: Option<&mut Vec<Change>>,
+    ) -> Result<(), Error> {
         trace!("TextFile::make_change");
+        let inverse_encoding = encoding.unwrap_or(PositionEncoding::UnicodeScalarValue);
         for c in changes {
             trace!("TextFile::make_change: {:?}", c);
-            let new_text = match **c {
+            match **c {
                 Change::ReplaceText {
                     span: ref vfs_span,
                     ref text,
                 } => {
-                    let (span, len) = (vfs_span.span(), vfs_span.len());
-
-                    let range = {
-                        let first_line = self.load_line(span.range.row_start)?;
-                        let byte_start = self.line_indices[span.range.row_start.0 as usize]
-                            + vfs_span.byte_in_str(first_line, span.range.col_start)? as u32;
-
-                        let byte_end = if let Some(len) = len {
-                            // if `len` exists, the replaced portion of text
-                            // is `len` chars starting from row_start/col_start.
-                            byte_start + vfs_span.byte_in_str(
-                                &self.text[byte_start as usize..],
-                                span::Column::new_zero_indexed(len as u32),
-                            )? as u32
-                        } else {
-                            // if no `len`, fall back to using row_end/col_end
-                            // for determining the tail end of replaced text.
-                            let last_line = self.load_line(span.range.row_end)?;
-                            self.line_indices[span.range.row_end.0 as usize]
-                                + vfs_span.byte_in_str(last_line, span.range.col_end)? as u32
+                    let converted = with_line_ending(text, line_ending);
+                    let text = &*converted;
+                    let span = vfs_span.span();
+                    let encoding = encoding.unwrap_or_else(|| vfs_span.encoding());
+                    let range = self.byte_range(vfs_span, encoding)?;
+                    if encoding == PositionEncoding::Utf16CodeUnit {
+                        let r = &span.range;
+                        if self.splits_surrogate_pair(r.row_start, r.col_start)?
+                            || (vfs_span.len().is_none()
+                                && self.splits_surrogate_pair(r.row_end, r.col_end)?)
+                        {
+                            self.clamped_changes += 1;
+                        }
+                    }
+                    if let Some(ref mut edits) = edits {
+                        let start = span::Position::new(span.range.row_start, span.range.col_start);
+                        edits.push(PositionEdit {
+                            start,
+                            old_end: end_position(start, &self.text[range.0..range.1], encoding),
+                            new_end: end_position(start, text, encoding),
+                        });
+                    }
+                    if let Some(ref mut undo) = undo {
+                        undo.push(UndoEdit {
+                            start: range.0,
+                            removed: self.text[range.0..range.1].to_owned(),
+                            inserted: text.to_owned(),
+                        });
+                    }
+                    if let Some(ref mut inverses) = inverses {
+                        let start = self.position_in(range.0, inverse_encoding);
+                        let end = end_position(start, text, inverse_encoding);
+                        let span = span::Span::from_positions(start, end, span.file.clone());
+                        let span = match inverse_encoding {
+                            PositionEncoding::Utf16CodeUnit => VfsSpan::from_utf16(span, None),
+                            _ => VfsSpan::from_usv(span, None),
                         };
-
-                        (byte_start, byte_end)
-                    };
-                    let mut new_text = self.text[..range.0 as usize].to_owned();
-                    new_text.push_str(text);
-                    new_text.push_str(&self.text[range.1 as usize..]);
-                    new_text
+                        inverses.push(Change::ReplaceText {
+                            span,
+                            text: self.text[range.0..range.1].to_owned(),
+                        });
+                    }
+                    self.replace_range(range.0, range.1, text);
+                }
+                Change::AddFile { ref file, ref text } => {
+                    if let Some(ref mut edits) = edits {
+                        let start = span::Position::new(
+                            span::Row::new_zero_indexed(0),
+                            span::Column::new_zero_indexed(0),
+                        );
+                        let encoding = PositionEncoding::UnicodeScalarValue;
+                        edits.push(PositionEdit {
+                            start,
+                            old_end: end_position(start, &self.text, encoding),
+                            new_end: end_position(start, text, encoding),
+                        });
+                    }
+                    if let Some(ref mut undo) = undo {
+                        undo.push(UndoEdit {
+                            start: 0,
+                            removed: (*self.text).clone(),
+                            inserted: text.clone(),
+                        });
+                    }
+                    if let Some(ref mut inverses) = inverses {
+                        inverses.push(Change::AddFile {
+                            file: file.clone(),
+                            text: (*self.text).clone(),
+                        });
+                    }
+                    self.text = Arc::new(text.to_owned());
+                    self.line_indices.reset(&self.text);
+                    self.column_indices.get_mut().clear();
                 }
-                Change::AddFile { file: _, ref text } => text.to_owned(),
+            }
+        }
+
+        // Changes which add or remove lines leave part of the index to be
+        // rebuilt, which is only worth doing once, after all of them.
+        self.line_indices.rebuild();
+        self.max_line_width.set(None);
+        self.changed = true;
+        self.debug_check_invariants();
+        Ok(())
+    }
+
+    // Return the range of bytes `change` would replace and the number of bytes
+    // it would insert, with the same arguments as `make_change`.
+    fn preview_change(
+        &self,
+        change: &Change,
+        encoding: Option<PositionEncoding>,
+        line_ending: Option<LineEnding>,
+    ) -> Result<(Range<usize>, usize), Error> {
+        match *change {
+            Change::ReplaceText {
+                span: ref vfs_span,
+                ref text,
+            } => {
+                let encoding = encoding.unwrap_or_else(|| vfs_span.encoding());
+                let range = self.byte_range(vfs_span, encoding)?;
+                Ok((range.0..range.1, with_line_ending(text, line_ending).len()))
+            }
+            Change::AddFile { ref text, .. } => Ok((0..self.text.len(), text.len())),
+        }
+    }
+
+    // Replace the bytes `start..end` of the text with `text`. Callers must
+    // update `max_line_width` and `changed`.
+    fn replace_range(&mut self, start: usize, end: usize, text: &str) {
+        if start == end && end == self.text.len() {
+            // Appending, e.g., to a log, only changes the last line.
+            let last_row = self.line_indices.len() - 2;
+            self.column_indices.get_mut().remove(&last_row);
+            self.line_indices.append(text);
+            Arc::make_mut(&mut self.text).push_str(text);
+            return;
+        }
+
+        let removed = &self.text[start..end];
+        let column_indices = self.column_indices.get_mut();
+        if removed.contains('\n') || text.contains('\n') {
+            // Line numbers may have changed.
+            column_indices.clear();
+        } else {
+            let row = self.line_indices.line_of(start);
+            if text.len() >= LONG_LINE_THRESHOLD {
+                // Rebuild the index rather than leave a large gap in it.
+                column_indices.remove(&row);
+            } else if let Some(index) = column_indices.get_mut(&row) {
+                let line_start = self.line_indices.get(row).unwrap() as usize;
+                index.splice(start - line_start, removed, text);
+            }
+        }
+        self.line_indices.splice(start, removed, text);
+        Arc::make_mut(&mut self.text).replace_range(start..end, text);
+    }
+
+    // Return the range of bytes covered by `vfs_span`, whose columns are in
+    // the units of `encoding`.
+    fn byte_range(
+        &self,
+        vfs_span: &VfsSpan,
+        encoding: PositionEncoding,
+    ) -> Result<(usize, usize), Error> {
+        let (span, len) = (vfs_span.span(), vfs_span.len());
+        let first_line = self.load_line(span.range.row_start)?;
+        let byte_in_first_line =
+            |c| self.byte_in_line(span.range.row_start, first_line, c, encoding);
+        let line_start = self.line_indices.get(span.range.row_start.0 as usize);
+        let byte_start =
+            try_opt_loc!(line_start) + byte_in_first_line(span.range.col_start)? as u32;
+
+        let byte_end = if let Some(len) = len {
+            // if `len` exists, the replaced portion of text
+            // is `len` chars starting from row_start/col_start,
+            // and may reach, but not run past, the end of the text.
+            let rest = &self.text[byte_start as usize..];
+            if len > u64::from(u32::MAX) {
+                return Err(Error::BadLocation);
+            }
+            let len = len as u32;
+            let len_in_bytes = encoding
+                .byte_in_str(rest, span::Column::new_zero_indexed(len))
+                .map_err(|e| {
+                    if len as usize > encoding.count(rest) {
+                        Error::BadLocation
+                    } else {
+                        e
+                    }
+                })?;
+            byte_start + len_in_bytes as u32
+        } else {
+            // if no `len`, fall back to using row_end/col_end
+            // for determining the tail end of replaced text.
+            let byte_in_last_line = if span.range.row_end == span.range.row_start {
+                byte_in_first_line(span.range.col_end)?
+            } else {
+                let last_line = self.load_line(span.range.row_end)?;
+                self.byte_in_line(span.range.row_end, last_line, span.range.col_end, encoding)?
             };
+            try_opt_loc!(self.line_indices.get(span.range.row_end.0 as usize))
+                + byte_in_last_line as u32
+        };
+
+        Ok((byte_start as usize, byte_end as usize))
+    }
+
+    // Return the byte offset in `line`, which is line `row` of the text, of
+    // column `c`. Uses a column index if the line is long.
+    fn byte_in_line(
+        &self,
+        row: span::Row<span::ZeroIndexed>,
+        line: &str,
+        c: span::Column<span::ZeroIndexed>,
+        encoding: PositionEncoding,
+    ) -> Result<usize, Error> {
+        let utf16 = match encoding {
+            _ if line.len() < LONG_LINE_THRESHOLD => return encoding.byte_in_str(line, c),
+            PositionEncoding::UnicodeScalarValue => false,
+            PositionEncoding::Utf16CodeUnit => true,
+            PositionEncoding::Grapheme => return encoding.byte_in_str(line, c),
+        };
+        let mut column_indices = self.column_indices.borrow_mut();
+        let index = column_indices
+            .entry(row.0 as usize)
+            .or_insert_with(|| ColumnIndex::new(line));
+        index.byte_offset(line, c, utf16)
+    }
 
-            self.text = new_text;
-            self.line_indices = make_line_indices(&self.text);
+    // Whether UTF-16 column `c` of a line is between the two halves of a
+    // surrogate pair, in which case it was clamped to the start of the
+    // character, and so maps to the same byte as the column before it.
+    fn splits_surrogate_pair(
+        &self,
+        row: span::Row<span::ZeroIndexed>,
+        c: span::Column<span::ZeroIndexed>,
+    ) -> Result<bool, Error> {
+        if c.0 == 0 {
+            return Ok(false);
         }
+        let line = self.load_line(row)?;
+        let encoding = PositionEncoding::Utf16CodeUnit;
+        let prev = span::Column::new_zero_indexed(c.0 - 1);
+        let before = self.byte_in_line(row, line, prev, encoding)?;
+        Ok(self.byte_in_line(row, line, c, encoding)? == before)
+    }
 
+    // Replace the whole text, keeping the derived data in sync.
+    fn set_text(&mut self, text: String) {
+        self.line_indices = LineIndex::new(&text);
+        self.column_indices.get_mut().clear();
+        self.text = Arc::new(text);
+        self.max_line_width.set(None);
         self.changed = true;
+        self.debug_check_invariants();
+    }
+
+    // Release memory left over from edits, without changing the text.
+    fn compact(&mut self) {
+        if let Some(text) = Arc::get_mut(&mut self.text) {
+            text.shrink_to_fit();
+        } else {
+            // Snapshots share the text, copy it rather than reallocate theirs.
+            self.text = Arc::new((*self.text).clone());
+        }
+        self.line_indices = LineIndex::new(&self.text);
+        self.line_indices.shrink_to_fit();
+        *self.column_indices.get_mut() = HashMap::new();
+    }
+
+    // Check that the line index matches the text.
+    fn check_invariants(&self) -> Result<(), Error> {
+        let mut prev = 0;
+        for (i, offset) in self.line_indices.iter().enumerate() {
+            if self.line_indices.get(i) != Some(offset) || offset < prev {
+                return Err(Error::InternalError("line index is inconsistent"));
+            }
+            let offset = offset as usize;
+            if i > 0 && i < self.line_indices.len() - 1 && self.text.as_bytes()[offset - 1] != b'\n'
+            {
+                return Err(Error::InternalError("line index does not match line breaks"));
+            }
+            prev = offset as u32;
+        }
+        if prev as usize != self.text.len() {
+            return Err(Error::InternalError("line index does not match text length"));
+        }
+        if self.text.matches('\n').count() != self.line_indices.len() - 2 {
+            return Err(Error::InternalError("line index does not match line breaks"));
+        }
         Ok(())
     }
 
+    fn debug_check_invariants(&self) {
+        if cfg!(debug_assertions) {
+            if let Err(e) = self.check_invariants() {
+                panic!("{}", e);
+            }
+        }
+    }
+
+    fn line_ending(&self) -> LineEnding {
+        let newlines = self.line_indices.len() - 2;
+        let crlfs = self.text.matches("\r\n").count();
+        if crlfs == 0 {
+            LineEnding::Lf
+        } else if crlfs == newlines {
+            LineEnding::CrLf
+        } else {
+            LineEnding::Mixed
+        }
+    }
+
+    // The more common line ending in the text, or `Lf` if neither is.
+    fn dominant_line_ending(&self) -> LineEnding {
+        let newlines = self.line_indices.len() - 2;
+        let crlfs = self.text.matches("\r\n").count();
+        if crlfs * 2 > newlines {
+            LineEnding::CrLf
+        } else {
+            LineEnding::Lf
+        }
+    }
+
+    // Returns true if the text was changed.
+    fn normalize_line_endings(&mut self, ending: LineEnding) -> bool {
+        if ending == LineEnding::Mixed {
+            return false;
+        }
+        let text = convert_line_endings(&self.text, ending);
+        if text == *self.text {
+            return false;
+        }
+        self.set_text(text);
+        true
+    }
+
+    fn line_ranges(&self) -> LineRanges {
+        let offsets: Vec<_> = self.line_indices.iter().map(|i| i as usize).collect();
+        offsets
+            .windows(2)
+            .enumerate()
+            .map(|(i, w)| (span::Row::new_zero_indexed(i as u32), w[0]..w[1]))
+            .collect()
+    }
+
+    // Every line but the first starts just after a `\n`, and the index ends
+    // with the length of the text.
+    fn newline_offsets(&self) -> Vec<usize> {
+        let n = self.line_indices.len();
+        self.line_indices.iter().skip(1).take(n - 2).map(|i| i as usize).collect()
+    }
+
+    fn max_line_width(&self) -> u32 {
+        if let Some(width) = self.max_line_width.get() {
+            return width;
+        }
+
+        let mut width = 0;
+        let _ = self.for_each_line(|line, _| {
+            width = width.max(trim_line_terminator(line).chars().count() as u32);
+            Ok(())
+        });
+        self.max_line_width.set(Some(width));
+        width
+    }
+
+    // Return the position of the byte at `offset`, with columns in unicode
+    // scalar values.
+    fn position_of(&self, offset: usize) -> span::Position<span::ZeroIndexed> {
+        self.position_in(offset, PositionEncoding::UnicodeScalarValue)
+    }
+
+    // Return the position of the byte at `offset`, with columns in the units of
+    // `encoding`.
+    fn position_in(
+        &self,
+        offset: usize,
+        encoding: PositionEncoding,
+    ) -> span::Position<span::ZeroIndexed> {
+        let row = self.line_indices.line_of(offset);
+        let line_start = self.line_indices.get(row).unwrap() as usize;
+        span::Position::new(
+            span::Row::new_zero_indexed(row as u32),
+            span::Column::new_zero_indexed(encoding.count(&self.text[line_start..offset]) as u32),
+        )
+    }
+
+    fn num_lines(&self, policy: TrailingEmptyLinePolicy) -> usize {
+        let n = self.line_indices.len() - 1;
+        if policy == TrailingEmptyLinePolicy::Drop && self.text.ends_with('\n') {
+            n - 1
+        } else {
+            n
+        }
+    }
+
     fn load_line(&self, line: span::Row<span::ZeroIndexed>) -> Result<&str, Error> {
-        let start = *try_opt_loc!(self.line_indices.get(line.0 as usize));
-        let end = *try_opt_loc!(self.line_indices.get(line.0 as usize + 1));
+        Ok(&self.text[self.line_byte_range(line)?])
+    }
+
+    // The byte range of a line, including its line terminator.
+    fn line_byte_range(&self, line: span::Row<span::ZeroIndexed>) -> Result<Range<usize>, Error> {
+        let start = try_opt_loc!(self.line_indices.get(line.0 as usize));
+        let end = try_opt_loc!(self.line_indices.get(line.0 as usize + 1));
 
         if (end as usize) <= self.text.len() && start <= end {
-            Ok(&self.text[start as usize..end as usize])
+            Ok(start as usize..end as usize)
         } else {
             Err(Error::BadLocation)
         }
     }
 
+    fn load_from_line(&self, line: span::Row<span::ZeroIndexed>) -> Result<&str, Error> {
+        // Check that the line exists, not just its start offset.
+        try_opt_loc!(self.line_indices.get(line.0 as usize + 1));
+        let start = try_opt_loc!(self.line_indices.get(line.0 as usize));
+        Ok(&self.text[start as usize..])
+    }
+
     fn load_lines(
         &self,
         line_start: span::Row<span::ZeroIndexed>,
@@ -772,8 +5020,8 @@ impl TextFile {
             line_end = self.line_indices.len() - 1;
         }
 
-        let start = (*try_opt_loc!(self.line_indices.get(line_start))) as usize;
-        let end = (*try_opt_loc!(self.line_indices.get(line_end))) as usize;
+        let start = try_opt_loc!(self.line_indices.get(line_start)) as usize;
+        let end = try_opt_loc!(self.line_indices.get(line_end)) as usize;
 
         if (end) <= self.text.len() && start <= end {
             Ok(&self.text[start..end])
@@ -789,9 +5037,9 @@ impl TextFile {
             line_end = self.line_indices.len() - 1;
         }
 
-        let start = (*try_opt_loc!(self.line_indices.get(line_start))) as usize;
+        let start = try_opt_loc!(self.line_indices.get(line_start)) as usize;
         let start = start + range.col_start.0 as usize;
-        let end = (*try_opt_loc!(self.line_indices.get(line_end))) as usize;
+        let end = try_opt_loc!(self.line_indices.get(line_end)) as usize;
         let end = end + range.col_end.0 as usize;
 
         if (end) <= self.text.len() && start <= end {
@@ -806,9 +5054,9 @@ impl TextFile {
         F: FnMut(&str, usize) -> Result<(), Error>,
     {
         let mut line_iter = self.line_indices.iter();
-        let mut start = *line_iter.next().unwrap() as usize;
+        let mut start = line_iter.next().unwrap() as usize;
         for (i, idx) in line_iter.enumerate() {
-            let idx = *idx as usize;
+            let idx = idx as usize;
             f(&self.text[start..idx], i)?;
             start = idx;
         }
@@ -817,6 +5065,104 @@ impl TextFile {
     }
 }
 
+/// A file on disk of which only the line indices are kept in memory.
+struct PagedFile {
+    file: fs::File,
+    line_indices: Vec<u64>,
+}
+
+impl PagedFile {
+    fn open(path: &Path) -> Result<PagedFile, Error> {
+        let io_err = |e: io::Error| Error::Io(Some(path.to_owned()), Some(e.to_string()));
+
+        let file = fs::File::open(path).map_err(io_err)?;
+        let mut line_indices = vec![0];
+        let mut len = 0;
+        {
+            let mut reader = io::BufReader::new(&file);
+            loop {
+                let consumed = {
+                    let buf = reader.fill_buf().map_err(io_err)?;
+                    for (i, b) in buf.iter().enumerate() {
+                        if *b == 0xA {
+                            line_indices.push(len + i as u64 + 1);
+                        }
+                    }
+                    buf.len()
+                };
+                if consumed == 0 {
+                    break;
+                }
+                reader.consume(consumed);
+                len += consumed as u64;
+            }
+        }
+        line_indices.push(len);
+
+        Ok(PagedFile { file, line_indices })
+    }
+
+    fn read(&self, start: u64, end: u64) -> Result<String, Error> {
+        let io_err = |e: io::Error| Error::Io(None, Some(e.to_string()));
+
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(start)).map_err(io_err)?;
+        let mut buf = vec![0; (end - start) as usize];
+        file.read_exact(&mut buf).map_err(io_err)?;
+        String::from_utf8(buf).map_err(|_| Error::BadFileKind)
+    }
+
+    fn num_lines(&self, policy: TrailingEmptyLinePolicy) -> usize {
+        let n = self.line_indices.len() - 1;
+        // Only the last line can be empty, and only after a newline.
+        let trailing_empty = n > 1 && self.line_indices[n - 1] == self.line_indices[n];
+        if policy == TrailingEmptyLinePolicy::Drop && trailing_empty {
+            n - 1
+        } else {
+            n
+        }
+    }
+
+    fn load_line(&self, line: span::Row<span::ZeroIndexed>) -> Result<String, Error> {
+        let start = *try_opt_loc!(self.line_indices.get(line.0 as usize));
+        let end = *try_opt_loc!(self.line_indices.get(line.0 as usize + 1));
+        self.read(start, end)
+    }
+
+    fn load_from_line(&self, line: span::Row<span::ZeroIndexed>) -> Result<String, Error> {
+        try_opt_loc!(self.line_indices.get(line.0 as usize + 1));
+        let start = *try_opt_loc!(self.line_indices.get(line.0 as usize));
+        self.read(start, *self.line_indices.last().unwrap())
+    }
+
+    fn load_lines(
+        &self,
+        line_start: span::Row<span::ZeroIndexed>,
+        line_end: span::Row<span::ZeroIndexed>,
+    ) -> Result<String, Error> {
+        let line_end = (line_end.0 as usize).min(self.line_indices.len() - 1);
+        let start = *try_opt_loc!(self.line_indices.get(line_start.0 as usize));
+        let end = self.line_indices[line_end];
+        if start <= end {
+            self.read(start, end)
+        } else {
+            Err(Error::BadLocation)
+        }
+    }
+
+    fn load_range(&self, range: span::Range<span::ZeroIndexed>) -> Result<String, Error> {
+        let line_end = (range.row_end.0 as usize).min(self.line_indices.len() - 1);
+        let start = *try_opt_loc!(self.line_indices.get(range.row_start.0 as usize));
+        let start = start + u64::from(range.col_start.0);
+        let end = self.line_indices[line_end] + u64::from(range.col_end.0);
+        if end <= *self.line_indices.last().unwrap() && start <= end {
+            self.read(start, end)
+        } else {
+            Err(Error::BadLocation)
+        }
+    }
+}
+
 /// Return a UTF-8 byte offset in `s` for a given UTF-8 unicode scalar value offset.
 fn byte_in_str(s: &str, c: span::Column<span::ZeroIndexed>) -> Result<usize, Error> {
     // We simulate a null-terminated string here because spans are exclusive at
@@ -836,15 +5182,15 @@ fn byte_in_str(s: &str, c: span::Column<span::ZeroIndexed>) -> Result<usize, Err
     ));
 }
 
-/// Return a UTF-8 byte offset in `s` for a given UTF-16 code unit offset.
+/// Return a UTF-8 byte offset in `s` for a given UTF-16 code unit offset. An
+/// offset between the two halves of a surrogate pair is clamped to the start of
+/// the character, as LSP requires.
 fn byte_in_str_utf16(s: &str, c: span::Column<span::ZeroIndexed>) -> Result<usize, Error> {
     let (mut utf8_offset, mut utf16_offset) = (0, 0);
     let target_utf16_offset = c.0 as usize;
 
     for chr in s.chars().chain(std::iter::once('\0')) {
-        if utf16_offset > target_utf16_offset {
-            break;
-        } else if utf16_offset == target_utf16_offset {
+        if utf16_offset + chr.len_utf16() > target_utf16_offset {
             return Ok(utf8_offset);
         }
 
@@ -853,19 +5199,138 @@ fn byte_in_str_utf16(s: &str, c: span::Column<span::ZeroIndexed>) -> Result<usiz
     }
 
     return Err(Error::InternalError(
-        "UTF-16 code unit offset is not at `str` char boundary",
+        "Out of bounds access in `byte_in_str_utf16`",
     ));
 }
 
+/// Return a UTF-8 byte offset in `s` for a given grapheme cluster offset.
+fn byte_in_str_grapheme(s: &str, c: span::Column<span::ZeroIndexed>) -> Result<usize, Error> {
+    match grapheme_starts(s).chain(Some(s.len())).nth(c.0 as usize) {
+        Some(b) => Ok(b),
+        None => Err(Error::InternalError(
+            "Out of bounds access in `byte_in_str_grapheme`",
+        )),
+    }
+}
+
+/// A source of files other than the disk. See `Vfs::register_loader`.
+pub trait FileSource: Send + Sync {
+    /// Read the file at `path`, or return `None` if this source does not
+    /// provide it, in which case the file is read from disk.
+    fn read(&self, path: &Path) -> Result<Option<FileContents>, Error>;
+
+    /// Write `contents` to the file at `path` for `Vfs::write_file`, and
+    /// return `true`, or return `false` if this source does not store the
+    /// file, in which case it is written to disk. By default, nothing is
+    /// written.
+    fn write(&self, _path: &Path, _contents: &FileContents) -> Result<bool, Error> {
+        Ok(false)
+    }
+}
+
+// Tries each source in turn, see `Vfs::with_loader_chain`.
+struct LoaderChain(Vec<Box<dyn FileSource>>);
+
+impl FileSource for LoaderChain {
+    fn read(&self, path: &Path) -> Result<Option<FileContents>, Error> {
+        for source in &self.0 {
+            if let Some(contents) = source.read(path)? {
+                return Ok(Some(contents));
+            }
+        }
+        Ok(None)
+    }
+
+    fn write(&self, path: &Path, contents: &FileContents) -> Result<bool, Error> {
+        for source in &self.0 {
+            if source.write(path, contents)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+// Serves a single file added with `Vfs::add_virtual`.
+struct VirtualSource {
+    path: PathBuf,
+    text: String,
+}
+
+impl FileSource for VirtualSource {
+    fn read(&self, path: &Path) -> Result<Option<FileContents>, Error> {
+        if path == self.path {
+            Ok(Some(FileContents::Text(self.text.clone())))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+// Keeps a scratch buffer registered with `Vfs::register_scratch` from being
+// read from disk.
+struct ScratchSource {
+    path: PathBuf,
+}
+
+impl FileSource for ScratchSource {
+    fn read(&self, path: &Path) -> Result<Option<FileContents>, Error> {
+        if path == self.path {
+            Err(Error::FileNotCached)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+// `gzip` is `Config::gzip`.
 trait FileLoader {
-    fn read<U>(file_name: &Path) -> Result<File<U>, Error>;
-    fn write(file_name: &Path, file: &FileKind) -> Result<(), Error>;
+    fn read<U>(file_name: &Path, options: &ReadOptions, gzip: bool) -> Result<File<U>, Error>;
+    fn write(
+        file_name: &Path,
+        file: &FileKind,
+        options: &WriteOptions,
+        gzip: bool,
+    ) -> Result<(), Error>;
+    // Remove a file. It is not an error if the file does not exist.
+    fn remove(file_name: &Path) -> Result<(), Error>;
+
+    // Read a file again, given its previously cached version, which may have
+    // metadata worth carrying over.
+    fn reload<U>(
+        file_name: &Path,
+        _old: &File<U>,
+        options: &ReadOptions,
+        gzip: bool,
+    ) -> Result<File<U>, Error> {
+        Self::read(file_name, options, gzip)
+    }
+
+    // The current stamp of the file on disk, if known.
+    fn stamp(_file_name: &Path) -> Option<DiskStamp> {
+        None
+    }
+
+    // Whether the file exists on disk.
+    fn exists(_file_name: &Path) -> bool {
+        false
+    }
+
+    // Call `f` with each line of a file and its index, as for
+    // `TextFile::for_each_line`, without keeping the whole file in memory if
+    // possible.
+    fn scan_lines<F>(file_name: &Path, f: F) -> Result<(), Error>
+    where
+        F: FnMut(&str, usize) -> Result<(), Error>,
+    {
+        Self::read::<()>(file_name, &ReadOptions::default(), false)?.for_each_line(f)
+    }
 }
 
 struct RealFileLoader;
 
 impl FileLoader for RealFileLoader {
-    fn read<U>(file_name: &Path) -> Result<File<U>, Error> {
+    fn read<U>(file_name: &Path, options: &ReadOptions, gzip: bool) -> Result<File<U>, Error> {
         let mut file = match fs::File::open(file_name) {
             Ok(f) => f,
             Err(_) => {
@@ -875,31 +5340,115 @@ impl FileLoader for RealFileLoader {
                 ))
             }
         };
+        let metadata = file.metadata().ok();
+        let too_large = |size| Error::FileTooLarge {
+            path: file_name.to_owned(),
+            size,
+            limit: options.max_file_size.unwrap_or(0),
+        };
+        if let (Some(limit), Some(ref metadata)) = (options.max_file_size, &metadata) {
+            if metadata.len() > limit as u64 {
+                return Err(too_large(metadata.len() as usize));
+            }
+        }
+
         let mut buf = vec![];
-        if let Err(_) = file.read_to_end(&mut buf) {
+        // The file may have grown since we checked its size.
+        let limit = options
+            .max_file_size
+            .map_or(u64::MAX, |limit| (limit as u64).saturating_add(1));
+        if let Err(_) = file.by_ref().take(limit).read_to_end(&mut buf) {
             return Err(Error::Io(
                 Some(file_name.to_owned()),
                 Some(format!("Could not read file: {}", file_name.display())),
             ));
         }
+        if buf.len() as u64 == limit {
+            return Err(too_large(buf.len()));
+        }
+        #[cfg(not(feature = "flate2"))]
+        let _ = gzip;
+        #[cfg(feature = "flate2")]
+        {
+            if gzip && (has_gz_extension(file_name) || buf.starts_with(&GZIP_MAGIC)) {
+                buf = gunzip(file_name, &buf, limit)?;
+                if buf.len() as u64 == limit {
+                    return Err(too_large(buf.len()));
+                }
+            }
+        }
 
-        match String::from_utf8(buf) {
-            Ok(s) => Ok(File {
-                kind: FileKind::Text(TextFile {
-                    line_indices: make_line_indices(&s),
-                    text: s,
-                    changed: false,
-                }),
-                user_data: None,
-            }),
-            Err(e) => Ok(File {
-                kind: FileKind::Binary(e.into_bytes()),
-                user_data: None,
-            }),
+        let mut result = match (String::from_utf8(buf), options.utf8_errors) {
+            (Ok(s), _) => File::new(FileKind::Text(TextFile::new(s, false))),
+            (Err(e), Utf8ErrorMode::Strict) => File::new(FileKind::Binary(e.into_bytes())),
+            (Err(e), Utf8ErrorMode::Lossy) => {
+                let text = String::from_utf8_lossy(e.as_bytes()).into_owned();
+                let mut file = File::new(FileKind::Text(TextFile::new(text, false)));
+                file.lossy = true;
+                file
+            }
+        };
+        result.disk_stamp = metadata.as_ref().and_then(DiskStamp::new);
+        Ok(result)
+    }
+
+    fn stamp(file_name: &Path) -> Option<DiskStamp> {
+        DiskStamp::new(&fs::metadata(file_name).ok()?)
+    }
+
+    fn exists(file_name: &Path) -> bool {
+        file_name.is_file()
+    }
+
+    fn scan_lines<F>(file_name: &Path, mut f: F) -> Result<(), Error>
+    where
+        F: FnMut(&str, usize) -> Result<(), Error>,
+    {
+        let io_err = |e: io::Error| match e.kind() {
+            io::ErrorKind::InvalidData => Error::BadFileKind,
+            _ => Error::Io(Some(file_name.to_owned()), Some(e.to_string())),
+        };
+        let file = fs::File::open(file_name).map_err(io_err)?;
+        let mut reader = io::BufReader::new(file);
+        let mut line = String::new();
+        for i in 0.. {
+            line.clear();
+            reader.read_line(&mut line).map_err(io_err)?;
+            f(&line, i)?;
+            // Only the last line has no line terminator. Like in `LineIndex`,
+            // it is empty if the file ends in a newline.
+            if !line.ends_with('\n') {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn reload<U>(
+        file_name: &Path,
+        old: &File<U>,
+        options: &ReadOptions,
+        gzip: bool,
+    ) -> Result<File<U>, Error> {
+        let mut file = Self::read(file_name, options, gzip)?;
+        file.encoding = old.encoding;
+        Ok(file)
+    }
+
+    fn remove(file_name: &Path) -> Result<(), Error> {
+        match fs::remove_file(file_name) {
+            Ok(()) => Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(Error::Io(Some(file_name.to_owned()), Some(e.to_string()))),
         }
     }
 
-    fn write(file_name: &Path, file: &FileKind) -> Result<(), Error> {
+    fn write(
+        file_name: &Path,
+        file: &FileKind,
+        options: &WriteOptions,
+        gzip: bool,
+    ) -> Result<(), Error> {
         use std::io::Write;
 
         macro_rules! try_io {
@@ -913,12 +5462,99 @@ impl FileLoader for RealFileLoader {
             };
         }
 
-        let mut out = try_io!(::std::fs::File::create(file_name));
-        try_io!(out.write_all(file.as_bytes()));
+        // The directory may have been deleted since the file was cached.
+        let parent = file_name.parent().filter(|dir| !dir.as_os_str().is_empty());
+        let create_missing_dir = || -> Result<(), Error> {
+            let dir = match parent {
+                Some(dir) if !dir.exists() => dir,
+                _ => return Ok(()),
+            };
+            if options.create_dirs {
+                try_io!(fs::create_dir_all(dir));
+                Ok(())
+            } else {
+                Err(Error::Io(
+                    Some(file_name.to_owned()),
+                    Some(format!("Directory does not exist: {}", dir.display())),
+                ))
+            }
+        };
+        create_missing_dir()?;
+
+        #[cfg(feature = "flate2")]
+        let compressed = if gzip && is_gzip_file(file_name) {
+            Some(try_io!(self::gzip(file.as_bytes())))
+        } else {
+            None
+        };
+        #[cfg(not(feature = "flate2"))]
+        let compressed: Option<Vec<u8>> = {
+            let _ = gzip;
+            None
+        };
+
+        let mut out = match fs::File::create(file_name) {
+            Ok(out) => out,
+            // The directory was deleted after we checked for it.
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                create_missing_dir()?;
+                try_io!(fs::File::create(file_name))
+            }
+            Err(e) => try_io!(Err(e)),
+        };
+        try_io!(out.write_all(compressed.as_deref().unwrap_or_else(|| file.as_bytes())));
+        if options.fsync {
+            try_io!(out.sync_all());
+        }
         Ok(())
     }
 }
 
+// The first bytes of a gzip-compressed file.
+#[cfg(feature = "flate2")]
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+#[cfg(feature = "flate2")]
+fn has_gz_extension(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "gz")
+}
+
+// Whether a file should be compressed when written: its path ends in `.gz`,
+// or the file on disk is compressed.
+#[cfg(feature = "flate2")]
+fn is_gzip_file(path: &Path) -> bool {
+    if has_gz_extension(path) {
+        return true;
+    }
+    let mut magic = [0; 2];
+    fs::File::open(path)
+        .and_then(|mut f| f.read_exact(&mut magic))
+        .is_ok_and(|()| magic == GZIP_MAGIC)
+}
+
+// Decompress the contents of a gzip-compressed file, reading at most `limit`
+// bytes of decompressed data.
+#[cfg(feature = "flate2")]
+fn gunzip(file_name: &Path, compressed: &[u8], limit: u64) -> Result<Vec<u8>, Error> {
+    let mut buf = vec![];
+    match flate2::read::GzDecoder::new(compressed).take(limit).read_to_end(&mut buf) {
+        Ok(_) => Ok(buf),
+        Err(_) => Err(Error::Io(
+            Some(file_name.to_owned()),
+            Some(format!("Could not decompress file: {}", file_name.display())),
+        )),
+    }
+}
+
+#[cfg(feature = "flate2")]
+fn gzip(bytes: &[u8]) -> io::Result<Vec<u8>> {
+    use std::io::Write;
+
+    let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+    encoder.write_all(bytes)?;
+    encoder.finish()
+}
+
 #[cfg(test)]
 mod tests {
     use span::Column;
@@ -932,7 +5568,30 @@ mod tests {
             byte_in_str_utf16("😢a", Column::new_zero_indexed('😢'.len_utf16() as u32)).unwrap()
         );
 
-        // 😢 is represented by 2 u16s - we can't index in the middle of a character
-        assert!(byte_in_str_utf16("😢", Column::new_zero_indexed(1)).is_err());
+        // 😢 is represented by 2 u16s - an offset in the middle of it is
+        // clamped to its start.
+        assert_eq!(byte_in_str_utf16("a😢", Column::new_zero_indexed(2)), Ok(1));
+        assert!(byte_in_str_utf16("😢", Column::new_zero_indexed(3)).is_err());
+    }
+
+    #[test]
+    fn diff_lines() {
+        use super::diff_lines;
+
+        let cases = [
+            ("a\nb\nc\n", "a\nc\n"),
+            ("a\nb\nc\n", "x\na\nb\ny\nc"),
+            ("", "a\n"),
+            ("a\nb", ""),
+            ("a\nb\na\nb\n", "b\na\nb\na\n"),
+        ];
+        for &(old, new) in &cases {
+            let mut lines: Vec<String> = old.split_inclusive('\n').map(|l| l.to_owned()).collect();
+            for edit in diff_lines(old, new).iter().rev() {
+                let range = edit.line_start.0 as usize..edit.line_end.0 as usize;
+                lines.splice(range, Some(edit.text.clone()));
+            }
+            assert_eq!(lines.concat(), new);
+        }
     }
 }
@@ -0,0 +1,380 @@
+//! An index of the byte offsets at which the lines of a text start.
+
+use std::cell::{Cell, RefCell};
+
+/// The byte offsets at which the lines of a text start, followed by the length
+/// of the text. A text ending in `\n` has an empty last line.
+///
+/// Offsets are stored as a Fenwick tree over line lengths, so looking up an
+/// offset takes O(log n), as does updating the index for an edit which does
+/// not add or remove lines. This is not a balanced tree: an edit which adds or
+/// removes lines still takes O(n) in the number of lines after it, both to
+/// shift their lengths and to rebuild the tree over them, although without
+/// rescanning the text. The tree is rebuilt lazily, when it is next needed, so
+/// several such edits, each before the previous one, only rebuild it once.
+/// Appending lines to the end of the text takes O(log n) per line.
+#[derive(Clone, Debug)]
+pub struct LineIndex {
+    // Length of each line in bytes, including its `\n`. Only the last line can
+    // be empty.
+    lengths: Vec<u32>,
+    // 1-based Fenwick tree over `lengths`: `tree[i]` is the sum of the lengths
+    // of lines `i - lowbit(i)..i`. `tree[0]` is unused. Only `tree[..=valid]`
+    // is up to date.
+    tree: RefCell<Vec<u32>>,
+    valid: Cell<usize>,
+    // The number of times part of the tree was rebuilt.
+    rebuilds: Cell<u64>,
+}
+
+fn lowbit(i: usize) -> usize {
+    i & i.wrapping_neg()
+}
+
+// Split `text` into line lengths, including the empty last line if `text`
+// ends in a newline.
+fn line_lengths(text: &str) -> Vec<u32> {
+    let mut lengths = vec![];
+    let mut start = 0;
+    for (i, b) in text.bytes().enumerate() {
+        if b == 0xA {
+            lengths.push((i + 1 - start) as u32);
+            start = i + 1;
+        }
+    }
+    lengths.push((text.len() - start) as u32);
+    lengths
+}
+
+impl LineIndex {
+    pub fn new(text: &str) -> LineIndex {
+        let lengths = line_lengths(text);
+        let n = lengths.len();
+        let mut tree = Vec::with_capacity(n + 1);
+        tree.push(0);
+        tree.extend_from_slice(&lengths);
+        for i in 1..=n {
+            let parent = i + lowbit(i);
+            if parent <= n {
+                tree[parent] += tree[i];
+            }
+        }
+        LineIndex {
+            lengths,
+            tree: RefCell::new(tree),
+            valid: Cell::new(n),
+            rebuilds: Cell::new(0),
+        }
+    }
+
+    /// Index `text` from scratch, e.g., after the whole text is replaced,
+    /// keeping the count of rebuilds.
+    pub fn reset(&mut self, text: &str) {
+        let rebuilds = self.rebuilds();
+        *self = LineIndex::new(text);
+        self.rebuilds.set(rebuilds);
+    }
+
+    // Bring `tree[..=end]` up to date.
+    fn rebuild_tree(&self, end: usize) {
+        let valid = self.valid.get();
+        if end <= valid {
+            return;
+        }
+        let mut tree = self.tree.borrow_mut();
+        tree.resize(self.lengths.len() + 1, 0);
+        for i in valid + 1..=end {
+            // Add the nodes covering lines `i - lowbit(i)..i - 1`.
+            let mut sum = self.lengths[i - 1];
+            let mut child = i - 1;
+            while child > i - lowbit(i) {
+                sum += tree[child];
+                child -= lowbit(child);
+            }
+            tree[i] = sum;
+        }
+        self.valid.set(end);
+        self.rebuilds.set(self.rebuilds.get() + 1);
+    }
+
+    /// Bring the whole index up to date, e.g., after a batch of edits.
+    pub fn rebuild(&self) {
+        self.rebuild_tree(self.lengths.len());
+    }
+
+    /// The number of times part of the index was rebuilt after edits which
+    /// added or removed lines.
+    pub fn rebuilds(&self) -> u64 {
+        self.rebuilds.get()
+    }
+
+    /// The number of offsets in the index, i.e., the number of lines plus one.
+    pub fn len(&self) -> usize {
+        self.lengths.len() + 1
+    }
+
+    /// The offset at which line `i` starts, or for `i == self.len() - 1`, the
+    /// length of the text.
+    pub fn get(&self, i: usize) -> Option<u32> {
+        if i >= self.len() {
+            return None;
+        }
+        self.rebuild_tree(i);
+        let tree = self.tree.borrow();
+        let (mut i, mut sum) = (i, 0);
+        while i > 0 {
+            sum += tree[i];
+            i -= lowbit(i);
+        }
+        Some(sum)
+    }
+
+    /// Iterate over all offsets in the index.
+    pub fn iter(&self) -> impl Iterator<Item = u32> + '_ {
+        let mut offset = 0;
+        Some(0).into_iter().chain(self.lengths.iter().map(move |len| {
+            offset += len;
+            offset
+        }))
+    }
+
+    /// Return the line containing the byte at `offset`. An offset at the end of
+    /// the text is in the last line.
+    pub fn line_of(&self, offset: usize) -> usize {
+        let n = self.lengths.len();
+        // Search the up to date part of the tree first, which is enough unless
+        // the offset is after it.
+        let valid = self.valid.get();
+        let line = self.search(offset, valid);
+        let line = if line == valid && valid < n {
+            self.rebuild();
+            self.search(offset, n)
+        } else {
+            line
+        };
+        line.min(n - 1)
+    }
+
+    // Return the last line `line <= end` such that lines `..line` end at or
+    // before `offset`.
+    fn search(&self, offset: usize, end: usize) -> usize {
+        let tree = self.tree.borrow();
+        let (mut line, mut rest) = (0, offset);
+        let mut step = end.next_power_of_two();
+        while step > 0 {
+            if line + step <= end && tree[line + step] as usize <= rest {
+                line += step;
+                rest -= tree[line] as usize;
+            }
+            step >>= 1;
+        }
+        line
+    }
+
+    /// Release any spare capacity.
+    pub fn shrink_to_fit(&mut self) {
+        self.lengths.shrink_to_fit();
+        self.tree.get_mut().shrink_to_fit();
+    }
+
+    fn add(&mut self, line: usize, delta: i64) {
+        self.lengths[line] = (i64::from(self.lengths[line]) + delta) as u32;
+        // Nodes which are not up to date are rebuilt from `lengths` anyway.
+        let valid = *self.valid.get_mut();
+        let tree = self.tree.get_mut();
+        let mut i = line + 1;
+        while i <= valid {
+            tree[i] = (i64::from(tree[i]) + delta) as u32;
+            i += lowbit(i);
+        }
+    }
+
+    /// Update the index for appending `text` to the end of the text. Takes
+    /// O(log n) per appended line, rather than rebuilding the index.
+    pub fn append(&mut self, text: &str) {
+        let mut new_lengths = line_lengths(text).into_iter();
+        let last = self.lengths.len() - 1;
+        self.add(last, i64::from(new_lengths.next().unwrap()));
+        let up_to_date = self.valid.get() == self.lengths.len();
+        for len in new_lengths {
+            self.lengths.push(len);
+            if !up_to_date {
+                continue;
+            }
+            // The new node covers lines `i - lowbit(i)..i`, the last of which is
+            // the new line.
+            let i = self.lengths.len();
+            let covered = self.get(i - 1).unwrap() - self.get(i - lowbit(i)).unwrap() + len;
+            let tree = self.tree.get_mut();
+            tree.truncate(i);
+            tree.push(covered);
+            self.valid.set(i);
+        }
+    }
+
+    /// Update the index for replacing the text `removed`, which starts at byte
+    /// `start`, with `inserted`. Takes O(log n) if neither contains a newline,
+    /// otherwise O(n) in the number of lines after `start`.
+    pub fn splice(&mut self, start: usize, removed: &str, inserted: &str) {
+        let first = self.line_of(start);
+        if !removed.contains('\n') && !inserted.contains('\n') {
+            self.add(first, inserted.len() as i64 - removed.len() as i64);
+            return;
+        }
+
+        let end = start + removed.len();
+        let last = self.line_of(end);
+        // The parts of the first and last lines which are not replaced.
+        let head = start - self.get(first).unwrap() as usize;
+        let tail = self.get(last + 1).unwrap() as usize - end;
+
+        let mut new_lengths = line_lengths(inserted);
+        new_lengths[0] += head as u32;
+        *new_lengths.last_mut().unwrap() += tail as u32;
+        self.lengths.splice(first..=last, new_lengths);
+        // Only the nodes covering lines before `first` are unaffected.
+        self.valid.set(self.valid.get().min(first));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LineIndex;
+
+    // A xorshift generator, so that failures are reproducible.
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+    }
+
+    #[test]
+    fn splice() {
+        let mut text = "ab\ncd\n\nef".to_owned();
+        let mut index = LineIndex::new(&text);
+        let edits = [
+            (0, 0, "x"),
+            (2, 5, ""),
+            (1, 1, "1\n2\n"),
+            (0, 7, "\n"),
+            (3, 3, "end\n"),
+        ];
+        for &(start, end, inserted) in &edits {
+            index.splice(start, &text[start..end], inserted);
+            text.replace_range(start..end, inserted);
+            assert_eq!(
+                index.iter().collect::<Vec<_>>(),
+                LineIndex::new(&text).iter().collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[test]
+    fn append() {
+        let mut text = "ab\ncd".to_owned();
+        let mut index = LineIndex::new(&text);
+        let appended = ["", "e", "\n", "f\ng\n", "\n\n\n", "h"];
+        for appended in appended.iter().cycle().take(100) {
+            index.append(appended);
+            text.push_str(appended);
+            let expected = LineIndex::new(&text);
+            assert_eq!(index.iter().collect::<Vec<_>>(), expected.iter().collect::<Vec<_>>());
+            assert_eq!(index.tree, expected.tree);
+        }
+    }
+
+    #[test]
+    fn deferred_rebuild() {
+        let mut text = "a\nb\nc\nd\ne\n".to_owned();
+        let mut index = LineIndex::new(&text);
+        // Edits which each come before the previous one only rebuild the
+        // index once.
+        for &(start, end, inserted) in &[(8, 9, "x\ny"), (5, 6, ""), (2, 2, "\n\n"), (0, 1, "z")] {
+            index.splice(start, &text[start..end], inserted);
+            text.replace_range(start..end, inserted);
+        }
+        assert_eq!(index.rebuilds(), 0);
+        index.rebuild();
+        assert_eq!(index.rebuilds(), 1);
+        let expected = LineIndex::new(&text);
+        for i in 0..=index.len() {
+            assert_eq!(index.get(i), expected.get(i));
+        }
+
+        // Batches of edits in any order, with no lookups in between.
+        let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+        let pieces = ["", "a", "\n", "b\nc", "\n\nd\n"];
+        for _ in 0..200 {
+            for _ in 0..rng.below(5) {
+                let mut start = rng.below(text.len() + 1);
+                let mut end = rng.below(text.len() + 1);
+                if start > end {
+                    std::mem::swap(&mut start, &mut end);
+                }
+                let inserted = if text.len() > 200 { "" } else { pieces[rng.below(pieces.len())] };
+                index.splice(start, &text[start..end], inserted);
+                text.replace_range(start..end, inserted);
+            }
+            let expected = LineIndex::new(&text);
+            for offset in 0..=text.len() {
+                assert_eq!(index.line_of(offset), expected.line_of(offset));
+            }
+            for i in 0..=index.len() {
+                assert_eq!(index.get(i), expected.get(i));
+            }
+        }
+    }
+
+    #[test]
+    fn splice_random() {
+        let mut rng = Rng(0x2545_F491_4F6C_DD1D);
+        let pieces = [
+            "",
+            "a",
+            "bc",
+            "\n",
+            "\n\n",
+            "d\ne",
+            "\r\n",
+            "\u{E9}\n\u{1F600}",
+            "fg\nhi\n",
+        ];
+        let mut text = "ab\ncd\n\nef".to_owned();
+        let mut index = LineIndex::new(&text);
+        for _ in 0..1000 {
+            let boundaries: Vec<_> =
+                (0..=text.len()).filter(|&i| text.is_char_boundary(i)).collect();
+            let mut start = boundaries[rng.below(boundaries.len())];
+            let mut end = boundaries[rng.below(boundaries.len())];
+            if start > end {
+                std::mem::swap(&mut start, &mut end);
+            }
+            // Keep the text from growing without bound.
+            let inserted = if text.len() > 200 { "" } else { pieces[rng.below(pieces.len())] };
+
+            index.splice(start, &text[start..end], inserted);
+            text.replace_range(start..end, inserted);
+            let expected = LineIndex::new(&text);
+            assert_eq!(
+                index.iter().collect::<Vec<_>>(),
+                expected.iter().collect::<Vec<_>>(),
+                "after replacing {}..{} with {:?}",
+                start,
+                end,
+                inserted
+            );
+            for i in 0..=index.len() {
+                assert_eq!(index.get(i), expected.get(i));
+            }
+            for offset in 0..=text.len() {
+                let line = text.as_bytes()[..offset].iter().filter(|&&b| b == b'\n').count();
+                assert_eq!(index.line_of(offset), line.min(index.len() - 2));
+            }
+        }
+    }
+}
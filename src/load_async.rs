@@ -0,0 +1,110 @@
+//! `Vfs::load_file_async`, which loads files on a small pool of threads shared
+//! by all VFSs, so that polling the returned future never blocks on IO.
+
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+use super::{Error, FileContents, FileLoader, VfsInternal};
+
+// The number of threads which load files for `Vfs::load_file_async`.
+const LOAD_THREADS: usize = 4;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+// Run `job` on one of the load threads, starting them the first time.
+fn spawn(job: Job) {
+    static JOBS: OnceLock<Sender<Job>> = OnceLock::new();
+    let jobs = JOBS.get_or_init(|| {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..LOAD_THREADS {
+            let receiver = receiver.clone();
+            thread::spawn(move || loop {
+                // Don't hold the lock while running the job.
+                let job = match receiver.lock().unwrap().recv() {
+                    Ok(job) => job,
+                    Err(_) => return,
+                };
+                // A job which panics drops its sender, which its future
+                // reports as an error, and must not take the thread with it.
+                let _ = panic::catch_unwind(AssertUnwindSafe(job));
+            });
+        }
+        sender
+    });
+    jobs.send(job).expect("load threads exited");
+}
+
+impl<T: FileLoader, U> VfsInternal<T, U> {
+    pub(crate) fn load_file_async(self: &Arc<Self>, path: &Path) -> LoadFileFuture<T, U> {
+        LoadFileFuture {
+            vfs: self.clone(),
+            path: path.to_owned(),
+            receiver: None,
+            waker: Arc::new(Mutex::new(None)),
+        }
+    }
+}
+
+pub(crate) struct LoadFileFuture<T, U> {
+    vfs: Arc<VfsInternal<T, U>>,
+    path: PathBuf,
+    // Set once the file is being loaded on a load thread.
+    receiver: Option<Receiver<Result<FileContents, Error>>>,
+    waker: Arc<Mutex<Option<Waker>>>,
+}
+
+impl<T, U> Future for LoadFileFuture<T, U>
+where
+    T: FileLoader + Send + Sync + 'static,
+    U: Send + 'static,
+{
+    type Output = Result<FileContents, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        *this.waker.lock().unwrap() = Some(cx.waker().clone());
+
+        let receiver = match this.receiver {
+            Some(ref receiver) => receiver,
+            None => {
+                // A cached file does not need a load thread.
+                let cached = {
+                    let path = this.vfs.resolve(&this.path);
+                    this.vfs.files.lock().unwrap().get(&path).map(|f| f.contents())
+                };
+                if let Some(contents) = cached {
+                    return Poll::Ready(Ok(contents));
+                }
+
+                // `load_file` waits for other threads loading the same file,
+                // so this only parks a load thread, never the polling thread.
+                let (sender, receiver) = mpsc::channel();
+                let vfs = this.vfs.clone();
+                let path = this.path.clone();
+                let waker = this.waker.clone();
+                spawn(Box::new(move || {
+                    let _ = sender.send(vfs.load_file(&path));
+                    if let Some(waker) = waker.lock().unwrap().take() {
+                        waker.wake();
+                    }
+                }));
+                this.receiver.get_or_insert(receiver)
+            }
+        };
+
+        match receiver.try_recv() {
+            Ok(result) => Poll::Ready(result),
+            Err(TryRecvError::Empty) => Poll::Pending,
+            Err(TryRecvError::Disconnected) => {
+                Poll::Ready(Err(Error::InternalError("file load thread panicked")))
+            }
+        }
+    }
+}
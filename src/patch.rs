@@ -0,0 +1,164 @@
+//! Applying unified diffs, such as those printed by `diff -u` or
+//! `rustfmt --emit=diff`, to a text.
+
+use super::{trim_line_terminator, Error};
+
+// A hunk of a unified diff: the line at which it starts in the old text,
+// 1-based, and its lines, each starting with ' ', '-', or '+'.
+struct Hunk<'a> {
+    old_start: usize,
+    old_len: usize,
+    lines: Vec<&'a str>,
+}
+
+// Parse a hunk header, `@@ -old_start,old_len +new_start,new_len @@`, where
+// the lengths default to 1. Returns the old and new ranges.
+fn parse_header(line: &str) -> Option<((usize, usize), (usize, usize))> {
+    let mut parts = line.strip_prefix("@@ ")?.split(' ');
+    let range = |part: Option<&str>, sign: char| {
+        let part = part?.strip_prefix(sign)?;
+        let mut numbers = part.splitn(2, ',');
+        let start = numbers.next()?.parse().ok()?;
+        let len = match numbers.next() {
+            Some(len) => len.parse().ok()?,
+            None => 1,
+        };
+        Some((start, len))
+    };
+    let old = range(parts.next(), '-')?;
+    let new = range(parts.next(), '+')?;
+    Some((old, new))
+}
+
+fn parse(patch: &str) -> Result<Vec<Hunk<'_>>, Error> {
+    let mut hunks = vec![];
+    let mut lines = patch.lines().peekable();
+    // Skip file headers, e.g., `--- a/foo.rs` and `+++ b/foo.rs`.
+    while lines.peek().is_some_and(|l| !l.starts_with("@@")) {
+        lines.next();
+    }
+
+    while let Some(header) = lines.next() {
+        let ((old_start, old_len), (_, new_len)) =
+            parse_header(header).ok_or(Error::BadLocation)?;
+        let (mut old_seen, mut new_seen) = (0, 0);
+        let mut hunk_lines = vec![];
+        while old_seen < old_len || new_seen < new_len {
+            let line = lines.next().ok_or(Error::BadLocation)?;
+            match line.chars().next() {
+                Some(' ') => {
+                    old_seen += 1;
+                    new_seen += 1;
+                }
+                Some('-') => old_seen += 1,
+                Some('+') => new_seen += 1,
+                // `\ No newline at end of file`, about the previous line.
+                Some('\\') => {}
+                // Some tools drop the space from empty context lines.
+                None => {
+                    old_seen += 1;
+                    new_seen += 1;
+                }
+                Some(_) => return Err(Error::BadLocation),
+            }
+            hunk_lines.push(line);
+            if lines.peek().is_some_and(|l| l.starts_with('\\')) {
+                hunk_lines.push(lines.next().unwrap());
+            }
+        }
+        if old_seen != old_len || new_seen != new_len {
+            return Err(Error::BadLocation);
+        }
+        hunks.push(Hunk {
+            old_start,
+            old_len,
+            lines: hunk_lines,
+        });
+        // Skip anything between hunks, e.g., the headers of another file.
+        while lines.peek().is_some_and(|l| !l.starts_with("@@")) {
+            lines.next();
+        }
+    }
+    Ok(hunks)
+}
+
+/// Apply the unified diff `patch` to `text` and return the result. Returns
+/// `Error::BadLocation` if the patch is malformed, or if the lines it removes
+/// or keeps as context do not match `text`.
+pub fn apply(text: &str, patch: &str) -> Result<String, Error> {
+    let old: Vec<&str> = text.split_inclusive('\n').collect();
+    let mut result = String::with_capacity(text.len());
+    // The next line of `old` to copy.
+    let mut pos = 0;
+    for hunk in parse(patch)? {
+        // An empty hunk starts after its start line, rather than at it.
+        let start = if hunk.old_len == 0 {
+            hunk.old_start
+        } else {
+            hunk.old_start.checked_sub(1).ok_or(Error::BadLocation)?
+        };
+        if start < pos || start > old.len() {
+            return Err(Error::BadLocation);
+        }
+        result.extend(old[pos..start].iter().copied());
+        pos = start;
+
+        let mut last_kind = "";
+        for line in hunk.lines {
+            let (kind, content) = line.split_at(line.len().min(1));
+            match kind {
+                " " | "-" | "" => {
+                    let old_line = old.get(pos).ok_or(Error::BadLocation)?;
+                    if trim_line_terminator(old_line) != content {
+                        return Err(Error::BadLocation);
+                    }
+                    if kind != "-" {
+                        result.push_str(old_line);
+                    }
+                    pos += 1;
+                }
+                "+" => {
+                    result.push_str(content);
+                    result.push('\n');
+                }
+                // The previous line has no line terminator. Old lines are
+                // copied as they are, so only added lines need fixing.
+                _ => {
+                    if last_kind == "+" {
+                        result.pop();
+                    }
+                }
+            }
+            last_kind = kind;
+        }
+    }
+    result.extend(old[pos..].iter().copied());
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::apply;
+    use Error;
+
+    #[test]
+    fn apply_hunks() {
+        let text = "a\nb\nc\nd\ne\nf\n";
+        let patch = "--- a/foo\n+++ b/foo\n@@ -1,2 +1,2 @@\n-a\n+A\n b\n@@ -5,0 +6,1 @@\n+x\n";
+        assert_eq!(apply(text, patch).unwrap(), "A\nb\nc\nd\ne\nx\nf\n");
+
+        // Removing and adding a final newline.
+        let patch = "@@ -6 +6 @@\n-f\n+g\n\\ No newline at end of file\n";
+        assert_eq!(apply(text, patch).unwrap(), "a\nb\nc\nd\ne\ng");
+        let patch = "@@ -1 +1 @@\n-x\n\\ No newline at end of file\n+x\n";
+        assert_eq!(apply("x", patch).unwrap(), "x\n");
+
+        // Hunks out of order or past the end of the text.
+        let patch = "@@ -3 +3 @@\n-c\n+C\n@@ -1 +1 @@\n-a\n+A\n";
+        assert_eq!(apply(text, patch), Err(Error::BadLocation));
+        let patch = "@@ -8 +8 @@\n-h\n+H\n";
+        assert_eq!(apply(text, patch), Err(Error::BadLocation));
+        // A hunk with fewer lines than its header says.
+        assert_eq!(apply(text, "@@ -1,2 +1,2 @@\n-a\n+A\n"), Err(Error::BadLocation));
+    }
+}
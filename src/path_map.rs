@@ -0,0 +1,168 @@
+//! A map keyed by paths, which compares paths without regard to case on
+//! platforms whose file systems are case-insensitive.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::ops::Index;
+use std::path::{Path, PathBuf};
+
+/// A map from paths to `V`. On Windows, paths which differ only in case, such
+/// as `C:\Foo\bar.rs` and `c:\foo\BAR.rs`, refer to the same entry; elsewhere
+/// it behaves like a `HashMap<PathBuf, V>`. An entry keeps the path it was
+/// first inserted with.
+#[derive(Debug)]
+pub struct PathMap<V> {
+    // Maps the normalized path to the path the entry was inserted with and
+    // its value.
+    map: HashMap<PathBuf, (PathBuf, V)>,
+    case_insensitive: bool,
+}
+
+impl<V> Default for PathMap<V> {
+    fn default() -> PathMap<V> {
+        PathMap::new()
+    }
+}
+
+impl<V> PathMap<V> {
+    pub fn new() -> PathMap<V> {
+        PathMap::with_case_insensitive(cfg!(windows))
+    }
+
+    pub fn with_case_insensitive(case_insensitive: bool) -> PathMap<V> {
+        PathMap {
+            map: HashMap::new(),
+            case_insensitive,
+        }
+    }
+
+    fn key<'a>(&self, path: &'a Path) -> Cow<'a, Path> {
+        if self.case_insensitive {
+            // Paths which are not valid Unicode are compared as they are.
+            if let Some(s) = path.to_str() {
+                return Cow::Owned(PathBuf::from(s.to_lowercase()));
+            }
+        }
+        Cow::Borrowed(path)
+    }
+
+    /// Whether `a` and `b` refer to the same entry.
+    pub fn same_path(&self, a: &Path, b: &Path) -> bool {
+        self.key(a) == self.key(b)
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.map.clear();
+    }
+
+    pub fn contains_key(&self, path: &Path) -> bool {
+        self.map.contains_key(&*self.key(path))
+    }
+
+    pub fn get(&self, path: &Path) -> Option<&V> {
+        self.map.get(&*self.key(path)).map(|(_, v)| v)
+    }
+
+    pub fn get_mut(&mut self, path: &Path) -> Option<&mut V> {
+        let key = self.key(path);
+        self.map.get_mut(&*key).map(|(_, v)| v)
+    }
+
+    /// Insert a value, returning the value it replaces, if any. As for
+    /// `HashMap`, an existing entry keeps its path.
+    pub fn insert(&mut self, path: PathBuf, value: V) -> Option<V> {
+        let key = self.key(&path).into_owned();
+        match self.map.get_mut(&key) {
+            Some(entry) => Some(std::mem::replace(&mut entry.1, value)),
+            None => {
+                self.map.insert(key, (path, value));
+                None
+            }
+        }
+    }
+
+    /// Insert a value unless there already is an entry for `path`.
+    pub fn insert_if_absent(&mut self, path: PathBuf, value: V) {
+        let key = self.key(&path).into_owned();
+        self.map.entry(key).or_insert((path, value));
+    }
+
+    pub fn remove(&mut self, path: &Path) -> Option<V> {
+        let key = self.key(path);
+        self.map.remove(&*key).map(|(_, v)| v)
+    }
+
+    /// Remove an entry, returning the path it was inserted with and its value.
+    pub fn remove_entry(&mut self, path: &Path) -> Option<(PathBuf, V)> {
+        let key = self.key(path);
+        self.map.remove(&*key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&PathBuf, &V)> + '_ {
+        self.map.values().map(|(p, v)| (p, v))
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&PathBuf, &mut V)> + '_ {
+        self.map.values_mut().map(|(p, v)| (&*p, v))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &PathBuf> + '_ {
+        self.map.values().map(|(p, _)| p)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> + '_ {
+        self.map.values().map(|(_, v)| v)
+    }
+
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut V> + '_ {
+        self.map.values_mut().map(|(_, v)| v)
+    }
+}
+
+impl<V> Index<&Path> for PathMap<V> {
+    type Output = V;
+
+    fn index(&self, path: &Path) -> &V {
+        self.get(path).expect("no entry for path")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PathMap;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn case_insensitive() {
+        let mut map = PathMap::with_case_insensitive(true);
+        assert_eq!(map.insert(PathBuf::from(r"C:\Foo\bar.rs"), 1), None);
+        assert_eq!(map.insert(PathBuf::from(r"c:\foo\BAR.rs"), 2), Some(1));
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.get(Path::new(r"C:\FOO\BAR.RS")), Some(&2));
+        assert!(map.same_path(Path::new(r"C:\Foo\bar.rs"), Path::new(r"c:\FOO\BAR.rs")));
+        // The entry keeps the path it was first inserted with.
+        assert_eq!(map.keys().collect::<Vec<_>>(), [Path::new(r"C:\Foo\bar.rs")]);
+        assert_eq!(map.remove(Path::new(r"c:\foo\bar.rs")), Some(2));
+        assert!(!map.contains_key(Path::new(r"C:\Foo\bar.rs")));
+        map.insert(PathBuf::from(r"C:\Foo\bar.rs"), 3);
+        assert_eq!(
+            map.remove_entry(Path::new(r"c:\FOO\bar.rs")),
+            Some((PathBuf::from(r"C:\Foo\bar.rs"), 3))
+        );
+    }
+
+    #[test]
+    fn case_sensitive() {
+        let mut map = PathMap::with_case_insensitive(false);
+        map.insert(PathBuf::from("/foo/bar.rs"), 1);
+        map.insert(PathBuf::from("/foo/BAR.rs"), 2);
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.get(Path::new("/foo/bar.rs")), Some(&1));
+        assert_eq!(map.get(Path::new("/FOO/bar.rs")), None);
+        assert!(!map.same_path(Path::new("/foo/bar.rs"), Path::new("/FOO/bar.rs")));
+    }
+}
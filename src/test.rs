@@ -1,10 +1,21 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::io;
 use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
 use span::{self, Column, Position, Row};
 
+use super::testing::{LoaderCall, RecordingFileLoader};
 use super::{
-    make_line_indices, Change, Error, File, FileContents, FileKind, FileLoader, TextFile,
-    VfsInternal, VfsSpan
+    affected_lines, sha256, Change, Error, File, FileContents, FileKind, FileState, FileLoader,
+    FileSource, TextFile, CHANGE_HISTORY_LEN, CHANGE_SUMMARY_TEXT_LEN, LineEnding, LineTerminator,
+    FsRead, PositionEncoding, ReadOptions, RealFileLoader, TextEdit, TrailingEmptyLinePolicy,
+    Utf8ErrorMode, Vfs, VfsFs, VfsInternal, VfsSpan, WriteOptions, VERSION_HASH_LEN
 };
 
 type Span = span::Span<span::ZeroIndexed>;
@@ -12,29 +23,42 @@ type Span = span::Span<span::ZeroIndexed>;
 struct MockFileLoader;
 
 impl FileLoader for MockFileLoader {
-    fn read<U>(file_name: &Path) -> Result<File<U>, Error> {
+    fn read<U>(file_name: &Path, options: &ReadOptions, _: bool) -> Result<File<U>, Error> {
         let text = format!("{}\nHello\nWorld\nHello, World!\n", file_name.display());
-        let text_file = TextFile {
-            line_indices: make_line_indices(&text),
-            text: text,
-            changed: false,
-        };
-        Ok(File {
-            kind: FileKind::Text(text_file),
-            user_data: None,
-        })
+        if let Some(limit) = options.max_file_size {
+            if text.len() > limit {
+                return Err(Error::FileTooLarge {
+                    path: file_name.to_owned(),
+                    size: text.len(),
+                    limit,
+                });
+            }
+        }
+        Ok(File::new(FileKind::Text(TextFile::new(text, false))))
     }
 
-    fn write(file_name: &Path, file: &FileKind) -> Result<(), Error> {
+    fn write(file_name: &Path, file: &FileKind, _: &WriteOptions, _: bool) -> Result<(), Error> {
         if let FileKind::Text(ref text_file) = *file  {
             if file_name.display().to_string() == "foo" {
                 // TODO: is this test useful still?
                 assert_eq!(text_file.changed, false);
-                assert_eq!(text_file.text, "foo\nHfooo\nWorld\nHello, World!\n");
+                assert_eq!(*text_file.text, "foo\nHfooo\nWorld\nHello, World!\n");
             }
         }
         Ok(())
     }
+
+    fn remove(_: &Path) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+// Returns a fresh, empty directory for tests which need a real file system.
+fn make_temp_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("rls-vfs-test-{}-{}", process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
 }
 
 fn make_change(with_len: bool) -> Change {
@@ -370,3 +394,2930 @@ fn test_wide_utf16() {
         FileContents::Text("".to_owned()),
     );
 }
+
+#[test]
+fn test_recompute_all_user_data() {
+    let vfs = VfsInternal::<MockFileLoader, usize>::new();
+    vfs.load_file(Path::new("foo")).unwrap();
+    vfs.load_file(Path::new("bar")).unwrap();
+    vfs.set_user_data(Path::new("foo"), Some(0)).unwrap();
+
+    vfs.recompute_all_user_data(|_, text| Ok(Some(text.len())))
+        .unwrap();
+    for name in &["foo", "bar"] {
+        vfs.with_user_data(Path::new(name), |u| {
+            let (text, data) = u.unwrap();
+            assert_eq!(*data, text.unwrap().len());
+            Ok(())
+        }).unwrap();
+    }
+
+    // An error from the factory is returned to the caller.
+    assert_eq!(
+        vfs.recompute_all_user_data(|_, _| Err(Error::BadLocation)),
+        Err(Error::BadLocation)
+    );
+}
+
+#[test]
+fn test_max_line_width() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    // The longest line is "Hello, World!".
+    assert_eq!(vfs.max_line_width(Path::new("foo")).unwrap(), 13);
+
+    let change = Change::AddFile {
+        file: PathBuf::from("foo"),
+        text: "a\r\n😢😢\r\n".to_owned(),
+    };
+    vfs.on_changes(&[change]).unwrap();
+    assert_eq!(vfs.max_line_width(Path::new("foo")).unwrap(), 2);
+}
+
+#[test]
+fn test_open_paged() {
+    let dir = make_temp_dir("open_paged");
+    let path = dir.join("big.log");
+    let text: String = (0..1000).map(|i| format!("line {}\n", i)).collect();
+    fs::write(&path, &text).unwrap();
+
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    vfs.open_paged(&path).unwrap();
+    assert_eq!(
+        vfs.load_line(&path, Row::new_zero_indexed(500)).unwrap(),
+        "line 500\n"
+    );
+    assert_eq!(
+        vfs.load_lines(&path, Row::new_zero_indexed(1), Row::new_zero_indexed(3)).unwrap(),
+        "line 1\nline 2\n"
+    );
+    // Only the requested lines were read, and nothing was cached.
+    assert_eq!(vfs.stats().paged_bytes_read, 23);
+    assert!(vfs.get_cached_files().is_empty());
+
+    // An edit falls back to loading the file in full (here, from the mock loader).
+    let change = Change::AddFile {
+        file: path.clone(),
+        text: "edited\n".to_owned(),
+    };
+    vfs.on_changes(&[change]).unwrap();
+    assert_eq!(vfs.load_line(&path, Row::new_zero_indexed(0)).unwrap(), "edited\n");
+    assert_eq!(vfs.stats().paged_bytes_read, 23);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_line_endings() {
+    let vfs = VfsInternal::<MockFileLoader, i32>::new();
+    assert_eq!(vfs.line_ending(Path::new("foo")).unwrap(), LineEnding::Lf);
+
+    let change = Change::AddFile {
+        file: PathBuf::from("foo"),
+        text: "a\r\nb\nc\r\n".to_owned(),
+    };
+    vfs.on_changes(&[change]).unwrap();
+    vfs.file_saved(Path::new("foo")).unwrap();
+    vfs.set_user_data(Path::new("foo"), Some(42)).unwrap();
+    assert_eq!(vfs.line_ending(Path::new("foo")).unwrap(), LineEnding::Mixed);
+
+    vfs.normalize_line_endings(Path::new("foo"), LineEnding::Lf).unwrap();
+    assert_eq!(vfs.line_ending(Path::new("foo")).unwrap(), LineEnding::Lf);
+    assert_eq!(
+        vfs.load_line(Path::new("foo"), Row::new_zero_indexed(1)).unwrap(),
+        "b\n"
+    );
+    assert!(!vfs.file_is_synced(Path::new("foo")).unwrap());
+    vfs.with_user_data(Path::new("foo"), |u| {
+        assert_eq!(u, Err(Error::NoUserDataForFile));
+        Ok(())
+    }).unwrap();
+
+    vfs.normalize_line_endings(Path::new("foo"), LineEnding::CrLf).unwrap();
+    assert_eq!(vfs.line_ending(Path::new("foo")).unwrap(), LineEnding::CrLf);
+    assert_eq!(
+        vfs.normalize_line_endings(Path::new("bar"), LineEnding::Lf),
+        Err(Error::FileNotCached)
+    );
+}
+
+#[test]
+fn test_load_file_bytes() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    let bytes = vfs.load_file_bytes(Path::new("foo")).unwrap();
+    match vfs.load_file(Path::new("foo")).unwrap() {
+        FileContents::Text(text) => assert_eq!(bytes, text.into_bytes()),
+        FileContents::Binary(_) => panic!("expected a text file"),
+    }
+}
+
+#[test]
+fn test_write_creates_dirs() {
+    let dir = make_temp_dir("write_creates_dirs");
+    let path = dir.join("a").join("b").join("new.rs");
+    let vfs = VfsInternal::<RealFileLoader, ()>::new();
+    vfs.set_file(&path, "fn main() {}\n").unwrap();
+
+    match vfs.write_file(&path) {
+        Err(Error::Io(Some(p), Some(msg))) => {
+            assert_eq!(p, path);
+            assert!(msg.contains(&path.parent().unwrap().display().to_string()));
+        }
+        r => panic!("unexpected result: {:?}", r),
+    }
+    assert!(!path.exists());
+
+    vfs.set_write_options(WriteOptions {
+        create_dirs: true,
+        ..WriteOptions::default()
+    });
+    vfs.write_file(&path).unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), "fn main() {}\n");
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_with_mut_text() {
+    let vfs = VfsInternal::<MockFileLoader, i32>::new();
+    vfs.load_file(Path::new("foo")).unwrap();
+    vfs.set_user_data(Path::new("foo"), Some(42)).unwrap();
+    assert_eq!(vfs.file_version(Path::new("foo")).unwrap(), 0);
+
+    vfs.with_mut_text(Path::new("foo"), |text| {
+        *text = text.to_uppercase().replace("WORLD\n", "WO\nRLD\n");
+    }).unwrap();
+    assert_eq!(
+        vfs.load_line(Path::new("foo"), Row::new_zero_indexed(3)).unwrap(),
+        "RLD\n"
+    );
+    assert_eq!(
+        vfs.load_line(Path::new("foo"), Row::new_zero_indexed(4)).unwrap(),
+        "HELLO, WORLD!\n"
+    );
+    assert!(vfs.has_changes());
+    assert_eq!(vfs.file_version(Path::new("foo")).unwrap(), 1);
+    vfs.with_user_data(Path::new("foo"), |u| {
+        assert_eq!(u, Err(Error::NoUserDataForFile));
+        Ok(())
+    }).unwrap();
+
+    assert_eq!(
+        vfs.with_mut_text(Path::new("bar"), |_| panic!()),
+        Err(Error::FileNotCached)
+    );
+}
+
+#[test]
+fn test_diff_against_disk() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    assert_eq!(vfs.diff_against_disk(Path::new("foo")), Err(Error::FileNotCached));
+    vfs.load_file(Path::new("foo")).unwrap();
+    assert_eq!(vfs.diff_against_disk(Path::new("foo")).unwrap(), None);
+
+    vfs.on_changes(&[make_change(false)]).unwrap();
+    vfs.with_mut_text(Path::new("foo"), |text| text.push_str("new line\n"))
+        .unwrap();
+    assert_eq!(
+        vfs.diff_against_disk(Path::new("foo")).unwrap(),
+        Some(vec![
+            TextEdit {
+                line_start: Row::new_zero_indexed(1),
+                line_end: Row::new_zero_indexed(2),
+                text: "Hfooo\n".to_owned(),
+            },
+            TextEdit {
+                line_start: Row::new_zero_indexed(4),
+                line_end: Row::new_zero_indexed(4),
+                text: "new line\n".to_owned(),
+            },
+        ])
+    );
+}
+
+#[test]
+fn test_with_user_data_or_default() {
+    let vfs = VfsInternal::<MockFileLoader, Vec<u32>>::new();
+    assert_eq!(
+        vfs.with_user_data_or_default(Path::new("foo"), |_, _| Ok(())),
+        Err(Error::FileNotCached)
+    );
+
+    vfs.load_file(Path::new("foo")).unwrap();
+    vfs.with_user_data_or_default(Path::new("foo"), |text, u| {
+        assert!(text.unwrap().starts_with("foo\n"));
+        assert!(u.is_empty());
+        u.push(1);
+        Ok(())
+    }).unwrap();
+    vfs.with_user_data(Path::new("foo"), |u| {
+        assert_eq!(*u.unwrap().1, vec![1]);
+        Ok(())
+    }).unwrap();
+    vfs.with_user_data_or_default(Path::new("foo"), |_, u| {
+        assert_eq!(*u, vec![1]);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn test_flush_under() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    for path in &["/a/b/c.rs", "/a/b/d/e.rs", "/a/bc/f.rs", "/a/g.rs"] {
+        vfs.load_file(Path::new(path)).unwrap();
+    }
+
+    let mut paths = vfs.paths_under(Path::new("/a/b"));
+    paths.sort();
+    assert_eq!(paths, vec![PathBuf::from("/a/b/c.rs"), PathBuf::from("/a/b/d/e.rs")]);
+
+    assert_eq!(vfs.flush_under(Path::new("/a/b")).unwrap(), 2);
+    let files = vfs.get_cached_files();
+    assert_eq!(files.len(), 2);
+    assert!(files.contains_key(Path::new("/a/bc/f.rs")));
+    assert!(files.contains_key(Path::new("/a/g.rs")));
+    assert_eq!(vfs.flush_under(Path::new("/a/b")).unwrap(), 0);
+}
+
+#[test]
+fn test_save_keeps_user_data() {
+    let vfs = VfsInternal::<MockFileLoader, i32>::new();
+    vfs.on_changes(&[make_change(false)]).unwrap();
+    vfs.set_user_data(Path::new("foo"), Some(42)).unwrap();
+    let version = vfs.file_version(Path::new("foo")).unwrap();
+
+    vfs.write_file(Path::new("foo")).unwrap();
+    vfs.file_saved(Path::new("foo")).unwrap();
+    assert!(!vfs.has_changes());
+    assert_eq!(vfs.file_version(Path::new("foo")).unwrap(), version);
+    vfs.with_user_data(Path::new("foo"), |u| {
+        assert_eq!(*u.unwrap().1, 42);
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn test_set_files() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    vfs.set_files(vec![
+        (PathBuf::from("a"), "1".to_owned()),
+        (PathBuf::from("b"), "2".to_owned()),
+        (PathBuf::from("c"), "3\n".to_owned()),
+    ]).unwrap();
+
+    let files = vfs.get_cached_files();
+    assert_eq!(files.len(), 3);
+    assert_eq!(files[Path::new("b")], "2");
+    assert_eq!(vfs.get_changes().len(), 3);
+    assert_eq!(
+        vfs.load_line(Path::new("c"), Row::new_zero_indexed(1)).unwrap(),
+        ""
+    );
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn test_load_file_async() {
+    use std::future::Future;
+    use std::task::{Context, Poll, Wake, Waker};
+    use std::thread::Thread;
+
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let vfs = Arc::new(VfsInternal::<MockFileLoader, ()>::new());
+    vfs.set_root(Some(PathBuf::from("/root")));
+    let mut future = Box::pin(vfs.load_file_async(Path::new("foo")));
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+
+    // The first poll starts the load on another thread and returns immediately.
+    assert!(future.as_mut().poll(&mut cx).is_pending());
+    let contents = loop {
+        if let Poll::Ready(contents) = future.as_mut().poll(&mut cx) {
+            break contents;
+        }
+        thread::park();
+    };
+    assert_eq!(
+        contents.unwrap(),
+        FileContents::Text("/root/foo\nHello\nWorld\nHello, World!\n".to_owned())
+    );
+
+    // The file is cached under its resolved path, so loading it again does
+    // not need another thread.
+    assert!(vfs.is_cached(Path::new("/root/foo")));
+    assert_eq!(vfs.file_count(), 1);
+    let mut future = Box::pin(vfs.load_file_async(Path::new("foo")));
+    assert!(future.as_mut().poll(&mut cx).is_ready());
+}
+
+#[test]
+fn test_file_count_and_total_bytes() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    assert_eq!(vfs.file_count(), 0);
+    assert_eq!(vfs.total_bytes(), 0);
+
+    vfs.load_file(Path::new("foo")).unwrap();
+    vfs.load_file(Path::new("quux")).unwrap();
+    assert_eq!(vfs.file_count(), 2);
+    let expected: usize = vfs.get_cached_files().values().map(|text| text.len()).sum();
+    assert_eq!(vfs.total_bytes(), expected);
+}
+
+#[test]
+fn test_add_identical_file_keeps_user_data() {
+    let vfs = VfsInternal::<MockFileLoader, i32>::new();
+    let add_file = |text: &str| Change::AddFile {
+        file: PathBuf::from("foo"),
+        text: text.to_owned(),
+    };
+    vfs.on_changes(&[add_file("Hello")]).unwrap();
+    vfs.set_user_data(Path::new("foo"), Some(42)).unwrap();
+    let version = vfs.file_version(Path::new("foo")).unwrap();
+
+    vfs.on_changes(&[add_file("Hello")]).unwrap();
+    assert_eq!(vfs.file_version(Path::new("foo")).unwrap(), version);
+    vfs.with_user_data(Path::new("foo"), |u| {
+        assert_eq!(*u.unwrap().1, 42);
+        Ok(())
+    }).unwrap();
+
+    vfs.on_changes(&[add_file("Hello, World!")]).unwrap();
+    assert_eq!(vfs.file_version(Path::new("foo")).unwrap(), version + 1);
+    vfs.with_user_data(Path::new("foo"), |u| {
+        assert_eq!(u, Err(Error::NoUserDataForFile));
+        Ok(())
+    }).unwrap();
+}
+
+#[test]
+fn test_line_char_count() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    let change = Change::AddFile {
+        file: PathBuf::from("foo"),
+        text: "abc\r\n😢 e\u{301}\n".to_owned(),
+    };
+    vfs.on_changes(&[change]).unwrap();
+
+    let count = |line, encoding| {
+        vfs.line_char_count(Path::new("foo"), Row::new_zero_indexed(line), encoding)
+            .unwrap()
+    };
+    assert_eq!(count(0, PositionEncoding::UnicodeScalarValue), 3);
+    assert_eq!(count(0, PositionEncoding::Utf16CodeUnit), 3);
+    assert_eq!(count(1, PositionEncoding::UnicodeScalarValue), 4);
+    assert_eq!(count(1, PositionEncoding::Utf16CodeUnit), 5);
+    assert_eq!(count(1, PositionEncoding::Grapheme), 3);
+    assert_eq!(count(2, PositionEncoding::Grapheme), 0);
+}
+
+#[test]
+fn test_validate_changes() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    vfs.set_validate_changes(true);
+    let add_bar = Change::AddFile {
+        file: PathBuf::from("bar"),
+        text: "bar\nbar\n".to_owned(),
+    };
+
+    // "foo" is neither cached nor added by the batch.
+    assert_eq!(
+        vfs.on_changes(&[add_bar, make_change(false)]),
+        Err(Error::UnknownFile(PathBuf::from("foo")))
+    );
+    assert!(vfs.get_cached_files().is_empty());
+
+    let add_foo = Change::AddFile {
+        file: PathBuf::from("foo"),
+        text: "foo\nHello\n".to_owned(),
+    };
+    vfs.on_changes(&[add_foo, make_change(false)]).unwrap();
+    vfs.on_changes(&[make_change(false)]).unwrap();
+}
+
+struct PreludeSource;
+
+impl FileSource for PreludeSource {
+    fn read(&self, path: &Path) -> Result<Option<FileContents>, Error> {
+        if path.ends_with("missing.rs") {
+            return Ok(None);
+        }
+        Ok(Some(FileContents::Text(format!("// generated {}\n", path.display()))))
+    }
+}
+
+#[test]
+fn test_register_loader() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    vfs.register_loader(Path::new("/gen"), Box::new(PreludeSource));
+
+    assert_eq!(
+        vfs.load_file(Path::new("/gen/prelude.rs")).unwrap(),
+        FileContents::Text("// generated /gen/prelude.rs\n".to_owned())
+    );
+    // Paths the source doesn't provide, and paths outside the prefix, are
+    // read as usual.
+    assert_eq!(
+        vfs.load_line(Path::new("/gen/missing.rs"), Row::new_zero_indexed(0)).unwrap(),
+        "/gen/missing.rs\n"
+    );
+    assert_eq!(
+        vfs.load_line(Path::new("/generated.rs"), Row::new_zero_indexed(0)).unwrap(),
+        "/generated.rs\n"
+    );
+}
+
+#[test]
+fn test_expected_checksums() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    let text = "foo\nHello\nWorld\nHello, World!\n";
+    let mut checksums = HashMap::new();
+    checksums.insert(PathBuf::from("foo"), sha256::digest(text.as_bytes()));
+    checksums.insert(PathBuf::from("bar"), [0; 32]);
+    vfs.set_expected_checksums(checksums);
+
+    assert_eq!(
+        vfs.load_file(Path::new("foo")).unwrap(),
+        FileContents::Text(text.to_owned())
+    );
+    assert_eq!(
+        vfs.load_file(Path::new("bar")),
+        Err(Error::ChecksumMismatch {
+            path: PathBuf::from("bar"),
+        })
+    );
+    assert!(!vfs.get_cached_files().contains_key(Path::new("bar")));
+    // Files without a checksum are not checked.
+    vfs.load_file(Path::new("baz")).unwrap();
+}
+
+#[test]
+fn test_on_changes_with_mapping() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    let text = "a\nb\nc\nd\ne\nf\n".to_owned();
+    vfs.on_changes(&[Change::AddFile {
+        file: PathBuf::from("foo"),
+        text,
+    }])
+    .unwrap();
+
+    // Insert two lines in the middle of line 1.
+    let change = Change::ReplaceText {
+        span: VfsSpan::from_usv(
+            Span::new(
+                Row::new_zero_indexed(1),
+                Row::new_zero_indexed(1),
+                Column::new_zero_indexed(1),
+                Column::new_zero_indexed(1),
+                "foo",
+            ),
+            None,
+        ),
+        text: "x\ny\nz".to_owned(),
+    };
+    let map = vfs.on_changes_with_mapping(&[change]).unwrap();
+    assert_eq!(vfs.load_line(Path::new("foo"), Row::new_zero_indexed(7)).unwrap(), "f\n");
+
+    let pos = |row, col| Position::new(Row::new_zero_indexed(row), Column::new_zero_indexed(col));
+    assert_eq!(map.map(Path::new("foo"), pos(5, 0)), pos(7, 0));
+    assert_eq!(map.map(Path::new("foo"), pos(1, 0)), pos(1, 0));
+    assert_eq!(map.map(Path::new("foo"), pos(1, 1)), pos(3, 1));
+    assert_eq!(map.map(Path::new("bar"), pos(5, 0)), pos(5, 0));
+}
+
+// Serves files with the given names, prefixed with the source's name.
+struct NamedSource(&'static str, &'static [&'static str]);
+
+impl FileSource for NamedSource {
+    fn read(&self, path: &Path) -> Result<Option<FileContents>, Error> {
+        if path.ends_with("broken.rs") {
+            return Err(Error::Io(Some(path.to_owned()), None));
+        }
+        if !self.1.iter().any(|name| path.ends_with(name)) {
+            return Ok(None);
+        }
+        Ok(Some(FileContents::Text(format!("{} {}\n", self.0, path.display()))))
+    }
+}
+
+#[test]
+fn test_loader_chain() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::with_loader_chain(vec![
+        Box::new(NamedSource("overlay", &["lib.rs"])),
+        Box::new(NamedSource("base", &["lib.rs", "main.rs"])),
+    ]);
+
+    assert_eq!(
+        vfs.load_file(Path::new("/src/lib.rs")).unwrap(),
+        FileContents::Text("overlay /src/lib.rs\n".to_owned())
+    );
+    assert_eq!(
+        vfs.load_file(Path::new("/src/main.rs")).unwrap(),
+        FileContents::Text("base /src/main.rs\n".to_owned())
+    );
+    // Errors short-circuit the chain.
+    assert_eq!(
+        vfs.load_file(Path::new("/src/broken.rs")),
+        Err(Error::Io(Some(PathBuf::from("/src/broken.rs")), None))
+    );
+    // Files no loader provides are read from disk.
+    assert_eq!(
+        vfs.load_line(Path::new("other.rs"), Row::new_zero_indexed(0)).unwrap(),
+        "other.rs\n"
+    );
+}
+
+#[test]
+fn test_move_range_to_file() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    vfs.enable_undo();
+    // Move "Hello\nWorld\n" out of foo.
+    let span = VfsSpan::from_usv(
+        Span::new(
+            Row::new_zero_indexed(1),
+            Row::new_zero_indexed(3),
+            Column::new_zero_indexed(0),
+            Column::new_zero_indexed(0),
+            "foo",
+        ),
+        None,
+    );
+    vfs.move_range_to_file(&span, Path::new("bar")).unwrap();
+
+    let files = vfs.get_changes();
+    assert_eq!(files[Path::new("foo")], "foo\nHello, World!\n");
+    assert_eq!(files[Path::new("bar")], "Hello\nWorld\n");
+    assert_eq!(vfs.file_version(Path::new("foo")).unwrap(), 1);
+    // The removal is recorded like any other change.
+    let history = vfs.change_history();
+    assert_eq!(history.len(), 1);
+    assert_eq!((&*history[0].0, history[0].1), (Path::new("foo"), 1));
+    assert_eq!(vfs.changed_since(0).1, [Path::new("bar"), Path::new("foo")]);
+
+    // Moving a span to its own file is refused.
+    assert_eq!(
+        vfs.move_range_to_file(&span, Path::new("foo")),
+        Err(Error::DuplicatePath(PathBuf::from("foo")))
+    );
+    assert_eq!(vfs.get_changes(), files);
+
+    // Moving a span outside the file changes neither file.
+    let span = VfsSpan::from_usv(
+        Span::new(
+            Row::new_zero_indexed(5),
+            Row::new_zero_indexed(6),
+            Column::new_zero_indexed(0),
+            Column::new_zero_indexed(0),
+            "foo",
+        ),
+        None,
+    );
+    assert_eq!(vfs.move_range_to_file(&span, Path::new("bar")), Err(Error::BadLocation));
+    assert_eq!(vfs.get_changes(), files);
+
+    // The removal can be undone.
+    vfs.undo(Path::new("foo")).unwrap();
+    assert_eq!(vfs.get_cached_files()[Path::new("foo")], "foo\nHello\nWorld\nHello, World!\n");
+}
+
+#[test]
+fn test_recent_changes() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    let insert = |text: &str| Change::ReplaceText {
+        span: VfsSpan::from_usv(
+            Span::new(
+                Row::new_zero_indexed(0),
+                Row::new_zero_indexed(0),
+                Column::new_zero_indexed(0),
+                Column::new_zero_indexed(0),
+                "foo",
+            ),
+            None,
+        ),
+        text: text.to_owned(),
+    };
+    vfs.on_changes(&[insert("a"), insert("b")]).unwrap();
+    vfs.on_changes(&[insert("c")]).unwrap();
+    assert_eq!(vfs.load_line(Path::new("foo"), Row::new_zero_indexed(0)).unwrap(), "cbafoo\n");
+
+    let texts: Vec<String> = vfs
+        .recent_changes(Path::new("foo"), 2)
+        .unwrap()
+        .into_iter()
+        .map(|c| match c {
+            Change::ReplaceText { text, .. } => text,
+            Change::AddFile { .. } => panic!("unexpected AddFile"),
+        })
+        .collect();
+    assert_eq!(texts, vec!["b", "c"]);
+    assert_eq!(vfs.recent_changes(Path::new("foo"), 10).unwrap().len(), 3);
+    assert_eq!(vfs.recent_changes(Path::new("bar"), 2).unwrap_err(), Error::FileNotCached);
+}
+
+#[test]
+fn test_check_invariants() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    vfs.on_changes(&[make_change(false)]).unwrap();
+    vfs.on_changes(&[make_change_2(true)]).unwrap();
+    vfs.on_changes(&[Change::ReplaceText {
+        span: VfsSpan::from_usv(
+            Span::new(
+                Row::new_zero_indexed(0),
+                Row::new_zero_indexed(2),
+                Column::new_zero_indexed(1),
+                Column::new_zero_indexed(0),
+                "foo",
+            ),
+            None,
+        ),
+        text: "\n\nx\r\n".to_owned(),
+    }])
+    .unwrap();
+    vfs.set_file(Path::new("bar"), "no newline").unwrap();
+    vfs.with_mut_text(Path::new("bar"), |text| text.push('\n')).unwrap();
+    vfs.check_invariants().unwrap();
+
+    let mut file = TextFile::new("a\nb".to_owned(), false);
+    Arc::make_mut(&mut file.text).push('\n');
+    assert!(file.check_invariants().is_err());
+}
+
+#[test]
+fn test_cached_files_arc() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    vfs.set_file(Path::new("foo"), "foo\n").unwrap();
+    vfs.set_file(Path::new("bar"), "bar\n").unwrap();
+
+    let snapshot = vfs.cached_files_arc();
+    let again = vfs.cached_files_arc();
+    assert!(Arc::ptr_eq(&snapshot[Path::new("foo")], &again[Path::new("foo")]));
+    assert_eq!(Arc::strong_count(&snapshot[Path::new("foo")]), 3);
+
+    // Edits don't affect existing snapshots.
+    vfs.with_mut_text(Path::new("foo"), |text| text.push_str("baz\n")).unwrap();
+    assert_eq!(*snapshot[Path::new("foo")], "foo\n");
+    assert_eq!(*snapshot[Path::new("bar")], "bar\n");
+    assert_eq!(*vfs.cached_files_arc()[Path::new("foo")], "foo\nbaz\n");
+}
+
+#[test]
+fn test_set_file_encoding() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    vfs.set_file(Path::new("foo"), "a\u{1F600}bc\n").unwrap();
+    vfs.set_file(Path::new("bar"), "a\u{1F600}bc\n").unwrap();
+    vfs.set_file(Path::new("baz"), "e\u{301}bc\n").unwrap();
+    vfs.set_file_encoding(Path::new("foo"), PositionEncoding::Utf16CodeUnit).unwrap();
+    vfs.set_file_encoding(Path::new("baz"), PositionEncoding::Grapheme).unwrap();
+    assert_eq!(
+        vfs.set_file_encoding(Path::new("qux"), PositionEncoding::Grapheme),
+        Err(Error::FileNotCached)
+    );
+
+    let replace = |file: &str, col: u32| Change::ReplaceText {
+        span: VfsSpan::from_usv(
+            Span::new(
+                Row::new_zero_indexed(0),
+                Row::new_zero_indexed(0),
+                Column::new_zero_indexed(col),
+                Column::new_zero_indexed(col + 1),
+                file,
+            ),
+            None,
+        ),
+        text: "X".to_owned(),
+    };
+    vfs.on_changes(&[replace("foo", 3), replace("bar", 3), replace("baz", 1)]).unwrap();
+
+    let files = vfs.get_cached_files();
+    assert_eq!(files[Path::new("foo")], "a\u{1F600}Xc\n");
+    assert_eq!(files[Path::new("bar")], "a\u{1F600}bX\n");
+    assert_eq!(files[Path::new("baz")], "e\u{301}Xc\n");
+}
+
+#[test]
+fn test_out_of_sync_files() {
+    let dir = make_temp_dir("out_of_sync_files");
+    let (foo, bar, baz) = (dir.join("foo.rs"), dir.join("bar.rs"), dir.join("baz.rs"));
+    for path in &[&foo, &bar, &baz] {
+        fs::write(path, "fn main() {}\n").unwrap();
+    }
+
+    let vfs = VfsInternal::<RealFileLoader, ()>::new();
+    for path in &[&foo, &bar, &baz] {
+        vfs.load_file(path).unwrap();
+    }
+    assert!(vfs.out_of_sync_files().is_empty());
+
+    fs::write(&foo, "fn main() { println!(); }\n").unwrap();
+    fs::write(&bar, "fn main() { println!(); }\n").unwrap();
+    // bar has in-memory edits, so it is expected to differ.
+    vfs.set_file(&bar, "fn main() {}\n").unwrap();
+    assert_eq!(vfs.out_of_sync_files(), vec![foo.clone()]);
+
+    fs::remove_file(&baz).unwrap();
+    let mut out_of_sync = vfs.out_of_sync_files();
+    out_of_sync.sort();
+    assert_eq!(out_of_sync, vec![baz, foo]);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_undo_redo() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    vfs.enable_undo();
+    let text = |vfs: &VfsInternal<MockFileLoader, ()>| {
+        vfs.get_cached_files()[Path::new("foo")].clone()
+    };
+
+    assert_eq!(vfs.undo(Path::new("foo")), Err(Error::FileNotCached));
+    vfs.on_changes(&[make_change(false)]).unwrap();
+    vfs.on_changes(&[make_change_2(false)]).unwrap();
+    let (before, after_one, after_two) = (
+        "foo\nHello\nWorld\nHello, World!\n",
+        "foo\nHfooo\nWorld\nHello, World!\n",
+        text(&vfs),
+    );
+    assert_ne!(after_two, after_one);
+
+    assert!(vfs.undo(Path::new("foo")).unwrap());
+    assert_eq!(text(&vfs), after_one);
+    assert!(vfs.undo(Path::new("foo")).unwrap());
+    assert_eq!(text(&vfs), before);
+    assert!(!vfs.undo(Path::new("foo")).unwrap());
+
+    assert!(vfs.redo(Path::new("foo")).unwrap());
+    assert_eq!(text(&vfs), after_one);
+
+    // Saving discards the changes which could be redone.
+    vfs.file_saved(Path::new("foo")).unwrap();
+    assert!(!vfs.redo(Path::new("foo")).unwrap());
+    assert!(vfs.undo(Path::new("foo")).unwrap());
+    assert_eq!(text(&vfs), before);
+}
+
+#[test]
+fn test_undo_after_text_replaced() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    let path = Path::new("foo");
+    let insert = Change::ReplaceText {
+        span: VfsSpan::from_usv(
+            Span::new(
+                Row::new_zero_indexed(0),
+                Row::new_zero_indexed(0),
+                Column::new_zero_indexed(0),
+                Column::new_zero_indexed(0),
+                "foo",
+            ),
+            None,
+        ),
+        text: "abc".to_owned(),
+    };
+
+    // Replacing the text in other ways forgets the history, which refers to
+    // the old text.
+    vfs.load_file(path).unwrap();
+    vfs.enable_file_undo(path).unwrap();
+    vfs.on_changes(std::slice::from_ref(&insert)).unwrap();
+    vfs.with_mut_text(path, |t| t.clear()).unwrap();
+    assert_eq!(vfs.undo(path), Ok(false));
+    assert_eq!(vfs.get_cached_files()[path], "");
+
+    vfs.on_changes(std::slice::from_ref(&insert)).unwrap();
+    vfs.with_mut_text(path, |t| t.push_str("\r\n")).unwrap();
+    vfs.normalize_line_endings(path, LineEnding::Lf).unwrap();
+    assert_eq!(vfs.undo(path), Ok(false));
+    assert_eq!(vfs.get_cached_files()[path], "abc\n");
+
+    vfs.on_changes(&[insert]).unwrap();
+    assert!(vfs.undo(path).unwrap());
+    assert_eq!(vfs.get_cached_files()[path], "abc\n");
+}
+
+#[test]
+fn test_line_ranges() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    vfs.set_file(Path::new("foo"), "a\r\n\nbc\u{1F600}\nd").unwrap();
+    let ranges = vfs.line_ranges(Path::new("foo")).unwrap();
+    assert_eq!(
+        ranges.iter().map(|(_, r)| r.clone()).collect::<Vec<_>>(),
+        vec![0..3, 3..4, 4..11, 11..12]
+    );
+
+    let ranges = vfs.line_ranges(Path::new("bar")).unwrap();
+    let text = &vfs.get_cached_files()[Path::new("bar")];
+    assert_eq!(ranges.len(), 5);
+    let mut end = 0;
+    for (i, (row, range)) in ranges.into_iter().enumerate() {
+        assert_eq!(row, Row::new_zero_indexed(i as u32));
+        assert_eq!(range.start, end);
+        end = range.end;
+    }
+    assert_eq!(end, text.len());
+}
+
+#[test]
+fn test_newline_offsets() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    let text = "foo\nHello\nWorld\nHello, World!\n";
+    assert_eq!(vfs.load_file(Path::new("foo")).unwrap(), FileContents::Text(text.to_owned()));
+    let expected: Vec<_> = text.match_indices('\n').map(|(i, _)| i + 1).collect();
+    assert_eq!(expected, [4, 10, 16, 30]);
+    assert_eq!(vfs.newline_offsets(Path::new("foo")).unwrap(), expected);
+
+    vfs.set_file(Path::new("bar"), "no newline").unwrap();
+    assert_eq!(vfs.newline_offsets(Path::new("bar")).unwrap(), []);
+}
+
+#[test]
+fn test_max_file_size() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    vfs.set_max_file_size(Some(20));
+    assert_eq!(
+        vfs.load_file(Path::new("foo")),
+        Err(Error::FileTooLarge {
+            path: PathBuf::from("foo"),
+            size: 30,
+            limit: 20,
+        })
+    );
+    vfs.set_max_file_size(None);
+    vfs.load_file(Path::new("foo")).unwrap();
+
+    let dir = make_temp_dir("max_file_size");
+    let path = dir.join("big.rs");
+    fs::write(&path, "0123456789").unwrap();
+    let vfs = VfsInternal::<RealFileLoader, ()>::new();
+    vfs.set_max_file_size(Some(9));
+    assert_eq!(
+        vfs.load_file(&path),
+        Err(Error::FileTooLarge {
+            path: path.clone(),
+            size: 10,
+            limit: 9,
+        })
+    );
+    vfs.set_max_file_size(Some(10));
+    vfs.load_file(&path).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_delete_if_empty() {
+    let dir = make_temp_dir("delete_if_empty");
+    let (foo, bar) = (dir.join("foo.rs"), dir.join("bar.rs"));
+    fs::write(&foo, "fn foo() {}\n").unwrap();
+    fs::write(&bar, "fn bar() {}\n").unwrap();
+
+    let vfs = VfsInternal::<RealFileLoader, ()>::new();
+    vfs.set_write_options(WriteOptions {
+        delete_if_empty: true,
+        ..WriteOptions::default()
+    });
+    vfs.load_file(&foo).unwrap();
+    vfs.load_file(&bar).unwrap();
+    vfs.with_mut_text(&foo, |text| *text = " \n\t\n".to_owned()).unwrap();
+    vfs.with_mut_text(&bar, |text| text.push_str("fn baz() {}\n")).unwrap();
+    vfs.write_file(&foo).unwrap();
+    vfs.write_file(&bar).unwrap();
+
+    assert!(!foo.exists());
+    assert!(!vfs.get_cached_files().contains_key(&foo));
+    assert_eq!(fs::read_to_string(&bar).unwrap(), "fn bar() {}\nfn baz() {}\n");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_load_from_line() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    assert_eq!(
+        vfs.load_from_line(Path::new("foo"), Row::new_zero_indexed(2)).unwrap(),
+        "World\nHello, World!\n"
+    );
+    assert_eq!(vfs.load_from_line(Path::new("foo"), Row::new_zero_indexed(4)).unwrap(), "");
+    assert_eq!(
+        vfs.load_from_line(Path::new("foo"), Row::new_zero_indexed(5)),
+        Err(Error::BadLocation)
+    );
+
+    let dir = make_temp_dir("load_from_line");
+    let path = dir.join("paged.log");
+    fs::write(&path, "a\nb\nc\n").unwrap();
+    vfs.open_paged(&path).unwrap();
+    assert_eq!(vfs.load_from_line(&path, Row::new_zero_indexed(1)).unwrap(), "b\nc\n");
+    assert_eq!(vfs.load_from_line(&path, Row::new_zero_indexed(4)), Err(Error::BadLocation));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+// Like `MockFileLoader`, but carries the encoding of a file over when it is
+// reloaded.
+struct ReloadingFileLoader;
+
+impl FileLoader for ReloadingFileLoader {
+    fn read<U>(file_name: &Path, options: &ReadOptions, gzip: bool) -> Result<File<U>, Error> {
+        MockFileLoader::read(file_name, options, gzip)
+    }
+
+    fn write(
+        file_name: &Path,
+        file: &FileKind,
+        options: &WriteOptions,
+        gzip: bool,
+    ) -> Result<(), Error> {
+        MockFileLoader::write(file_name, file, options, gzip)
+    }
+
+    fn remove(file_name: &Path) -> Result<(), Error> {
+        MockFileLoader::remove(file_name)
+    }
+
+    fn reload<U>(
+        file_name: &Path,
+        old: &File<U>,
+        options: &ReadOptions,
+        gzip: bool,
+    ) -> Result<File<U>, Error> {
+        let mut file = Self::read(file_name, options, gzip)?;
+        file.encoding = old.encoding;
+        Ok(file)
+    }
+}
+
+#[test]
+fn test_reload_file() {
+    let vfs = VfsInternal::<ReloadingFileLoader, ()>::new();
+    assert_eq!(vfs.reload_file(Path::new("foo")), Err(Error::FileNotCached));
+    vfs.load_file(Path::new("foo")).unwrap();
+    vfs.set_file_encoding(Path::new("foo"), PositionEncoding::Grapheme).unwrap();
+    vfs.set_user_data(Path::new("foo"), Some(())).unwrap();
+
+    vfs.reload_file(Path::new("foo")).unwrap();
+    {
+        let files = vfs.files.lock().unwrap();
+        let file = &files[Path::new("foo")];
+        assert_eq!(file.encoding, Some(PositionEncoding::Grapheme));
+        // The contents didn't change, so neither did the user data.
+        assert!(file.user_data.is_some());
+        assert_eq!(file.version, 0);
+    }
+
+    vfs.on_changes(&[make_change(false)]).unwrap();
+    assert_eq!(
+        vfs.reload_file(Path::new("foo")),
+        Err(Error::UncommittedChanges(PathBuf::from("foo")))
+    );
+}
+
+#[test]
+fn test_selections() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    let range = |row_start, col_start, row_end, col_end| {
+        span::Range::new(
+            Row::new_zero_indexed(row_start),
+            Row::new_zero_indexed(row_end),
+            Column::new_zero_indexed(col_start),
+            Column::new_zero_indexed(col_end),
+        )
+    };
+    assert_eq!(vfs.selections(Path::new("foo")), Err(Error::FileNotCached));
+    vfs.load_file(Path::new("foo")).unwrap();
+    let selections = vec![range(1, 4, 2, 2), range(3, 0, 3, 0), range(3, 5, 3, 5)];
+    vfs.set_selections(Path::new("foo"), selections.clone()).unwrap();
+    assert_eq!(vfs.selections(Path::new("foo")).unwrap(), selections);
+
+    // Replace "ell" on line 1 with "foo", and "d\nHe" with "aye carumba".
+    vfs.on_changes(&[make_change(false), make_change_2(false)]).unwrap();
+    assert_eq!(
+        vfs.load_line(Path::new("foo"), Row::new_zero_indexed(2)).unwrap(),
+        "Worlaye carumballo, World!\n"
+    );
+    // A selection in replaced text moves to the start of the replacement, and
+    // selections after an edit are shifted.
+    assert_eq!(
+        vfs.selections(Path::new("foo")).unwrap(),
+        vec![range(1, 4, 2, 2), range(2, 4, 2, 4), range(2, 18, 2, 18)]
+    );
+
+    vfs.with_mut_text(Path::new("foo"), |text| text.clear()).unwrap();
+    assert!(vfs.selections(Path::new("foo")).unwrap().is_empty());
+}
+
+#[test]
+fn test_word_at() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    let pos = |row, col| Position::new(Row::new_zero_indexed(row), Column::new_zero_indexed(col));
+
+    // Inside "World" in "Hello, World!".
+    let (word, span) = vfs.word_at(Path::new("foo"), pos(3, 9)).unwrap().unwrap();
+    assert_eq!(word, "World");
+    assert_eq!(
+        span,
+        Span::new(
+            Row::new_zero_indexed(3),
+            Row::new_zero_indexed(3),
+            Column::new_zero_indexed(7),
+            Column::new_zero_indexed(12),
+            "foo",
+        )
+    );
+    assert_eq!(vfs.word_at(Path::new("foo"), pos(3, 0)).unwrap().unwrap().0, "Hello");
+
+    // On the comma, and past the end of the line.
+    assert_eq!(vfs.word_at(Path::new("foo"), pos(3, 5)).unwrap(), None);
+    assert_eq!(vfs.word_at(Path::new("foo"), pos(3, 20)).unwrap(), None);
+}
+
+#[test]
+fn test_word_at_graphemes() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    let pos = |col| Position::new(Row::new_zero_indexed(0), Column::new_zero_indexed(col));
+    let path = Path::new("foo");
+    // "cafe\u{301}" is four grapheme clusters, the last of them "e\u{301}".
+    vfs.set_file(path, "let cafe\u{301} = 1;\n").unwrap();
+    vfs.set_file_encoding(path, PositionEncoding::Grapheme).unwrap();
+
+    let span = |start, end| {
+        let row = Row::new_zero_indexed(0);
+        let (start, end) = (Column::new_zero_indexed(start), Column::new_zero_indexed(end));
+        Span::new(row, row, start, end, "foo")
+    };
+    let expected = Some(("cafe\u{301}".to_owned(), span(4, 8)));
+    assert_eq!(vfs.word_at(path, pos(5)).unwrap(), expected);
+    assert_eq!(vfs.word_at(path, pos(7)).unwrap(), expected);
+    assert_eq!(vfs.word_at(path, pos(8)).unwrap(), None);
+
+    // Otherwise the combining accent is a column of its own, and not part of
+    // the word.
+    vfs.set_file_encoding(path, PositionEncoding::UnicodeScalarValue).unwrap();
+    assert_eq!(vfs.word_at(path, pos(5)).unwrap(), Some(("cafe".to_owned(), span(4, 8))));
+    assert_eq!(vfs.word_at(path, pos(8)).unwrap(), None);
+}
+
+#[test]
+fn test_write_fsync() {
+    let dir = make_temp_dir("write_fsync");
+    let path = dir.join("foo.rs");
+    let vfs = VfsInternal::<RealFileLoader, ()>::new();
+    vfs.set_write_options(WriteOptions {
+        fsync: true,
+        ..WriteOptions::default()
+    });
+    vfs.set_file(&path, "fn main() {}\n").unwrap();
+    vfs.write_file(&path).unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), "fn main() {}\n");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_files_with_user_data() {
+    let vfs = VfsInternal::<MockFileLoader, u32>::new();
+    vfs.load_file(Path::new("foo")).unwrap();
+    vfs.load_file(Path::new("bar")).unwrap();
+    assert!(vfs.files_with_user_data().is_empty());
+
+    vfs.set_user_data(Path::new("foo"), Some(42)).unwrap();
+    assert_eq!(vfs.files_with_user_data(), vec![PathBuf::from("foo")]);
+
+    // Changing the file clears its user data.
+    vfs.on_changes(&[make_change(false)]).unwrap();
+    assert!(vfs.files_with_user_data().is_empty());
+}
+
+#[test]
+fn test_edit_long_line() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    let mut expected = "a\u{E9}\u{1F600}".repeat(5_000);
+    vfs.set_file(Path::new("foo"), &format!("{}\nend", expected)).unwrap();
+
+    let replace = |span: VfsSpan, text: &str| {
+        vfs.on_changes(&[Change::ReplaceText {
+            span,
+            text: text.to_owned(),
+        }])
+        .unwrap();
+    };
+    let span = |start, end| {
+        Span::from_positions(
+            Position::new(Row::new_zero_indexed(0), Column::new_zero_indexed(start)),
+            Position::new(Row::new_zero_indexed(0), Column::new_zero_indexed(end)),
+            "foo",
+        )
+    };
+
+    // Columns 9_000..9_003 in scalar values are bytes 21_000..21_007.
+    replace(VfsSpan::from_usv(span(9_000, 9_003), None), "bc");
+    expected.replace_range(21_000..21_007, "bc");
+    // Columns 16_000..16_004 in UTF-16 are now bytes 27_998..28_005.
+    replace(VfsSpan::from_utf16(span(16_000, 16_004), None), "");
+    expected.replace_range(27_998..28_005, "");
+    // A later edit in the same line uses the updated index.
+    replace(VfsSpan::from_usv(span(14_000, 14_001), None), "d");
+    let start = expected.char_indices().nth(14_000).unwrap().0;
+    let end = expected.char_indices().nth(14_001).unwrap().0;
+    expected.replace_range(start..end, "d");
+
+    assert_eq!(
+        vfs.load_file(Path::new("foo")).unwrap(),
+        FileContents::Text(format!("{}\nend", expected)),
+    );
+}
+
+#[test]
+fn test_edit_after_write_in_sync() {
+    let dir = make_temp_dir("edit_after_write_in_sync");
+    let path = dir.join("foo.rs");
+    fs::write(&path, "fn main() {}\n").unwrap();
+
+    let vfs = VfsInternal::<RealFileLoader, ()>::new();
+    vfs.load_file(&path).unwrap();
+    let change = |end: u32, text: &str| Change::ReplaceText {
+        span: VfsSpan::from_usv(
+            Span::from_positions(
+                Position::new(Row::new_zero_indexed(0), Column::new_zero_indexed(3)),
+                Position::new(Row::new_zero_indexed(0), Column::new_zero_indexed(end)),
+                &path,
+            ),
+            None,
+        ),
+        text: text.to_owned(),
+    };
+
+    vfs.on_changes(&[change(7, "start")]).unwrap();
+    vfs.write_file(&path).unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), "fn start() {}\n");
+
+    // Our own write does not make the cached file out of sync.
+    vfs.on_changes(&[change(8, "begin")]).unwrap();
+    assert_eq!(
+        vfs.load_file(&path).unwrap(),
+        FileContents::Text("fn begin() {}\n".to_owned())
+    );
+
+    // Nor does a save by the client which we are told about.
+    fs::write(&path, "fn begin() {}\n").unwrap();
+    vfs.file_saved(&path).unwrap();
+    vfs.on_changes(&[change(8, "run")]).unwrap();
+}
+
+#[test]
+fn test_edit_out_of_sync() {
+    let dir = make_temp_dir("edit_out_of_sync");
+    let path = dir.join("foo.rs");
+    fs::write(&path, "fn main() {}\n").unwrap();
+
+    let vfs = VfsInternal::<RealFileLoader, ()>::new();
+    vfs.load_file(&path).unwrap();
+    fs::write(&path, "fn main() { println!(); }\n").unwrap();
+
+    let changes = [Change::ReplaceText {
+        span: VfsSpan::from_usv(
+            Span::from_positions(
+                Position::new(Row::new_zero_indexed(0), Column::new_zero_indexed(3)),
+                Position::new(Row::new_zero_indexed(0), Column::new_zero_indexed(7)),
+                &path,
+            ),
+            None,
+        ),
+        text: "start".to_owned(),
+    }];
+    assert_eq!(vfs.on_changes(&changes), Err(Error::OutOfSync(path.clone())));
+    assert_eq!(
+        vfs.load_file(&path).unwrap(),
+        FileContents::Text("fn main() {}\n".to_owned())
+    );
+
+    // Once the file has been reloaded, the edit applies.
+    vfs.reload_file(&path).unwrap();
+    vfs.on_changes(&changes).unwrap();
+    assert_eq!(
+        vfs.load_file(&path).unwrap(),
+        FileContents::Text("fn start() { println!(); }\n".to_owned())
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_content_equals() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    assert_eq!(vfs.content_equals(Path::new("foo"), ""), Err(Error::FileNotCached));
+
+    vfs.load_file(Path::new("foo")).unwrap();
+    assert_eq!(
+        vfs.content_equals(Path::new("foo"), "foo\nHello\nWorld\nHello, World!\n"),
+        Ok(true)
+    );
+    assert_eq!(vfs.content_equals(Path::new("foo"), "foo\nHello\n"), Ok(false));
+
+    vfs.on_changes(&[make_change(false)]).unwrap();
+    assert_eq!(
+        vfs.content_equals(Path::new("foo"), "foo\nHello\nWorld\nHello, World!\n"),
+        Ok(false)
+    );
+}
+
+#[test]
+fn test_line_terminator() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    vfs.set_file(Path::new("lf"), "a\nb\nc").unwrap();
+    vfs.set_file(Path::new("crlf"), "a\r\nb\r\n").unwrap();
+    let terminator = |path: &str, line| {
+        vfs.line_terminator(Path::new(path), Row::new_zero_indexed(line))
+    };
+
+    assert_eq!(terminator("lf", 0), Ok(LineTerminator::Lf));
+    assert_eq!(terminator("lf", 1), Ok(LineTerminator::Lf));
+    assert_eq!(terminator("lf", 2), Ok(LineTerminator::Unterminated));
+    assert_eq!(terminator("lf", 3), Err(Error::BadLocation));
+
+    assert_eq!(terminator("crlf", 0), Ok(LineTerminator::CrLf));
+    assert_eq!(terminator("crlf", 1), Ok(LineTerminator::CrLf));
+    // The empty line after the final newline.
+    assert_eq!(terminator("crlf", 2), Ok(LineTerminator::Unterminated));
+}
+
+#[test]
+fn test_soft_delete() {
+    let vfs = VfsInternal::<MockFileLoader, u32>::new();
+    let text = "foo\nHello\nWorld\nHello, World!\n".to_owned();
+    assert_eq!(vfs.soft_delete(Path::new("foo")), Err(Error::FileNotCached));
+
+    vfs.load_file(Path::new("foo")).unwrap();
+    vfs.set_user_data(Path::new("foo"), Some(42)).unwrap();
+    vfs.soft_delete(Path::new("foo")).unwrap();
+    assert_eq!(
+        vfs.load_file(Path::new("foo")),
+        Err(Error::Deleted(PathBuf::from("foo")))
+    );
+    assert_eq!(
+        vfs.on_changes(&[make_change(false)]),
+        Err(Error::Deleted(PathBuf::from("foo")))
+    );
+
+    // Adding the file again with the same text restores its user data.
+    let change = Change::AddFile {
+        file: PathBuf::from("foo"),
+        text: text.clone(),
+    };
+    vfs.on_changes(&[change]).unwrap();
+    assert_eq!(vfs.load_file(Path::new("foo")).unwrap(), FileContents::Text(text));
+    vfs.with_user_data(Path::new("foo"), |u| {
+        assert_eq!(*u.unwrap().1, 42);
+        Ok(())
+    })
+    .unwrap();
+
+    // Other ways of adding the file again discard the tombstone.
+    vfs.soft_delete(Path::new("foo")).unwrap();
+    vfs.set_file(Path::new("foo"), "fn main() {}\n").unwrap();
+    assert!(vfs.load_file(Path::new("foo")).is_ok());
+}
+
+#[test]
+fn test_coalescing() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::with_coalescing(10);
+    vfs.load_file(Path::new("foo")).unwrap();
+    let insert = |col, text: &str| Change::ReplaceText {
+        span: VfsSpan::from_usv(
+            Span::from_positions(
+                Position::new(Row::new_zero_indexed(1), Column::new_zero_indexed(col)),
+                Position::new(Row::new_zero_indexed(1), Column::new_zero_indexed(col)),
+                "foo",
+            ),
+            None,
+        ),
+        text: text.to_owned(),
+    };
+
+    vfs.on_changes(&[insert(5, "a")]).unwrap();
+    vfs.on_changes(&[insert(6, "b")]).unwrap();
+    vfs.on_changes(&[insert(7, "c")]).unwrap();
+    assert_eq!(vfs.stats().change_batches, 0);
+    assert_eq!(vfs.load_line(Path::new("foo"), Row::new_zero_indexed(1)).unwrap(), "Hello\n");
+
+    vfs.flush_pending().unwrap();
+    assert_eq!(vfs.stats().change_batches, 1);
+    assert_eq!(vfs.load_line(Path::new("foo"), Row::new_zero_indexed(1)).unwrap(), "Helloabc\n");
+
+    // Filling the buffer applies the changes.
+    let changes: Vec<_> = (0..10).map(|i| insert(i, "x")).collect();
+    vfs.on_changes(&changes).unwrap();
+    assert_eq!(vfs.stats().change_batches, 2);
+}
+
+#[test]
+fn test_affected_lines() {
+    let replace = |file: &str, row_start, row_end| Change::ReplaceText {
+        span: VfsSpan::from_usv(
+            Span::from_positions(
+                Position::new(Row::new_zero_indexed(row_start), Column::new_zero_indexed(0)),
+                Position::new(Row::new_zero_indexed(row_end), Column::new_zero_indexed(2)),
+                file,
+            ),
+            None,
+        ),
+        text: String::new(),
+    };
+    let changes = [
+        replace("foo", 1, 3),
+        Change::AddFile {
+            file: PathBuf::from("bar"),
+            text: String::new(),
+        },
+        replace("foo", 5, 5),
+        // A span with a `len` covers its nominal rows, even if the `len`
+        // reaches further.
+        Change::ReplaceText {
+            span: VfsSpan::from_usv(
+                Span::from_positions(
+                    Position::new(Row::new_zero_indexed(7), Column::new_zero_indexed(0)),
+                    Position::new(Row::new_zero_indexed(7), Column::new_zero_indexed(0)),
+                    "foo",
+                ),
+                Some(100),
+            ),
+            text: String::new(),
+        },
+    ];
+
+    let affected = affected_lines(&changes);
+    assert_eq!(affected.len(), 1);
+    assert_eq!(
+        affected[Path::new("foo")],
+        vec![
+            Row::new_zero_indexed(1)..Row::new_zero_indexed(4),
+            Row::new_zero_indexed(5)..Row::new_zero_indexed(6),
+            Row::new_zero_indexed(7)..Row::new_zero_indexed(8),
+        ]
+    );
+}
+
+#[test]
+fn test_debug_dump() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    assert_eq!(vfs.debug_dump(), "");
+
+    vfs.load_file(Path::new("foo")).unwrap();
+    vfs.set_file(Path::new("bar"), "fn main() {}").unwrap();
+    assert_eq!(
+        vfs.debug_dump(),
+        "bar: 12 bytes, 1 lines, changed, version 0, no user data\n\
+         foo: 30 bytes, 5 lines, unchanged, version 0, no user data\n"
+    );
+}
+
+#[test]
+fn test_add_virtual() {
+    let vfs = VfsInternal::<RealFileLoader, ()>::new();
+    let path = Path::new("<stdin>");
+    assert!(vfs.load_file(path).is_err());
+
+    vfs.add_virtual(path, "fn main() {}\n".to_owned());
+    assert_eq!(
+        vfs.load_file(path).unwrap(),
+        FileContents::Text("fn main() {}\n".to_owned())
+    );
+    assert!(!vfs.exists_on_disk(path));
+    assert_eq!(vfs.write_file(path), Err(Error::ReadOnly(path.to_owned())));
+    let change = Change::AddFile {
+        file: path.to_owned(),
+        text: String::new(),
+    };
+    assert_eq!(vfs.on_changes(&[change]), Err(Error::ReadOnly(path.to_owned())));
+
+    // Nor can it be changed in other ways.
+    let read_only = Err(Error::ReadOnly(path.to_owned()));
+    assert_eq!(vfs.set_file(path, ""), read_only);
+    assert_eq!(vfs.set_files(vec![(path.to_owned(), String::new())]), read_only);
+    assert_eq!(vfs.with_mut_text(path, |t| t.clear()), read_only);
+    assert_eq!(vfs.undo(path).map(|_| ()), read_only);
+    let span = VfsSpan::from_usv(
+        Span::new(
+            Row::new_zero_indexed(0),
+            Row::new_zero_indexed(0),
+            Column::new_zero_indexed(0),
+            Column::new_zero_indexed(2),
+            path,
+        ),
+        None,
+    );
+    assert_eq!(vfs.move_range_to_file(&span, Path::new("other.rs")), read_only);
+    vfs.set_file(Path::new("other.rs"), "fn other() {}\n").unwrap();
+    let span = VfsSpan::from_usv(
+        Span::new(
+            Row::new_zero_indexed(0),
+            Row::new_zero_indexed(0),
+            Column::new_zero_indexed(0),
+            Column::new_zero_indexed(2),
+            "other.rs",
+        ),
+        None,
+    );
+    assert_eq!(vfs.move_range_to_file(&span, path), read_only);
+    assert_eq!(
+        vfs.load_file(path).unwrap(),
+        FileContents::Text("fn main() {}\n".to_owned())
+    );
+
+    // Adding the path again replaces the cached contents.
+    vfs.add_virtual(path, "fn foo() {}\n".to_owned());
+    assert_eq!(
+        vfs.load_file(path).unwrap(),
+        FileContents::Text("fn foo() {}\n".to_owned())
+    );
+
+    let dir = make_temp_dir("add_virtual");
+    let on_disk = dir.join("foo.rs");
+    fs::write(&on_disk, "").unwrap();
+    assert!(vfs.exists_on_disk(&on_disk));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(windows)]
+#[test]
+fn test_add_virtual_case_insensitive() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    vfs.add_virtual(Path::new(r"C:\gen\Foo.rs"), "fn main() {}\n".to_owned());
+    let path = Path::new(r"c:\GEN\foo.rs");
+    assert_eq!(vfs.set_file(path, ""), Err(Error::ReadOnly(path.to_owned())));
+}
+
+#[test]
+fn test_preserve_line_endings() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    vfs.set_file(Path::new("foo"), "a\r\nb\r\nc\r\n").unwrap();
+    let replace = || Change::ReplaceText {
+        span: VfsSpan::from_usv(
+            Span::from_positions(
+                Position::new(Row::new_zero_indexed(0), Column::new_zero_indexed(1)),
+                Position::new(Row::new_zero_indexed(1), Column::new_zero_indexed(1)),
+                "foo",
+            ),
+            None,
+        ),
+        text: "1\n2\n3".to_owned(),
+    };
+
+    vfs.set_preserve_line_endings(true);
+    vfs.on_changes(&[replace()]).unwrap();
+    assert_eq!(
+        vfs.load_file(Path::new("foo")).unwrap(),
+        FileContents::Text("a1\r\n2\r\n3\r\nc\r\n".to_owned())
+    );
+    assert_eq!(vfs.line_ending(Path::new("foo")).unwrap(), LineEnding::CrLf);
+
+    vfs.set_preserve_line_endings(false);
+    vfs.on_changes(&[replace()]).unwrap();
+    assert_eq!(vfs.line_ending(Path::new("foo")).unwrap(), LineEnding::Mixed);
+}
+
+#[test]
+fn test_read_guard() {
+    let vfs = VfsInternal::<MockFileLoader, u32>::new();
+    vfs.load_file(Path::new("foo")).unwrap();
+    vfs.set_file(Path::new("bar"), "fn main() {}\n").unwrap();
+    vfs.set_user_data(Path::new("foo"), Some(42)).unwrap();
+
+    let guard = vfs.read_guard();
+    let mut paths: Vec<_> = guard.paths().collect();
+    paths.sort();
+    assert_eq!(paths, vec![Path::new("bar"), Path::new("foo")]);
+
+    let foo = guard.get(Path::new("foo")).unwrap();
+    let bar = guard.get(Path::new("bar")).unwrap();
+    assert_eq!(foo.load_line(Row::new_zero_indexed(1)).unwrap(), "Hello\n");
+    assert_eq!(foo.user_data(), Some(&42));
+    assert!(!foo.changed());
+    assert_eq!(bar.text(), Some("fn main() {}\n"));
+    assert_eq!(bar.user_data(), None);
+    assert!(bar.changed());
+    assert!(guard.get(Path::new("baz")).is_none());
+}
+
+#[test]
+fn test_lossy_utf8() {
+    let dir = make_temp_dir("lossy_utf8");
+    let path = dir.join("foo.rs");
+    fs::write(&path, b"fn \xFFmain() {}\n").unwrap();
+
+    let vfs = VfsInternal::<RealFileLoader, ()>::new();
+    assert!(matches!(vfs.load_file(&path).unwrap(), FileContents::Binary(_)));
+
+    vfs.flush_file(&path).unwrap();
+    vfs.set_utf8_error_mode(Utf8ErrorMode::Lossy);
+    assert_eq!(
+        vfs.load_file(&path).unwrap(),
+        FileContents::Text("fn \u{FFFD}main() {}\n".to_owned())
+    );
+    assert_eq!(vfs.write_file(&path), Err(Error::LossilyDecoded(path.clone())));
+    assert_eq!(fs::read(&path).unwrap(), b"fn \xFFmain() {}\n");
+
+    // Once the client provides the whole text, the file can be written.
+    let change = Change::AddFile {
+        file: path.clone(),
+        text: "fn main() {}\n".to_owned(),
+    };
+    vfs.on_changes(&[change]).unwrap();
+    vfs.write_file(&path).unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), "fn main() {}\n");
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_mark_clean() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    assert_eq!(vfs.mark_clean(Path::new("foo")), Err(Error::FileNotCached));
+    // `file_saved` is lenient about unknown files.
+    assert_eq!(vfs.file_saved(Path::new("foo")), Ok(()));
+
+    vfs.set_file(Path::new("foo"), "fn main() {}\n").unwrap();
+    assert!(!vfs.file_is_synced(Path::new("foo")).unwrap());
+    vfs.mark_clean(Path::new("foo")).unwrap();
+    assert!(vfs.file_is_synced(Path::new("foo")).unwrap());
+}
+
+#[test]
+fn test_read_file_cow() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    let text = "foo\nHello\nWorld\nHello, World!\n";
+    let digest = vfs.read_file_cow(Path::new("foo"), |text| sha256::digest(text.as_bytes()));
+    assert_eq!(digest, Ok(sha256::digest(text.as_bytes())));
+}
+
+#[test]
+fn test_pending_lsp_changes() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    vfs.load_file(Path::new("/src/foo.rs")).unwrap();
+    vfs.load_file(Path::new("/src/my lib.rs")).unwrap();
+    vfs.load_file(Path::new("/src/unchanged.rs")).unwrap();
+    assert!(vfs.pending_lsp_changes().is_empty());
+
+    let insert = |file: &str, text: &str| Change::ReplaceText {
+        span: VfsSpan::from_usv(
+            Span::from_positions(
+                Position::new(Row::new_zero_indexed(0), Column::new_zero_indexed(0)),
+                Position::new(Row::new_zero_indexed(0), Column::new_zero_indexed(0)),
+                file,
+            ),
+            None,
+        ),
+        text: text.to_owned(),
+    };
+    vfs.on_changes(&[insert("/src/foo.rs", "a")]).unwrap();
+    vfs.on_changes(&[insert("/src/foo.rs", "b"), insert("/src/my lib.rs", "c")]).unwrap();
+
+    let changes = vfs.pending_lsp_changes();
+    assert_eq!(changes.len(), 2);
+    assert_eq!(changes[0].uri, "file:///src/foo.rs");
+    assert_eq!(changes[0].version, 2);
+    assert_eq!(changes[0].text, "ba/src/foo.rs\nHello\nWorld\nHello, World!\n");
+    assert_eq!(changes[1].uri, "file:///src/my%20lib.rs");
+    assert_eq!(changes[1].version, 1);
+    assert_eq!(changes[1].text, "c/src/my lib.rs\nHello\nWorld\nHello, World!\n");
+
+
+    // A relative path is resolved against the root, or skipped without one.
+    vfs.set_file(Path::new("rel.rs"), "d").unwrap();
+    assert_eq!(vfs.pending_lsp_changes().len(), 2);
+    vfs.set_root(Some(PathBuf::from("/work")));
+    let changes = vfs.pending_lsp_changes();
+    assert_eq!(changes.len(), 3);
+    assert_eq!(changes[2].uri, "file:///work/rel.rs");
+    assert_eq!(changes[2].text, "d");
+}
+
+#[test]
+fn test_compact() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    assert_eq!(vfs.compact(Path::new("foo")), Err(Error::FileNotCached));
+    vfs.load_file(Path::new("foo")).unwrap();
+
+    let replace = |end, text: String| Change::ReplaceText {
+        span: VfsSpan::from_usv(
+            Span::from_positions(
+                Position::new(Row::new_zero_indexed(0), Column::new_zero_indexed(0)),
+                Position::new(Row::new_zero_indexed(end), Column::new_zero_indexed(0)),
+                "foo",
+            ),
+            None,
+        ),
+        text,
+    };
+    vfs.on_changes(&[replace(0, "x\n".repeat(10_000))]).unwrap();
+    vfs.on_changes(&[replace(10_000, String::new())]).unwrap();
+    let capacity = || match vfs.files.lock().unwrap()[Path::new("foo")].kind {
+        FileKind::Text(ref t) => t.text.capacity(),
+        FileKind::Binary(_) => unreachable!(),
+    };
+    assert!(capacity() >= 20_000);
+
+    vfs.compact(Path::new("foo")).unwrap();
+    assert!(capacity() < 100);
+    assert_eq!(
+        vfs.load_file(Path::new("foo")).unwrap(),
+        FileContents::Text("foo\nHello\nWorld\nHello, World!\n".to_owned())
+    );
+    assert_eq!(vfs.load_line(Path::new("foo"), Row::new_zero_indexed(2)).unwrap(), "World\n");
+    vfs.compact_all();
+    vfs.check_invariants().unwrap();
+}
+
+#[test]
+fn test_first_nonblank_offset() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    vfs.set_file(Path::new("foo"), "fn main() {\n    foo();\n \t\r\n}").unwrap();
+    let offset = |line| vfs.first_nonblank_offset(Path::new("foo"), Row::new_zero_indexed(line));
+
+    assert_eq!(offset(0), Ok(Some(0)));
+    // An indented line.
+    assert_eq!(offset(1), Ok(Some(16)));
+    // A blank line.
+    assert_eq!(offset(2), Ok(None));
+    // The last line, without a line terminator.
+    assert_eq!(offset(3), Ok(Some(27)));
+    assert_eq!(offset(4), Err(Error::BadLocation));
+}
+
+#[test]
+fn test_last_modified() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    assert_eq!(vfs.last_modified(Path::new("foo")), Err(Error::FileNotCached));
+    vfs.load_file(Path::new("foo")).unwrap();
+    let loaded = vfs.last_modified(Path::new("foo")).unwrap();
+
+    thread::sleep(std::time::Duration::from_millis(10));
+    vfs.on_changes(&[make_change(false)]).unwrap();
+    let changed = vfs.last_modified(Path::new("foo")).unwrap();
+    assert!(changed > loaded);
+
+    // Reading the file does not change it.
+    vfs.load_file(Path::new("foo")).unwrap();
+    assert_eq!(vfs.last_modified(Path::new("foo")), Ok(changed));
+}
+
+#[test]
+fn test_with_paths() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    vfs.load_file(Path::new("foo")).unwrap();
+    vfs.load_file(Path::new("bar")).unwrap();
+    vfs.set_file(Path::new("baz/qux"), "").unwrap();
+
+    let mut paths = HashSet::new();
+    vfs.with_paths(|path| {
+        paths.insert(path.to_owned());
+    });
+    let cached: HashSet<_> = vfs.get_cached_files().keys().cloned().collect();
+    assert_eq!(paths, cached);
+    assert_eq!(paths.len(), 3);
+}
+
+#[cfg(feature = "regex")]
+#[test]
+fn test_regex_replace() {
+    use regex::Regex;
+
+    let vfs = VfsInternal::<MockFileLoader, u32>::new();
+    let pattern = Regex::new(r"W\w+").unwrap();
+    assert_eq!(vfs.regex_replace(Path::new("foo"), &pattern, "X"), Err(Error::FileNotCached));
+
+    vfs.load_file(Path::new("foo")).unwrap();
+    vfs.set_user_data(Path::new("foo"), Some(42)).unwrap();
+    assert_eq!(vfs.regex_replace(Path::new("foo"), &pattern, "X"), Ok(2));
+    assert_eq!(
+        vfs.load_file(Path::new("foo")).unwrap(),
+        FileContents::Text("foo\nHello\nX\nHello, X!\n".to_owned())
+    );
+    assert_eq!(vfs.load_line(Path::new("foo"), Row::new_zero_indexed(3)).unwrap(), "Hello, X!\n");
+    assert!(!vfs.file_is_synced(Path::new("foo")).unwrap());
+    vfs.with_user_data(Path::new("foo"), |u| {
+        assert_eq!(u, Err(Error::NoUserDataForFile));
+        Ok(())
+    })
+    .unwrap();
+
+    // Capture groups.
+    let pattern = Regex::new(r"H(\w+)").unwrap();
+    assert_eq!(vfs.regex_replace(Path::new("foo"), &pattern, "J$1"), Ok(2));
+    assert_eq!(
+        vfs.load_file(Path::new("foo")).unwrap(),
+        FileContents::Text("foo\nJello\nX\nJello, X!\n".to_owned())
+    );
+}
+
+#[test]
+fn test_scan_lines() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    let scan = |path: &Path| {
+        let mut lines = vec![];
+        vfs.scan_lines(path, |row, line| lines.push((row.0, line.to_owned()))).unwrap();
+        lines
+    };
+    let expected = vec![
+        (0, "foo\n".to_owned()),
+        (1, "Hello\n".to_owned()),
+        (2, "World\n".to_owned()),
+        (3, "Hello, World!\n".to_owned()),
+        (4, String::new()),
+    ];
+    assert_eq!(scan(Path::new("foo")), expected);
+    assert!(!vfs.is_cached(Path::new("foo")));
+
+    vfs.load_file(Path::new("foo")).unwrap();
+    assert!(vfs.is_cached(Path::new("foo")));
+    assert_eq!(scan(Path::new("foo")), expected);
+}
+
+#[test]
+fn test_scan_lines_from_disk() {
+    let dir = make_temp_dir("scan_lines");
+    let path = dir.join("foo.rs");
+    let vfs = VfsInternal::<RealFileLoader, ()>::new();
+    for text in &["", "a\n", "a\r\nb", "a\n\nb\n"] {
+        fs::write(&path, text).unwrap();
+        let mut lines = vec![];
+        vfs.scan_lines(&path, |row, line| lines.push((row.0, line.to_owned()))).unwrap();
+        assert!(!vfs.is_cached(&path));
+
+        vfs.set_file(&path, text).unwrap();
+        let mut cached_lines = vec![];
+        vfs.scan_lines(&path, |row, line| cached_lines.push((row.0, line.to_owned())))
+            .unwrap();
+        assert_eq!(lines, cached_lines);
+        vfs.flush_file(&path).unwrap();
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_apply_patch() {
+    let vfs = VfsInternal::<MockFileLoader, u32>::new();
+    let patch = "--- a/foo\n+++ b/foo\n@@ -2,3 +2,3 @@\n Hello\n-World\n+Patched\n Hello, World!\n";
+    assert_eq!(vfs.apply_patch(Path::new("foo"), patch), Err(Error::FileNotCached));
+
+    vfs.load_file(Path::new("foo")).unwrap();
+    vfs.set_user_data(Path::new("foo"), Some(42)).unwrap();
+    vfs.apply_patch(Path::new("foo"), patch).unwrap();
+    assert_eq!(
+        vfs.load_file(Path::new("foo")).unwrap(),
+        FileContents::Text("foo\nHello\nPatched\nHello, World!\n".to_owned())
+    );
+    assert_eq!(vfs.load_line(Path::new("foo"), Row::new_zero_indexed(2)).unwrap(), "Patched\n");
+    assert!(!vfs.file_is_synced(Path::new("foo")).unwrap());
+    assert_eq!(vfs.file_version(Path::new("foo")).unwrap(), 1);
+    vfs.with_user_data(Path::new("foo"), |u| {
+        assert_eq!(u, Err(Error::NoUserDataForFile));
+        Ok(())
+    })
+    .unwrap();
+
+    // The context no longer matches, so the file is left unchanged.
+    assert_eq!(vfs.apply_patch(Path::new("foo"), patch), Err(Error::BadLocation));
+    assert_eq!(
+        vfs.load_file(Path::new("foo")).unwrap(),
+        FileContents::Text("foo\nHello\nPatched\nHello, World!\n".to_owned())
+    );
+    assert_eq!(vfs.file_version(Path::new("foo")).unwrap(), 1);
+}
+
+#[test]
+fn test_change_len_at_eof() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    vfs.load_file(Path::new("foo")).unwrap();
+    let change = |len, utf16| {
+        let span = Span::new(
+            Row::new_zero_indexed(3),
+            Row::new_zero_indexed(0),
+            Column::new_zero_indexed(7),
+            Column::new_zero_indexed(0),
+            "foo",
+        );
+        Change::ReplaceText {
+            span: if utf16 {
+                VfsSpan::from_utf16(span, Some(len))
+            } else {
+                VfsSpan::from_usv(span, Some(len))
+            },
+            text: "X".to_owned(),
+        }
+    };
+
+    // `len` runs past the end of the file, which is left unchanged.
+    for &len in &[8, 100, u64::from(u32::MAX) + 1] {
+        for &utf16 in &[false, true] {
+            assert_eq!(vfs.on_changes(&[change(len, utf16)]), Err(Error::BadLocation));
+        }
+    }
+    assert_eq!(
+        vfs.load_file(Path::new("foo")).unwrap(),
+        FileContents::Text("foo\nHello\nWorld\nHello, World!\n".to_owned())
+    );
+
+    // `len` reaches exactly the end of the file.
+    vfs.on_changes(&[change(7, true)]).unwrap();
+    assert_eq!(
+        vfs.load_file(Path::new("foo")).unwrap(),
+        FileContents::Text("foo\nHello\nWorld\nHello, X".to_owned())
+    );
+}
+
+#[test]
+fn test_with_user_data_multi() {
+    let vfs = VfsInternal::<MockFileLoader, i32>::new();
+    vfs.load_file(Path::new("foo")).unwrap();
+    vfs.load_file(Path::new("bar")).unwrap();
+    vfs.load_file(Path::new("baz")).unwrap();
+    vfs.set_user_data(Path::new("foo"), Some(1)).unwrap();
+    vfs.set_user_data(Path::new("bar"), Some(2)).unwrap();
+
+    let paths = [
+        Path::new("foo"),
+        Path::new("bar"),
+        Path::new("baz"),
+        Path::new("qux"),
+        Path::new("foo"),
+    ];
+    let sum = vfs.with_user_data_multi(&paths, |entries| {
+        assert_eq!(entries[0].0, Path::new("foo"));
+        assert_eq!(entries[1].0, Path::new("bar"));
+        {
+            let (text, foo) = entries[0].1.as_mut().unwrap();
+            assert_eq!(*text, Some("foo\nHello\nWorld\nHello, World!\n"));
+            **foo += 10;
+        }
+        let sum = *entries[0].1.as_ref().unwrap().1 + *entries[1].1.as_ref().unwrap().1;
+        assert_eq!(entries[2].1, Err(Error::NoUserDataForFile));
+        assert_eq!(entries[3].1, Err(Error::FileNotCached));
+        assert_eq!(entries[4].1, Err(Error::DuplicatePath(PathBuf::from("foo"))));
+        // Erase bar's user data.
+        entries[1].1 = Err(Error::NoUserDataForFile);
+        sum
+    });
+    assert_eq!(sum, 13);
+
+    vfs.with_user_data(Path::new("foo"), |u| {
+        assert_eq!(*u.unwrap().1, 11);
+        Ok(())
+    })
+    .unwrap();
+    vfs.with_user_data(Path::new("bar"), |u| {
+        assert_eq!(u, Err(Error::NoUserDataForFile));
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn test_change_history() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    let text = "\u{E9}".repeat(CHANGE_SUMMARY_TEXT_LEN);
+    vfs.on_changes(&[Change::AddFile {
+        file: PathBuf::from("bar"),
+        text: text.clone(),
+    }])
+    .unwrap();
+    let history = vfs.change_history();
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].0, PathBuf::from("bar"));
+    assert_eq!(history[0].2.span, None);
+    assert_eq!(history[0].2.text_len, text.len());
+    assert_eq!(history[0].2.text, text[..CHANGE_SUMMARY_TEXT_LEN]);
+
+    for _ in 0..CHANGE_HISTORY_LEN {
+        vfs.on_changes(&[make_change(false)]).unwrap();
+    }
+    let history = vfs.change_history();
+    assert_eq!(history.len(), CHANGE_HISTORY_LEN);
+    assert!(history.iter().all(|(path, _, _)| path == Path::new("foo")));
+    assert_eq!(history[0].1, 1);
+    assert_eq!(history.last().unwrap().1, CHANGE_HISTORY_LEN as u64);
+    assert_eq!(history.last().unwrap().2.text, "foo");
+    match make_change(false) {
+        Change::ReplaceText { span, .. } => assert_eq!(history[0].2.span, Some(span)),
+        Change::AddFile { .. } => unreachable!(),
+    }
+}
+
+#[test]
+fn test_create_missing_files() {
+    let dir = make_temp_dir("create_missing");
+    let path = dir.join("new.rs");
+    let vfs = VfsInternal::<RealFileLoader, ()>::new();
+    let insert = |row, col| Change::ReplaceText {
+        span: VfsSpan::from_usv(
+            Span::new(
+                Row::new_zero_indexed(row),
+                Row::new_zero_indexed(row),
+                Column::new_zero_indexed(col),
+                Column::new_zero_indexed(col),
+                &path,
+            ),
+            None,
+        ),
+        text: "fn main() {}\n".to_owned(),
+    };
+
+    assert!(matches!(vfs.on_changes(&[insert(0, 0)]), Err(Error::Io(..))));
+    vfs.set_create_missing_files(true);
+    // Only insertions at the start of the file create it.
+    assert!(matches!(vfs.on_changes(&[insert(0, 1)]), Err(Error::Io(..))));
+    assert!(!vfs.is_cached(&path));
+
+    vfs.on_changes(&[insert(0, 0)]).unwrap();
+    assert_eq!(
+        vfs.load_file(&path).unwrap(),
+        FileContents::Text("fn main() {}\n".to_owned())
+    );
+    assert!(!vfs.file_is_synced(&path).unwrap());
+    assert!(!path.exists());
+    vfs.write_file(&path).unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), "fn main() {}\n");
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_common_root() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    assert_eq!(vfs.common_root(), None);
+
+    vfs.set_file(Path::new("/ws/src/main.rs"), "").unwrap();
+    assert_eq!(vfs.common_root(), Some(PathBuf::from("/ws/src")));
+    vfs.set_file(Path::new("/ws/src/bin/tool.rs"), "").unwrap();
+    vfs.set_file(Path::new("/ws/tests/it.rs"), "").unwrap();
+    assert_eq!(vfs.common_root(), Some(PathBuf::from("/ws")));
+    // Component-wise, not character-wise.
+    vfs.set_file(Path::new("/wsx/lib.rs"), "").unwrap();
+    assert_eq!(vfs.common_root(), Some(PathBuf::from("/")));
+
+    // A relative path has a different root from the absolute paths.
+    vfs.set_file(Path::new("ws/lib.rs"), "").unwrap();
+    assert_eq!(vfs.common_root(), None);
+}
+
+#[test]
+fn test_trailing_empty_line_policy() {
+    let dir = make_temp_dir("trailing_empty_line");
+    let paged = dir.join("paged.txt");
+    fs::write(&paged, "a\n").unwrap();
+
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    vfs.set_file(Path::new("foo"), "a\n").unwrap();
+    vfs.set_file(Path::new("bar"), "a").unwrap();
+    vfs.open_paged(&paged).unwrap();
+    let load_line = |path: &Path, row| vfs.load_line(path, Row::new_zero_indexed(row));
+
+    for path in &[Path::new("foo"), &paged] {
+        assert_eq!(vfs.num_lines(path), Ok(2));
+        assert_eq!(load_line(path, 0).unwrap(), "a\n");
+        assert_eq!(load_line(path, 1).unwrap(), "");
+    }
+    assert_eq!(vfs.line_ranges(Path::new("foo")).unwrap().len(), 2);
+
+    vfs.set_trailing_empty_line_policy(TrailingEmptyLinePolicy::Drop);
+    for path in &[Path::new("foo"), &paged] {
+        assert_eq!(vfs.num_lines(path), Ok(1));
+        assert_eq!(load_line(path, 0).unwrap(), "a\n");
+        assert_eq!(load_line(path, 1), Err(Error::BadLocation));
+    }
+    assert_eq!(
+        vfs.line_ranges(Path::new("foo")).unwrap(),
+        vec![(Row::new_zero_indexed(0), 0..2)]
+    );
+    // Files not ending in a newline are not affected.
+    assert_eq!(vfs.num_lines(Path::new("bar")), Ok(1));
+    assert_eq!(load_line(Path::new("bar"), 0).unwrap(), "a");
+    assert!(!vfs.is_cached(&paged));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_replace_if_version() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    assert_eq!(vfs.replace_if_version(Path::new("foo"), 0, "new\n"), Err(Error::FileNotCached));
+
+    vfs.load_file(Path::new("foo")).unwrap();
+    let version = vfs.file_version(Path::new("foo")).unwrap();
+    // Another thread edits the file in the meantime.
+    vfs.on_changes(&[make_change(false)]).unwrap();
+    assert_eq!(vfs.replace_if_version(Path::new("foo"), version, "new\n"), Ok(false));
+    assert_eq!(
+        vfs.load_file(Path::new("foo")).unwrap(),
+        FileContents::Text("foo\nHfooo\nWorld\nHello, World!\n".to_owned())
+    );
+    assert_eq!(vfs.file_version(Path::new("foo")).unwrap(), version + 1);
+
+    assert_eq!(vfs.replace_if_version(Path::new("foo"), version + 1, "new\n"), Ok(true));
+    assert_eq!(vfs.load_file(Path::new("foo")).unwrap(), FileContents::Text("new\n".to_owned()));
+    assert_eq!(vfs.file_version(Path::new("foo")).unwrap(), version + 2);
+    assert_eq!(vfs.load_line(Path::new("foo"), Row::new_zero_indexed(0)).unwrap(), "new\n");
+}
+
+#[test]
+fn test_hash_at_version() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    let path = Path::new("foo");
+    assert_eq!(vfs.hash_at_version(path, 0), None);
+
+    vfs.enable_version_hashes();
+    vfs.load_file(path).unwrap();
+    let v0 = vfs.file_version(path).unwrap();
+    let h0 = vfs.hash_at_version(path, v0).unwrap();
+    vfs.on_changes(&[make_change(false)]).unwrap();
+    let h1 = vfs.hash_at_version(path, v0 + 1).unwrap();
+    vfs.with_mut_text(path, |t| t.push_str("more\n")).unwrap();
+    let h2 = vfs.hash_at_version(path, v0 + 2).unwrap();
+    assert_eq!(vfs.file_version(path).unwrap(), v0 + 2);
+    assert!(h0 != h1 && h1 != h2 && h0 != h2);
+    assert_eq!(vfs.hash_at_version(path, v0), Some(h0));
+    assert_eq!(vfs.hash_at_version(path, v0 + 1), Some(h1));
+    assert_eq!(vfs.hash_at_version(path, v0 + 3), None);
+
+    // The same contents have the same hash.
+    vfs.with_mut_text(path, |t| *t = "foo\nHello\nWorld\nHello, World!\n".to_owned()).unwrap();
+    assert_eq!(vfs.hash_at_version(path, v0 + 3), Some(h0));
+
+    // Only the most recent versions are kept.
+    for _ in 0..VERSION_HASH_LEN {
+        vfs.with_mut_text(path, |t| t.push('x')).unwrap();
+    }
+    assert_eq!(vfs.hash_at_version(path, v0 + 2), None);
+    assert_eq!(vfs.hash_at_version(path, v0 + 3), Some(h0));
+}
+
+#[test]
+fn test_line_suffix() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    vfs.set_file(Path::new("foo"), "let x = 1;\r\n😢 e\u{301}x\n").unwrap();
+    let suffix = |row, col, encoding| {
+        let pos = Position::new(Row::new_zero_indexed(row), Column::new_zero_indexed(col));
+        vfs.line_suffix(Path::new("foo"), pos, encoding)
+    };
+    let usv = PositionEncoding::UnicodeScalarValue;
+
+    assert_eq!(suffix(0, 0, usv), Ok("let x = 1;".to_owned()));
+    assert_eq!(suffix(0, 4, usv), Ok("x = 1;".to_owned()));
+    assert_eq!(suffix(0, 10, usv), Ok(String::new()));
+    assert_eq!(suffix(0, 11, usv), Err(Error::BadLocation));
+    assert_eq!(suffix(1, 1, usv), Ok(" e\u{301}x".to_owned()));
+    assert_eq!(suffix(1, 3, PositionEncoding::Utf16CodeUnit), Ok("e\u{301}x".to_owned()));
+    assert_eq!(suffix(1, 3, PositionEncoding::Grapheme), Ok("x".to_owned()));
+    assert_eq!(suffix(2, 0, usv), Ok(String::new()));
+}
+
+#[test]
+fn test_vfs_fs() {
+    let dir = make_temp_dir("vfs_fs");
+    let (cached, uncached) = (dir.join("cached.rs"), dir.join("uncached.rs"));
+    fs::write(&cached, "on disk\n").unwrap();
+    fs::write(&uncached, "also on disk\n").unwrap();
+
+    let vfs = Vfs::<()>::new();
+    vfs.load_file(&cached).unwrap();
+    vfs.with_mut_text(&cached, |t| *t = "edited\n".to_owned()).unwrap();
+    let vfs_fs = VfsFs::new(&vfs);
+    assert_eq!(vfs_fs.read_to_string(&cached).unwrap(), "edited\n");
+    let metadata = vfs_fs.metadata(&cached).unwrap();
+    assert_eq!(metadata.len, 7);
+    assert_eq!(metadata.modified, vfs.last_modified(&cached).unwrap());
+
+    // Other files are read from disk, but not cached.
+    assert_eq!(vfs_fs.read_to_string(&uncached).unwrap(), "also on disk\n");
+    assert_eq!(vfs_fs.metadata(&uncached).unwrap().len, 13);
+    assert!(!vfs.is_cached(&uncached));
+    let missing = vfs_fs.read_to_string(&dir.join("missing.rs")).unwrap_err();
+    assert_eq!(missing.kind(), io::ErrorKind::NotFound);
+}
+
+#[test]
+fn test_batch_rebuilds_line_index_once() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    let path = Path::new("foo");
+    vfs.load_file(path).unwrap();
+    let insert = |row, text: &str| {
+        let pos = Position::new(Row::new_zero_indexed(row), Column::new_zero_indexed(0));
+        Change::ReplaceText {
+            span: VfsSpan::from_usv(Span::from_positions(pos, pos, "foo"), None),
+            text: text.to_owned(),
+        }
+    };
+
+    // Editors send the edits of a batch from the end of the file backwards.
+    let changes: Vec<_> = (0..5).rev().map(|row| insert(row, "\n")).collect();
+    vfs.on_changes(&changes).unwrap();
+    assert_eq!(vfs.stats().change_batches, 1);
+    assert_eq!(vfs.stats().line_index_rebuilds, 1);
+
+    let text = "\nfoo\n\nHello\n\nWorld\n\nHello, World!\n\n";
+    assert_eq!(vfs.load_file(path).unwrap(), FileContents::Text(text.to_owned()));
+    for (i, line) in text.split_inclusive('\n').enumerate() {
+        assert_eq!(vfs.load_line(path, Row::new_zero_indexed(i as u32)).unwrap(), line);
+    }
+    vfs.check_invariants().unwrap();
+
+    // Edits which do not add or remove lines leave the index up to date.
+    vfs.on_changes(&[insert(0, "x"), insert(3, "y")]).unwrap();
+    assert_eq!(vfs.stats().line_index_rebuilds, 1);
+}
+
+#[test]
+fn test_recording_file_loader() {
+    let loader = RecordingFileLoader::new();
+    let path = Path::new("/project/src/lib.rs");
+    loader.add_file(path, "fn main() {}\n");
+    let vfs = VfsInternal::<MockFileLoader, ()>::with_loader_chain(vec![Box::new(loader.clone())]);
+
+    vfs.set_file(path, "fn main() { loop {} }\n").unwrap();
+    vfs.write_file(path).unwrap();
+    let text = FileContents::Text("fn main() { loop {} }\n".to_owned());
+    assert_eq!(loader.writes(), [(path.to_owned(), text.clone())]);
+    assert_eq!(loader.calls(), [LoaderCall::Write(path.to_owned(), text)]);
+
+    // Reads are served from the loader, including what was written.
+    loader.clear_calls();
+    vfs.flush_file(path).unwrap();
+    assert_eq!(vfs.load_line(path, Row::new_zero_indexed(0)).unwrap(), "fn main() { loop {} }\n");
+    assert_eq!(loader.calls(), [LoaderCall::Read(path.to_owned())]);
+    assert!(vfs.load_file(Path::new("/project/missing.rs")).is_err());
+}
+
+#[test]
+fn test_undo_change() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    let path = Path::new("foo");
+    let original = vfs.load_file(path).unwrap();
+    let pos = |row, col| Position::new(Row::new_zero_indexed(row), Column::new_zero_indexed(col));
+    let replace = |start, end, text: &str| Change::ReplaceText {
+        span: VfsSpan::from_usv(Span::from_positions(start, end, "foo"), None),
+        text: text.to_owned(),
+    };
+
+    let inverses = vfs.on_changes_with_inverses(&[make_change(false)]).unwrap();
+    let edited = FileContents::Text("foo\nHfooo\nWorld\nHello, World!\n".to_owned());
+    assert_eq!(vfs.load_file(path).unwrap(), edited);
+    assert_eq!(inverses.len(), 1);
+    let redo = vfs.undo_change(path, &inverses[0]).unwrap();
+    assert_eq!(vfs.load_file(path).unwrap(), original);
+    vfs.undo_change(path, &redo).unwrap();
+    assert_eq!(vfs.load_file(path).unwrap(), edited);
+
+    // Several changes, which add and remove lines, are reverted in reverse.
+    let changes = [
+        replace(pos(1, 0), pos(2, 2), "\u{E9}\n\u{1F600}"),
+        replace(pos(0, 3), pos(1, 1), ""),
+        Change::AddFile {
+            file: PathBuf::from("foo"),
+            text: "new\n".to_owned(),
+        },
+        replace(pos(1, 0), pos(1, 0), "more\n"),
+    ];
+    let inverses = vfs.on_changes_with_inverses(&changes).unwrap();
+    assert_eq!(vfs.load_file(path).unwrap(), FileContents::Text("new\nmore\n".to_owned()));
+    for inverse in &inverses {
+        vfs.undo_change(path, inverse).unwrap();
+    }
+    assert_eq!(vfs.load_file(path).unwrap(), edited);
+
+    // A change to another file is rejected, without applying it.
+    assert_eq!(
+        vfs.undo_change(Path::new("bar"), &inverses[0]).unwrap_err(),
+        Error::BadLocation
+    );
+    assert_eq!(vfs.load_file(path).unwrap(), edited);
+
+    // The columns of inverses for a file with an encoding override are in its
+    // units, since it overrides those of the inverses too.
+    vfs.set_file(Path::new("bar"), "\u{1F600}ab\n").unwrap();
+    vfs.set_file_encoding(Path::new("bar"), PositionEncoding::Utf16CodeUnit).unwrap();
+    let change = Change::ReplaceText {
+        span: VfsSpan::from_utf16(Span::from_positions(pos(0, 2), pos(0, 3), "bar"), None),
+        text: "XY".to_owned(),
+    };
+    let inverses = vfs.on_changes_with_inverses(&[change]).unwrap();
+    assert_eq!(vfs.get_cached_files()[Path::new("bar")], "\u{1F600}XYb\n");
+    vfs.undo_change(Path::new("bar"), &inverses[0]).unwrap();
+    assert_eq!(vfs.get_cached_files()[Path::new("bar")], "\u{1F600}ab\n");
+}
+
+#[cfg(windows)]
+#[test]
+fn test_case_insensitive_paths() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    vfs.set_file(Path::new(r"C:\Foo\bar.rs"), "fn main() {}\n").unwrap();
+    assert!(vfs.is_cached(Path::new(r"c:\foo\BAR.rs")));
+    vfs.set_file(Path::new(r"c:\foo\BAR.rs"), "fn foo() {}\n").unwrap();
+
+    let files = vfs.get_cached_files();
+    assert_eq!(files.len(), 1);
+    // The entry keeps the path it was first added with.
+    assert_eq!(files[Path::new(r"C:\Foo\bar.rs")], "fn foo() {}\n");
+}
+
+#[cfg(not(windows))]
+#[test]
+fn test_case_sensitive_paths() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    vfs.set_file(Path::new("/foo/bar.rs"), "fn main() {}\n").unwrap();
+    assert!(!vfs.is_cached(Path::new("/foo/BAR.rs")));
+    vfs.set_file(Path::new("/foo/BAR.rs"), "fn foo() {}\n").unwrap();
+    assert_eq!(vfs.get_cached_files().len(), 2);
+}
+
+#[cfg(feature = "flate2")]
+#[test]
+fn test_gzip() {
+    use flate2::read::GzDecoder;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::{Read, Write};
+
+    let dir = make_temp_dir("gzip");
+    let path = dir.join("vendored.rs.gz");
+    let mut encoder = GzEncoder::new(vec![], Compression::default());
+    encoder.write_all(b"fn main() {}\n").unwrap();
+    fs::write(&path, encoder.finish().unwrap()).unwrap();
+
+    let vfs = VfsInternal::<RealFileLoader, ()>::new();
+    assert!(matches!(vfs.load_file(&path), Ok(FileContents::Binary(_))));
+    vfs.flush_file(&path).unwrap();
+
+    vfs.set_gzip(true);
+    assert_eq!(
+        vfs.load_file(&path).unwrap(),
+        FileContents::Text("fn main() {}\n".to_owned())
+    );
+    // Setting the write options later keeps gzip enabled.
+    vfs.set_write_options(WriteOptions {
+        fsync: true,
+        ..WriteOptions::default()
+    });
+    vfs.set_file(&path, "fn main() { println!(); }\n").unwrap();
+    vfs.write_file(&path).unwrap();
+
+    let mut text = String::new();
+    GzDecoder::new(&fs::read(&path).unwrap()[..]).read_to_string(&mut text).unwrap();
+    assert_eq!(text, "fn main() { println!(); }\n");
+    vfs.flush_file(&path).unwrap();
+    assert_eq!(
+        vfs.load_file(&path).unwrap(),
+        FileContents::Text("fn main() { println!(); }\n".to_owned())
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_enclosing_brackets() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    let path = Path::new("foo");
+    vfs.set_file(path, "fn f(a: (u8, u16), b: [u8; 2]) {\n    g((1, 2), (3));\n}\n").unwrap();
+    let enclosing = |row, col, open, close| {
+        let pos = Position::new(Row::new_zero_indexed(row), Column::new_zero_indexed(col));
+        vfs.enclosing_brackets(path, pos, open, close).unwrap()
+    };
+    let span = |row_start, col_start, row_end, col_end| {
+        Some(Span::new(
+            Row::new_zero_indexed(row_start),
+            Row::new_zero_indexed(row_end),
+            Column::new_zero_indexed(col_start),
+            Column::new_zero_indexed(col_end),
+            path,
+        ))
+    };
+
+    assert_eq!(enclosing(1, 7, '(', ')'), span(1, 6, 1, 12));
+    // Just before and just after a nested pair.
+    assert_eq!(enclosing(1, 6, '(', ')'), span(1, 5, 1, 18));
+    assert_eq!(enclosing(1, 12, '(', ')'), span(1, 5, 1, 18));
+    assert_eq!(enclosing(1, 5, '(', ')'), None);
+    assert_eq!(enclosing(0, 12, '(', ')'), span(0, 8, 0, 17));
+    assert_eq!(enclosing(0, 19, '(', ')'), span(0, 4, 0, 30));
+    assert_eq!(enclosing(1, 0, '{', '}'), span(0, 31, 2, 1));
+    assert_eq!(enclosing(0, 25, '[', ']'), span(0, 22, 0, 29));
+
+    // Unbalanced brackets.
+    vfs.set_file(path, "a)b(c").unwrap();
+    assert_eq!(enclosing(0, 2, '(', ')'), None);
+    assert_eq!(enclosing(0, 5, '(', ')'), None);
+
+    // A column past the end of the line, or a row past the end of the file.
+    let pos = |row, col| Position::new(Row::new_zero_indexed(row), Column::new_zero_indexed(col));
+    assert_eq!(vfs.enclosing_brackets(path, pos(0, 6), '(', ')'), Err(Error::BadLocation));
+    assert_eq!(vfs.enclosing_brackets(path, pos(1, 0), '(', ')'), Err(Error::BadLocation));
+}
+
+#[test]
+fn test_rename_word() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    let pos = |row, col| Position::new(Row::new_zero_indexed(row), Column::new_zero_indexed(col));
+
+    // "Hello" is a whole word in "Hello, World!", since "," is not part of a word.
+    assert_eq!(vfs.rename_word(Path::new("foo"), pos(1, 2), "Hi"), Ok(2));
+    assert_eq!(
+        vfs.load_file(Path::new("foo")).unwrap(),
+        FileContents::Text("foo\nHi\nWorld\nHi, World!\n".to_owned())
+    );
+    assert_eq!(vfs.file_version(Path::new("foo")).unwrap(), 1);
+    // Not on a word.
+    assert_eq!(vfs.rename_word(Path::new("foo"), pos(3, 2), "x"), Ok(0));
+
+    vfs.set_file(Path::new("bar"), "let Hello = HelloWorld(Hello_1, Hello);\n").unwrap();
+    assert_eq!(vfs.rename_word(Path::new("bar"), pos(0, 4), "Hi"), Ok(2));
+    assert_eq!(
+        vfs.load_file(Path::new("bar")).unwrap(),
+        FileContents::Text("let Hi = HelloWorld(Hello_1, Hi);\n".to_owned())
+    );
+}
+
+#[test]
+fn test_register_scratch() {
+    let dir = make_temp_dir("register_scratch");
+    // A file on disk at the scratch path is never read or written.
+    let path = dir.join("Untitled-1");
+    fs::write(&path, "on disk\n").unwrap();
+
+    let vfs = VfsInternal::<RealFileLoader, ()>::new();
+    vfs.register_scratch(&path);
+    assert_eq!(vfs.load_file(&path), Err(Error::FileNotCached));
+    assert_eq!(vfs.write_file(&path), Err(Error::FileNotCached));
+    assert!(!vfs.exists_on_disk(&path));
+
+    let change = Change::AddFile {
+        file: path.clone(),
+        text: "scratch\n".to_owned(),
+    };
+    vfs.on_changes(&[change]).unwrap();
+    assert_eq!(
+        vfs.load_file(&path).unwrap(),
+        FileContents::Text("scratch\n".to_owned())
+    );
+    vfs.write_file(&path).unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), "on disk\n");
+    assert!(!vfs.file_is_synced(&path).unwrap());
+
+    vfs.flush_file(&path).unwrap();
+    assert_eq!(vfs.load_file(&path), Err(Error::FileNotCached));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_invalidate_user_data_where() {
+    let vfs = VfsInternal::<MockFileLoader, u32>::new();
+    vfs.load_file(Path::new("foo")).unwrap();
+    vfs.set_file(Path::new("bar"), "Hello\n").unwrap();
+    vfs.set_file(Path::new("baz"), "Hello, World!\n").unwrap();
+    for (i, path) in ["foo", "bar", "baz"].iter().enumerate() {
+        vfs.set_user_data(Path::new(path), Some(i as u32)).unwrap();
+    }
+
+    vfs.invalidate_user_data_where(|_, text, _| text.contains("World"));
+    let user_data = |path| vfs.with_user_data(Path::new(path), |u| Ok(*u?.1));
+    assert_eq!(user_data("foo"), Err(Error::NoUserDataForFile));
+    assert_eq!(user_data("bar"), Ok(1));
+    assert_eq!(user_data("baz"), Err(Error::NoUserDataForFile));
+
+    // The predicate also sees the user data.
+    vfs.set_user_data(Path::new("foo"), Some(0)).unwrap();
+    vfs.invalidate_user_data_where(|_, _, u| *u == 1);
+    assert_eq!(user_data("foo"), Ok(0));
+    assert_eq!(user_data("bar"), Err(Error::NoUserDataForFile));
+}
+
+#[test]
+fn test_file_state() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    assert_eq!(vfs.file_state(Path::new("foo")), FileState::NotCached);
+    // Asking does not load the file.
+    assert!(!vfs.is_cached(Path::new("foo")));
+
+    vfs.load_file(Path::new("foo")).unwrap();
+    assert_eq!(
+        vfs.file_state(Path::new("foo")),
+        FileState::NonEmpty {
+            lines: 5,
+            bytes: 30,
+            changed: false,
+        }
+    );
+    vfs.on_changes(&[make_change(false)]).unwrap();
+    assert_eq!(
+        vfs.file_state(Path::new("foo")),
+        FileState::NonEmpty {
+            lines: 5,
+            bytes: 30,
+            changed: true,
+        }
+    );
+
+    let change = Change::AddFile {
+        file: PathBuf::from("bar"),
+        text: String::new(),
+    };
+    vfs.on_changes(&[change]).unwrap();
+    assert_eq!(vfs.file_state(Path::new("bar")), FileState::Empty);
+    assert_eq!(vfs.load_line(Path::new("bar"), Row::new_zero_indexed(0)).unwrap(), "");
+}
+
+#[test]
+fn test_metadata() {
+    let vfs = VfsInternal::<MockFileLoader, u32>::new();
+    assert_eq!(vfs.set_metadata(Path::new("foo"), "lang", "rust"), Err(Error::FileNotCached));
+
+    vfs.load_file(Path::new("foo")).unwrap();
+    vfs.set_metadata(Path::new("foo"), "lang", "rust").unwrap();
+    vfs.set_metadata(Path::new("foo"), "project", "a").unwrap();
+    vfs.set_metadata(Path::new("foo"), "project", "b").unwrap();
+    vfs.set_user_data(Path::new("foo"), Some(42)).unwrap();
+
+    vfs.on_changes(&[make_change(false)]).unwrap();
+    vfs.with_user_data(Path::new("foo"), |u| {
+        assert_eq!(u, Err(Error::NoUserDataForFile));
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(vfs.get_metadata(Path::new("foo"), "lang"), Ok(Some("rust".to_owned())));
+    assert_eq!(vfs.get_metadata(Path::new("foo"), "project"), Ok(Some("b".to_owned())));
+    assert_eq!(vfs.get_metadata(Path::new("foo"), "other"), Ok(None));
+
+    vfs.flush_file(Path::new("foo")).unwrap();
+    assert_eq!(vfs.get_metadata(Path::new("foo"), "lang"), Err(Error::FileNotCached));
+}
+
+#[test]
+fn test_random_edits() {
+    // A xorshift generator, so that failures are reproducible.
+    let mut state: u64 = 0x9E37_79B9_7F4A_7C15;
+    let mut below = |n: usize| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        (state % n as u64) as usize
+    };
+    let pieces = ["", "x", "yz", "\n", "\n\n", "a\nb", "\u{E9}\n\u{1F600}", "cd\nef\n"];
+
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    let mut text = "ab\ncd\n\nef".to_owned();
+    vfs.set_file(Path::new("foo"), &text).unwrap();
+    for _ in 0..500 {
+        // Pick two positions in the text, in lines and unicode scalar values.
+        let positions: Vec<_> = text
+            .split('\n')
+            .enumerate()
+            .flat_map(|(row, line)| (0..=line.chars().count()).map(move |col| (row, col)))
+            .collect();
+        let mut start = positions[below(positions.len())];
+        let mut end = positions[below(positions.len())];
+        if start > end {
+            std::mem::swap(&mut start, &mut end);
+        }
+        let inserted = if text.len() > 300 { "" } else { pieces[below(pieces.len())] };
+
+        let change = Change::ReplaceText {
+            span: VfsSpan::from_usv(
+                Span::new(
+                    Row::new_zero_indexed(start.0 as u32),
+                    Row::new_zero_indexed(end.0 as u32),
+                    Column::new_zero_indexed(start.1 as u32),
+                    Column::new_zero_indexed(end.1 as u32),
+                    "foo",
+                ),
+                None,
+            ),
+            text: inserted.to_owned(),
+        };
+        vfs.on_changes(&[change]).unwrap();
+
+        let offset = |(row, col): (usize, usize)| {
+            let line_start: usize = text.split('\n').take(row).map(|l| l.len() + 1).sum();
+            line_start + text[line_start..].chars().take(col).map(char::len_utf8).sum::<usize>()
+        };
+        let range = offset(start)..offset(end);
+        text.replace_range(range, inserted);
+        assert_eq!(vfs.load_file(Path::new("foo")).unwrap(), FileContents::Text(text.clone()));
+        let ranges = vfs.line_ranges(Path::new("foo")).unwrap();
+        assert_eq!(ranges.len(), text.split('\n').count());
+        for (i, line) in text.split_inclusive('\n').enumerate() {
+            let row = Row::new_zero_indexed(i as u32);
+            assert_eq!(vfs.load_line(Path::new("foo"), row).unwrap(), line);
+        }
+    }
+}
+
+#[test]
+fn test_slice() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    assert_eq!(vfs.slice(Path::new("foo"), 4, 9).unwrap(), "Hello");
+    assert_eq!(vfs.slice(Path::new("foo"), 16, 30).unwrap(), "Hello, World!\n");
+    assert_eq!(vfs.slice(Path::new("foo"), 30, 30).unwrap(), "");
+    assert_eq!(vfs.slice(Path::new("foo"), 9, 4), Err(Error::BadLocation));
+    assert_eq!(vfs.slice(Path::new("foo"), 16, 31), Err(Error::BadLocation));
+
+    vfs.set_file(Path::new("bar"), "caf\u{E9}!").unwrap();
+    assert_eq!(vfs.slice(Path::new("bar"), 3, 5).unwrap(), "\u{E9}");
+    // Inside the two bytes of "\u{E9}".
+    assert_eq!(vfs.slice(Path::new("bar"), 4, 6), Err(Error::BadLocation));
+    assert_eq!(vfs.slice(Path::new("bar"), 0, 4), Err(Error::BadLocation));
+}
+
+#[test]
+fn test_materialize_to() {
+    let dir = make_temp_dir("materialize_to");
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    vfs.load_file(Path::new("/src/foo.rs")).unwrap();
+    vfs.on_changes(&[Change::ReplaceText {
+        span: VfsSpan::from_usv(
+            Span::new(
+                Row::new_zero_indexed(1),
+                Row::new_zero_indexed(1),
+                Column::new_zero_indexed(0),
+                Column::new_zero_indexed(5),
+                "/src/foo.rs",
+            ),
+            None,
+        ),
+        text: "Dirty".to_owned(),
+    }])
+    .unwrap();
+    vfs.set_file(Path::new("bar.rs"), "in memory only\n").unwrap();
+
+    let paths = vfs.materialize_to(&dir).unwrap();
+    assert_eq!(paths.len(), 2);
+    assert_eq!(paths[Path::new("/src/foo.rs")], dir.join("src").join("foo.rs"));
+    assert_eq!(
+        fs::read_to_string(&paths[Path::new("/src/foo.rs")]).unwrap(),
+        "/src/foo.rs\nDirty\nWorld\nHello, World!\n"
+    );
+    assert_eq!(fs::read_to_string(dir.join("bar.rs")).unwrap(), "in memory only\n");
+    // The cached files are unchanged.
+    assert!(!vfs.file_is_synced(Path::new("/src/foo.rs")).unwrap());
+
+    // A relative path which would be written over an absolute one is an
+    // error, and nothing is written.
+    vfs.set_file(Path::new("src/foo.rs"), "clash\n").unwrap();
+    vfs.set_file(Path::new("baz.rs"), "new\n").unwrap();
+    assert_eq!(
+        vfs.materialize_to(&dir),
+        Err(Error::DuplicatePath(dir.join("src").join("foo.rs")))
+    );
+    assert!(!dir.join("baz.rs").exists());
+    vfs.flush_file(Path::new("src/foo.rs")).unwrap();
+
+    // So is a path which could lead outside `dir`.
+    vfs.set_file(Path::new("/src/../../escape.rs"), "escaped\n").unwrap();
+    assert!(matches!(vfs.materialize_to(&dir), Err(Error::Io(..))));
+    assert!(!dir.join("baz.rs").exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_char_count_on_line() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    let count = |row, ch| vfs.char_count_on_line(Path::new("foo"), Row::new_zero_indexed(row), ch);
+    assert_eq!(count(3, ' '), Ok(1));
+    assert_eq!(count(3, 'l'), Ok(3));
+    assert_eq!(count(3, '\n'), Ok(0));
+    assert_eq!(count(1, ' '), Ok(0));
+    assert_eq!(count(5, ' '), Err(Error::BadLocation));
+
+    vfs.set_file(Path::new("foo"), "fn f() {\n\t\tlet x = '\t';\r\n}\n").unwrap();
+    assert_eq!(count(1, '\t'), Ok(3));
+    assert_eq!(count(1, '\r'), Ok(0));
+    assert_eq!(count(0, '\t'), Ok(0));
+}
+
+#[test]
+fn test_append() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    let mut text = "first\n\u{E9}".to_owned();
+    vfs.set_file(Path::new("foo"), &text).unwrap();
+    for i in 0..50 {
+        let lines = text.split('\n').count();
+        let end = Position::new(
+            Row::new_zero_indexed(lines as u32 - 1),
+            Column::new_zero_indexed(text.rsplit('\n').next().unwrap().chars().count() as u32),
+        );
+        // Long lines get a column index, which appending must update.
+        let appended = if i % 3 == 0 { "x".repeat(5000) } else { format!("line {}\n", i) };
+        vfs.on_changes(&[Change::ReplaceText {
+            span: VfsSpan::from_usv(Span::from_positions(end, end, "foo"), None),
+            text: appended.clone(),
+        }])
+        .unwrap();
+        text.push_str(&appended);
+
+        assert_eq!(vfs.load_file(Path::new("foo")).unwrap(), FileContents::Text(text.clone()));
+        for (row, line) in text.split_inclusive('\n').enumerate() {
+            let row = Row::new_zero_indexed(row as u32);
+            assert_eq!(vfs.load_line(Path::new("foo"), row).unwrap(), line);
+        }
+    }
+}
+
+#[test]
+fn test_utf16_clamps_mid_surrogate() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    vfs.set_file(Path::new("foo"), "a😢b\n").unwrap();
+    let insert = |col| {
+        let pos = Position::new(Row::new_zero_indexed(0), Column::new_zero_indexed(col));
+        Change::ReplaceText {
+            span: VfsSpan::from_utf16(Span::from_positions(pos, pos, "foo"), None),
+            text: "x".into(),
+        }
+    };
+
+    // Column 2 is between the halves of the surrogate pair for 😢.
+    vfs.on_changes(&[insert(2)]).unwrap();
+    assert_eq!(
+        vfs.load_file(Path::new("foo")).unwrap(),
+        FileContents::Text("ax😢b\n".to_owned())
+    );
+    assert_eq!(vfs.stats().clamped_changes, 1);
+
+    vfs.on_changes(&[insert(4)]).unwrap();
+    assert_eq!(
+        vfs.load_file(Path::new("foo")).unwrap(),
+        FileContents::Text("ax😢xb\n".to_owned())
+    );
+    assert_eq!(vfs.stats().clamped_changes, 1);
+}
+
+#[test]
+fn test_pending_change_count() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    for name in &["foo", "bar", "baz"] {
+        vfs.load_file(Path::new(name)).unwrap();
+    }
+    assert_eq!(vfs.pending_change_count(), 0);
+
+    vfs.on_changes(&[make_change(false)]).unwrap();
+    vfs.with_mut_text(Path::new("bar"), |t| t.push('x')).unwrap();
+    assert_eq!(vfs.pending_change_count(), 2);
+
+    vfs.file_saved(Path::new("foo")).unwrap();
+    assert_eq!(vfs.pending_change_count(), 1);
+}
+
+#[test]
+fn test_arc_snapshot() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    for name in &["foo", "bar"] {
+        vfs.load_file(Path::new(name)).unwrap();
+    }
+    let snapshot = vfs.arc_snapshot();
+    let expected =
+        |name: &str| FileContents::Text(format!("{}\nHello\nWorld\nHello, World!\n", name));
+
+    thread::scope(|s| {
+        for _ in 0..4 {
+            let snapshot = snapshot.clone();
+            s.spawn(move || {
+                for _ in 0..100 {
+                    for name in &["foo", "bar"] {
+                        let contents = snapshot.load_file(Path::new(name)).unwrap();
+                        assert_eq!(contents, expected(name));
+                    }
+                }
+            });
+        }
+        for _ in 0..100 {
+            vfs.with_mut_text(Path::new("foo"), |t| t.push('x')).unwrap();
+            vfs.set_file(Path::new("bar"), "replaced").unwrap();
+        }
+    });
+
+    assert_eq!(snapshot.version(Path::new("foo")), Some(0));
+    assert_eq!(snapshot.load_file(Path::new("baz")), Err(Error::FileNotCached));
+    assert_ne!(vfs.load_file(Path::new("foo")).unwrap(), expected("foo"));
+}
+
+#[test]
+fn test_changed_since() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    let paths = |names: &[&str]| names.iter().map(PathBuf::from).collect::<Vec<_>>();
+    assert_eq!(vfs.changed_since(0), (0, vec![]));
+
+    // Loading a file is not a change.
+    vfs.load_file(Path::new("foo")).unwrap();
+    vfs.on_changes(&[make_change(false)]).unwrap();
+    vfs.set_file(Path::new("bar"), "bar").unwrap();
+    let (seq, changed) = vfs.changed_since(0);
+    assert_eq!(seq, 2);
+    assert_eq!(changed, paths(&["bar", "foo"]));
+
+    vfs.with_mut_text(Path::new("baz"), |t| t.push('x')).unwrap_err();
+    vfs.with_mut_text(Path::new("bar"), |t| t.push('x')).unwrap();
+    vfs.set_file(Path::new("baz"), "baz").unwrap();
+    assert_eq!(vfs.changed_since(seq), (4, paths(&["bar", "baz"])));
+    assert_eq!(vfs.changed_since(4), (4, vec![]));
+
+    vfs.soft_delete(Path::new("foo")).unwrap();
+    assert_eq!(vfs.changed_since(4), (5, paths(&["foo"])));
+    assert_eq!(vfs.changed_since(1), (5, paths(&["bar", "baz", "foo"])));
+
+    // Re-adding a file with its current contents is not a change, but
+    // restoring a deleted file is.
+    let add = |name: &str, text: &str| Change::AddFile {
+        file: PathBuf::from(name),
+        text: text.to_owned(),
+    };
+    vfs.on_changes(&[add("bar", "barx")]).unwrap();
+    assert_eq!(vfs.changed_since(5), (5, vec![]));
+    assert_eq!(vfs.change_history().len(), 1);
+    vfs.on_changes(&[add("foo", "foo\nHfooo\nWorld\nHello, World!\n")]).unwrap();
+    assert_eq!(vfs.changed_since(5), (6, paths(&["foo"])));
+}
+
+#[test]
+fn test_write_to_deleted_dir() {
+    let dir = make_temp_dir("write_to_deleted_dir");
+    let path = dir.join("src").join("lib.rs");
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(&path, "fn f() {}\n").unwrap();
+    let vfs = VfsInternal::<RealFileLoader, ()>::new();
+    vfs.load_file(&path).unwrap();
+    vfs.with_mut_text(&path, |t| t.push_str("fn g() {}\n")).unwrap();
+
+    fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    let message = format!("Directory does not exist: {}", path.parent().unwrap().display());
+    assert_eq!(vfs.write_file(&path), Err(Error::Io(Some(path.clone()), Some(message))));
+
+    vfs.set_write_options(WriteOptions {
+        create_dirs: true,
+        ..WriteOptions::default()
+    });
+    vfs.write_file(&path).unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), "fn f() {}\nfn g() {}\n");
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_preview_change() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    let change = make_change(false);
+    let (range, inserted) = vfs.preview_change(&change).unwrap();
+    // "ell" in "foo\nHello\n..." is replaced by "foo".
+    assert_eq!((range.clone(), inserted), (5..8, 3));
+
+    let before = match vfs.load_file(Path::new("foo")).unwrap() {
+        FileContents::Text(text) => text,
+        FileContents::Binary(_) => panic!("expected a text file"),
+    };
+    vfs.on_changes(&[change]).unwrap();
+    let mut expected = before.clone();
+    expected.replace_range(range, "foo");
+    assert_eq!(vfs.load_file(Path::new("foo")).unwrap(), FileContents::Text(expected));
+    assert_eq!(vfs.file_version(Path::new("foo")).unwrap(), 1);
+
+    let add = Change::AddFile {
+        file: PathBuf::from("foo"),
+        text: "new".to_owned(),
+    };
+    assert_eq!(vfs.preview_change(&add).unwrap(), (0..before.len(), 3));
+}
+
+#[test]
+fn test_set_root() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    let root = if cfg!(windows) { Path::new(r"C:\work") } else { Path::new("/work") };
+    vfs.set_root(Some(root.to_owned()));
+    let abs = root.join("src").join("lib.rs");
+
+    let expected = FileContents::Text(format!("{}\nHello\nWorld\nHello, World!\n", abs.display()));
+    assert_eq!(vfs.load_file(Path::new("src/lib.rs")).unwrap(), expected);
+    assert_eq!(vfs.get_cached_files().keys().collect::<Vec<_>>(), [&abs]);
+
+    vfs.on_changes(&[Change::AddFile {
+        file: PathBuf::from("src/main.rs"),
+        text: "fn main() {}\n".to_owned(),
+    }])
+    .unwrap();
+    assert!(vfs.is_cached(&root.join("src").join("main.rs")));
+    assert!(!vfs.is_cached(Path::new("src/main.rs")));
+
+    // Absolute paths are used as they are.
+    let other = root.parent().unwrap().join("other.rs");
+    vfs.load_file(&other).unwrap();
+    assert!(vfs.is_cached(&other));
+}
+
+#[test]
+fn test_drain_changes() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    for name in &["a", "b", "c"] {
+        vfs.set_file(Path::new(name), name).unwrap();
+    }
+    let mut saved = HashMap::new();
+    let result = vfs.drain_changes(|path, text| {
+        if path == Path::new("b") {
+            return Err(Error::Io(Some(path.to_owned()), None));
+        }
+        saved.insert(path.to_owned(), text.to_owned());
+        Ok(())
+    });
+    assert_eq!(result, Err(Error::Io(Some(PathBuf::from("b")), None)));
+    assert_eq!(saved.into_iter().collect::<Vec<_>>(), [(PathBuf::from("a"), "a".to_owned())]);
+    assert_eq!(vfs.file_is_synced(Path::new("a")), Ok(true));
+    assert_eq!(vfs.file_is_synced(Path::new("b")), Ok(false));
+    assert_eq!(vfs.file_is_synced(Path::new("c")), Ok(false));
+
+    let mut saved = vec![];
+    vfs.drain_changes(|path, _| {
+        saved.push(path.to_owned());
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(saved, [PathBuf::from("b"), PathBuf::from("c")]);
+    assert!(!vfs.has_changes());
+}
+
+// Takes a while to read each file, recording the number of reads in progress
+// and the most there were at once.
+#[derive(Clone, Default)]
+struct SlowSource(Arc<Mutex<(usize, usize)>>);
+
+impl FileSource for SlowSource {
+    fn read(&self, path: &Path) -> Result<Option<FileContents>, Error> {
+        {
+            let mut reads = self.0.lock().unwrap();
+            reads.0 += 1;
+            reads.1 = reads.1.max(reads.0);
+        }
+        thread::sleep(Duration::from_millis(20));
+        self.0.lock().unwrap().0 -= 1;
+        Ok(Some(FileContents::Text(format!("{}\n", path.display()))))
+    }
+}
+
+#[test]
+fn test_read_concurrency() {
+    let source = SlowSource::default();
+    let vfs = VfsInternal::<MockFileLoader, ()>::with_loader_chain(vec![Box::new(source.clone())]);
+    vfs.set_read_concurrency(Some(2));
+
+    thread::scope(|s| {
+        for i in 0..8 {
+            let vfs = &vfs;
+            s.spawn(move || vfs.load_file(Path::new(&format!("file{}.rs", i))).unwrap());
+        }
+    });
+    assert_eq!(vfs.file_count(), 8);
+    assert_eq!(*source.0.lock().unwrap(), (0, 2));
+}
+
+#[test]
+fn test_soft_delete_while_loading() {
+    let source = SlowSource::default();
+    let vfs = VfsInternal::<MockFileLoader, ()>::with_loader_chain(vec![Box::new(source)]);
+
+    // Deleting a file which is being loaded waits for the load, without
+    // blocking it.
+    thread::scope(|s| {
+        let vfs = &vfs;
+        s.spawn(move || vfs.load_file(Path::new("foo.rs")).unwrap());
+        thread::sleep(Duration::from_millis(5));
+        vfs.soft_delete(Path::new("foo.rs")).unwrap();
+    });
+    assert!(!vfs.is_cached(Path::new("foo.rs")));
+}
+
+#[test]
+fn test_transform_lines() {
+    let vfs = VfsInternal::<MockFileLoader, ()>::new();
+    vfs.load_file(Path::new("foo")).unwrap();
+    let count = vfs
+        .transform_lines(Path::new("foo"), |_, line| {
+            if line.contains("Hello") {
+                Some(format!("// {}", line))
+            } else {
+                None
+            }
+        })
+        .unwrap();
+    assert_eq!(count, 2);
+    assert_eq!(
+        vfs.load_file(Path::new("foo")).unwrap(),
+        FileContents::Text("foo\n// Hello\nWorld\n// Hello, World!\n".to_owned())
+    );
+    assert_eq!(vfs.file_version(Path::new("foo")).unwrap(), 1);
+    assert_eq!(vfs.file_is_synced(Path::new("foo")), Ok(false));
+
+    // The closure sees each line without its terminator.
+    vfs.set_file(Path::new("bar"), "a\r\nb").unwrap();
+    let seen = Mutex::new(vec![]);
+    let count = vfs
+        .transform_lines(Path::new("bar"), |row, line| {
+            seen.lock().unwrap().push((row.0, line.to_owned()));
+            None
+        })
+        .unwrap();
+    assert_eq!(count, 0);
+    assert_eq!(seen.into_inner().unwrap(), [(0, "a".to_owned()), (1, "b".to_owned())]);
+}
@@ -0,0 +1,77 @@
+//! Helpers for testing code which uses a `Vfs`.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use super::{Error, FileContents, FileSource};
+
+/// A call made by the VFS to a `RecordingFileLoader`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoaderCall {
+    /// The file at the path was read.
+    Read(PathBuf),
+    /// The contents were written to the file at the path.
+    Write(PathBuf, FileContents),
+}
+
+/// A `FileSource` which serves files from memory and records every read and
+/// write, so that tests can check what the VFS did without touching the disk.
+/// Reading a file which was neither added nor written fails with
+/// `Error::Io`. Clones share their files and calls, so a clone can be given to
+/// the VFS, e.g., with `Vfs::with_loader_chain`, and the original inspected.
+#[derive(Clone, Default)]
+pub struct RecordingFileLoader {
+    files: Arc<Mutex<HashMap<PathBuf, FileContents>>>,
+    calls: Arc<Mutex<Vec<LoaderCall>>>,
+}
+
+impl RecordingFileLoader {
+    pub fn new() -> RecordingFileLoader {
+        RecordingFileLoader::default()
+    }
+
+    /// Add a text file, or replace the contents of one. This is not recorded.
+    pub fn add_file(&self, path: &Path, text: &str) {
+        let contents = FileContents::Text(text.to_owned());
+        self.files.lock().unwrap().insert(path.to_owned(), contents);
+    }
+
+    /// Return the calls made so far, oldest first.
+    pub fn calls(&self) -> Vec<LoaderCall> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    /// Return the writes made so far, oldest first.
+    pub fn writes(&self) -> Vec<(PathBuf, FileContents)> {
+        let calls = self.calls.lock().unwrap();
+        calls
+            .iter()
+            .filter_map(|call| match *call {
+                LoaderCall::Write(ref path, ref contents) => Some((path.clone(), contents.clone())),
+                LoaderCall::Read(_) => None,
+            })
+            .collect()
+    }
+
+    /// Forget the calls made so far.
+    pub fn clear_calls(&self) {
+        self.calls.lock().unwrap().clear();
+    }
+}
+
+impl FileSource for RecordingFileLoader {
+    fn read(&self, path: &Path) -> Result<Option<FileContents>, Error> {
+        self.calls.lock().unwrap().push(LoaderCall::Read(path.to_owned()));
+        match self.files.lock().unwrap().get(path) {
+            Some(contents) => Ok(Some(contents.clone())),
+            None => Err(Error::Io(Some(path.to_owned()), Some("file not found".to_owned()))),
+        }
+    }
+
+    fn write(&self, path: &Path, contents: &FileContents) -> Result<bool, Error> {
+        self.calls.lock().unwrap().push(LoaderCall::Write(path.to_owned(), contents.clone()));
+        self.files.lock().unwrap().insert(path.to_owned(), contents.clone());
+        Ok(true)
+    }
+}
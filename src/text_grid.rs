@@ -0,0 +1,71 @@
+//! Conversions between byte offsets in a text and 1-based line and column
+//! positions, usable without a `Vfs`. Columns count unicode scalar values.
+
+use span::{Column, OneIndexed, Position, Row};
+
+use super::{byte_in_str, Error};
+use line_index::LineIndex;
+
+/// Return the byte offset in `text` of the given position. The position may be
+/// at the end of a line (or of the text), but not beyond it.
+pub fn byte_offset(
+    text: &str,
+    line: Row<OneIndexed>,
+    col: Column<OneIndexed>,
+) -> Result<usize, Error> {
+    let index = LineIndex::new(text);
+    let line = line.zero_indexed().0 as usize;
+    let (start, end) = match (index.get(line), index.get(line + 1)) {
+        (Some(start), Some(end)) => (start as usize, end as usize),
+        _ => return Err(Error::BadLocation),
+    };
+    let line_text = text[start..end].trim_end_matches('\n');
+    match byte_in_str(line_text, col.zero_indexed()) {
+        Ok(b) => Ok(start + b),
+        Err(_) => Err(Error::BadLocation),
+    }
+}
+
+/// Return the position of the byte at `offset` in `text`. `offset` may be the
+/// length of the text, but must otherwise be at a character boundary.
+pub fn position_of(text: &str, offset: usize) -> Result<Position<OneIndexed>, Error> {
+    if !text.is_char_boundary(offset) {
+        return Err(Error::BadLocation);
+    }
+    let before = &text[..offset];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    let line = before.bytes().filter(|&b| b == b'\n').count() as u32;
+    let col = before[line_start..].chars().count() as u32;
+    Ok(Position::new(
+        Row::new_one_indexed(line + 1),
+        Column::new_one_indexed(col + 1),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{byte_offset, position_of};
+    use span::{Column, Position, Row};
+    use Error;
+
+    #[test]
+    fn conversions() {
+        let text = "fn main() {\n    let \u{E9} = 1;\n}\n";
+        let pos = |line, col| (Row::new_one_indexed(line), Column::new_one_indexed(col));
+        let cases = [(1, 1, 0), (1, 12, 11), (2, 9, 20), (2, 10, 22), (3, 1, 28), (4, 1, 30)];
+        for &(line, col, offset) in &cases {
+            let (row, column) = pos(line, col);
+            assert_eq!(byte_offset(text, row, column), Ok(offset));
+            assert_eq!(position_of(text, offset), Ok(Position::new(row, column)));
+        }
+
+        // Past the end of a line, or of the text.
+        let (row, column) = pos(1, 13);
+        assert_eq!(byte_offset(text, row, column), Err(Error::BadLocation));
+        let (row, column) = pos(5, 1);
+        assert_eq!(byte_offset(text, row, column), Err(Error::BadLocation));
+        // Inside a multi-byte character.
+        assert_eq!(position_of(text, 21), Err(Error::BadLocation));
+        assert_eq!(position_of(text, 31), Err(Error::BadLocation));
+    }
+}